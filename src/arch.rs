@@ -1,18 +1,30 @@
-use crate::arch::apu::Apu;
-use crate::arch::cartridge::Cartridge;
-use crate::arch::cpu::Cpu;
+use std::collections::{HashSet, VecDeque};
+use std::fmt::Write;
+use std::path::Path;
+use crate::arch::apu::{Apu, BUFFER_LEN};
+use crate::arch::cartridge::{Cartridge, CartridgeHeader};
+use crate::arch::cpu::{Cpu, CpuError, Interrupt};
+use crate::arch::joypad::{Button, InputLatency, Joypad};
 use crate::arch::memory::Memory;
-use crate::arch::ppu::Ppu;
-use crate::util::InfCell;
+use crate::arch::ppu::{Framebuffer, ModeTransition, Ppu, FRAME_TCYCLES};
+use crate::arch::serial::Serial;
+use crate::arch::timer::Timer;
 
 pub mod apu;
 pub mod cartridge;
 pub mod cpu;
+pub mod disasm;
+pub mod joypad;
+pub mod mbc;
 pub mod memory;
 pub mod ppu;
+pub mod serial;
+pub mod timer;
+pub mod timing;
 
 
 #[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum SystemMode {
     Gameboy,
     GameboyPocket,
@@ -24,6 +36,23 @@ pub enum SystemMode {
 impl Default for SystemMode {
     fn default() -> Self { Self::Gameboy }
 }
+impl SystemMode {
+    /// True for any model built on CGB hardware, whether it's running in CGB or DMG mode.
+    pub fn is_cgb_hardware(&self) -> bool {
+        matches!(self, Self::GameboyColorDMG | Self::GameboyColorGBC)
+    }
+    
+    /// Size of this model's boot ROM. The CGB boot ROM is 0x900 bytes, mapped over 0x0000-0x00FF and
+    /// 0x0200-0x08FF, leaving the cartridge header in between visible.
+    pub fn boot_rom_len(&self) -> usize {
+        if self.is_cgb_hardware() { 0x900 } else { 0x100 }
+    }
+    
+    /// The boot ROM built in for this model, if there is one. Only [DMG_BOOT_ROM] is built in.
+    pub fn builtin_boot_rom(&self) -> Option<&'static [u8]> {
+        if self.is_cgb_hardware() { None } else { Some(DMG_BOOT_ROM) }
+    }
+}
 
 
 pub trait BusAccessable {
@@ -32,113 +61,1177 @@ pub trait BusAccessable {
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Bus {
-    pub cpu: Cpu,
+    mode: SystemMode,
     pub ppu: Ppu,
     pub mem: Memory,
     pub cart: Cartridge,
     pub apu: Apu,
-    pub boot_rom: [u8; 0x100],
+    pub joypad: Joypad,
+    pub serial: Serial,
+    pub timer: Timer,
+    /// Mapped over the cartridge until disabled through 0xFF50. Empty if no boot ROM is loaded, in
+    /// which case the cartridge is visible from the start. See [SystemMode::boot_rom_len()].
+    pub boot_rom: Vec<u8>,
     pub boot_disabled: u8,
+    /// OAM DMA Source Address (0xFF46) (R/W)
+    pub dma: u8,
+    /// T-cycles since the current OAM DMA transfer was started, or None if no transfer is running.
+    dma_tcycles: Option<u16>,
+    /// VRAM DMA source, from HDMA1/HDMA2 (0xFF51-0xFF52) (W, CGB only). Advances as blocks are copied.
+    pub hdma_src: u16,
+    /// VRAM DMA destination, from HDMA3/HDMA4 (0xFF53-0xFF54) (W, CGB only). Advances as blocks are copied.
+    pub hdma_dst: u16,
+    /// Remaining 0x10 byte blocks minus 1, in the lower 7 bits of HDMA5 (0xFF55) (R/W, CGB only).
+    pub hdma_len: u8,
+    /// Whether an HBlank DMA transfer is in progress.
+    pub hdma_active: bool,
+    /// T-cycles left for which the CPU is stopped by a VRAM DMA transfer.
+    hdma_stall: u16,
+    /// Interrupt Flag (0xFF0F) (R/W). Requested interrupts, lower 5 bits only.
+    pub interrupt_flags: u8,
+    /// Interrupt Enable (0xFFFF) (R/W)
+    pub interrupt_enable: u8,
+    /// Set while running at double speed (CGB only), shown in KEY1 (0xFF4D) bit 7.
+    pub double_speed: bool,
+    /// Prepare Speed Switch, KEY1 (0xFF4D) bit 0 (R/W, CGB only). When set, the next STOP switches
+    /// speeds instead of stopping the CPU.
+    pub speed_switch_armed: bool,
 }
 impl Bus {
     pub fn new(mode: SystemMode) -> Self { Self {
-        cpu: Cpu::new(mode),
+        mode,
         ppu: Ppu::new(mode),
         mem: Memory::new(mode),
         cart: Cartridge::new(),
-        apu: Apu::new(),
-        boot_rom: [0u8; 0x100],
+        apu: Apu::new(mode),
+        joypad: Joypad::new(),
+        serial: Serial::new(mode),
+        timer: Timer::new(),
+        boot_rom: vec![],
         boot_disabled: 0,
+        dma: 0,
+        dma_tcycles: None,
+        hdma_src: 0,
+        hdma_dst: 0,
+        hdma_len: 0x7F,
+        hdma_active: false,
+        hdma_stall: 0,
+        interrupt_flags: 0,
+        interrupt_enable: 0,
+        double_speed: false,
+        speed_switch_armed: false,
     }}
+    
+    /// True if `addr` currently reads from the boot ROM instead of the cartridge.
+    fn boot_rom_mapped(&self, addr: u16) -> bool {
+        self.boot_disabled == 0
+            && (addr < 0x0100 || (0x0200..0x0900).contains(&addr))
+            && (addr as usize) < self.boot_rom.len()
+    }
+    
+    /// Disables the boot ROM and sets the IO registers to the values it leaves behind, so the
+    /// cartridge can be started at 0x0100. See [Gameboy::skip_bootrom()], which also sets the CPU up.
+    /// 
+    /// The values are those left by the DMG boot ROM. On CGB hardware, the DMA register reads 0x00
+    /// instead, and in CGB mode the BG palettes are cleared to white. Others like DIV depend on how
+    /// long the CGB boot ROM ran, so they won't match exactly.
+    pub fn skip_bootrom(&mut self) {
+        // The APU is powered on first, since its other registers can't be written while it's off.
+        // Writing NR14 triggers channel 1, which the boot ROM leaves playing its chime.
+        for (addr, data) in [
+            (0xFF26, 0xF1),
+            (0xFF10, 0x80), (0xFF11, 0xBF), (0xFF12, 0xF3), (0xFF13, 0xFF), (0xFF14, 0xBF),
+            (0xFF16, 0x3F), (0xFF17, 0x00), (0xFF18, 0xFF), (0xFF19, 0xBF),
+            (0xFF1A, 0x7F), (0xFF1B, 0xFF), (0xFF1C, 0x9F), (0xFF1D, 0xFF), (0xFF1E, 0xBF),
+            (0xFF20, 0xFF), (0xFF21, 0x00), (0xFF22, 0x00), (0xFF23, 0xBF),
+            (0xFF24, 0x77), (0xFF25, 0xF3),
+            (0xFF05, 0x00), (0xFF06, 0x00), (0xFF07, 0xF8),
+            (0xFF40, 0x91), (0xFF42, 0x00), (0xFF43, 0x00), (0xFF45, 0x00),
+            (0xFF47, 0xFC), (0xFF4A, 0x00), (0xFF4B, 0x00),
+        ] {
+            self.write(addr, data);
+        }
+        
+        self.timer.counter = 0xABCC; // DIV 0xAB
+        self.ppu.stat = 0x85; // VBlank, with LY=LYC
+        self.interrupt_flags = Interrupt::VBlank.bits();
+        self.boot_disabled = 1;
+        
+        if self.mode.is_cgb_hardware() {
+            self.dma = 0x00;
+            if self.mode == SystemMode::GameboyColorGBC {
+                self.ppu.bg_palette_ram = [0xFF; 64];
+            }
+        } else {
+            self.dma = 0xFF;
+        }
+    }
+    
+    /// Interrupts which are both requested (IF) and enabled (IE).
+    pub fn pending_interrupts(&self) -> u8 {
+        self.interrupt_enable & self.interrupt_flags & 0x1F
+    }
+    
+    /// Sets the given interrupt's bit in IF.
+    pub fn request_interrupt(&mut self, interrupt: Interrupt) {
+        self.interrupt_flags |= interrupt.bits();
+    }
+    
+    /// Reads a byte without causing any side effects, for use by debugging tools.
+    /// 
+    /// VRAM and OAM are read directly, even when the PPU would block access. The I/O registers aren't
+    /// touched at all, and always read as 0xFF.
+    pub fn peek(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x8000..=0x9FFF if self.ppu.vbk & 0b1 != 0 => self.ppu.vram1[(addr & 0x1FFF) as usize],
+            0x8000..=0x9FFF => self.ppu.vram[(addr & 0x1FFF) as usize],
+            0xFE00..=0xFE9F => self.ppu.oam[(addr - 0xFE00) as usize],
+            0xFEA0..=0xFF7F => 0xFF,
+            _ => self.read(addr),
+        }
+    }
+    
+    /// True while an OAM DMA transfer is running, during which the CPU can't access OAM.
+    pub fn oam_dma_active(&self) -> bool {
+        self.dma_tcycles.is_some()
+    }
+    
+    /// Advances a running OAM DMA transfer by one t-cycle.
+    /// 
+    /// After a single m-cycle of setup, one byte is copied per m-cycle from `XX00-XX9F` (where XX is
+    /// the value written to 0xFF46) into OAM, taking 160 m-cycles in total. The DMA unit can't see
+    /// OAM or the I/O registers, so sources of 0xE0-0xFF read from WRAM through echo RAM instead.
+    pub fn oam_dma_tcycle(&mut self) {
+        let tcycles = match self.dma_tcycles {
+            Some(tcycles) => tcycles + 1,
+            None => return,
+        };
+        
+        if tcycles % 4 == 0 {
+            let index = (tcycles / 4) - 1;
+            let mut src = ((self.dma as u16) << 8) | index;
+            if src >= 0xE000 {
+                src -= 0x2000;
+            }
+            
+            // The DMA unit reads VRAM directly, so it isn't blocked during mode 3 like the CPU is
+            self.ppu.oam[index as usize] = self.peek(src);
+        }
+        
+        self.dma_tcycles = if tcycles == 0xA0 * 4 { None } else { Some(tcycles) };
+    }
+    
+    /// Copies a single 0x10 byte block of VRAM DMA, from the source into VRAM at the destination.
+    /// 
+    /// Each block stops the CPU for 8 m-cycles. Returns true when the final block was copied.
+    fn hdma_copy_block(&mut self) -> bool {
+        for _ in 0..0x10 {
+            let data = self.read(self.hdma_src);
+            self.ppu.write(0x8000 | (self.hdma_dst & 0x1FFF), data);
+            self.hdma_src = self.hdma_src.wrapping_add(1);
+            self.hdma_dst = self.hdma_dst.wrapping_add(1);
+        }
+        self.hdma_stall += 8 * 4;
+        
+        self.hdma_len = self.hdma_len.wrapping_sub(1) & 0x7F;
+        self.hdma_len == 0x7F
+    }
+    
+    /// Called as the PPU enters HBlank, to copy the next block of an active HBlank DMA transfer.
+    pub fn hdma_hblank(&mut self) {
+        if self.hdma_active && self.hdma_copy_block() {
+            self.hdma_active = false;
+        }
+    }
+    
+    /// Takes one t-cycle from a VRAM DMA stall, returning true if the CPU is stopped for it.
+    pub fn hdma_stalled(&mut self) -> bool {
+        if self.hdma_stall > 0 {
+            self.hdma_stall -= 1;
+            true
+        } else {
+            false
+        }
+    }
+    
+    /// Switches between normal and double speed if a switch was armed through KEY1, returning false
+    /// if it wasn't. Called by STOP.
+    pub fn switch_speed(&mut self) -> bool {
+        if !self.speed_switch_armed {
+            return false;
+        }
+        
+        self.double_speed = !self.double_speed;
+        self.speed_switch_armed = false;
+        true
+    }
+    
+    /// Clocks the timer and serial port, which run at the CPU's speed, along with the APU's frame
+    /// sequencer driven by DIV.
+    fn timer_serial_tcycle(&mut self) {
+        if self.timer.tcycle() {
+            self.request_interrupt(Interrupt::Timer);
+        }
+        if self.timer.take_div_apu(self.double_speed) {
+            self.apu.div_apu();
+        }
+        if self.serial.tcycle() {
+            self.request_interrupt(Interrupt::Serial);
+        }
+    }
+    
+    /// Starts or cancels a VRAM DMA transfer through HDMA5 (0xFF55).
+    /// 
+    /// With bit 7 clear, a general purpose DMA copies (length + 1) * 0x10 bytes immediately, unless
+    /// an HBlank DMA is active, in which case that transfer is cancelled instead. With bit 7 set, an
+    /// HBlank DMA copies one block at the start of each HBlank.
+    fn write_hdma5(&mut self, data: u8) {
+        self.hdma_len = data & 0x7F;
+        
+        if data & 0b10000000 != 0 {
+            self.hdma_active = true;
+        } else if self.hdma_active {
+            self.hdma_active = false;
+        } else {
+            while !self.hdma_copy_block() {}
+        }
+    }
 }
 
 impl BusAccessable for Bus {
     fn write(&mut self, addr: u16, data: u8) {
         match addr {
-            0x0000..=0x00FF if self.boot_disabled == 0 => (), // Boot ROM is read-only
+            0x0000..=0x08FF if self.boot_rom_mapped(addr) => (), // Boot ROM is read-only
             
             0x0000..=0x7FFF => self.cart.write(addr, data), // Cart ROM bank 00-NN
             0x8000..=0x9FFF => self.ppu.write(addr, data),  // VRAM
             0xA000..=0xBFFF => self.cart.write(addr, data), // Cart RAM
             0xC000..=0xFDFF => self.mem.write(addr, data),  // WRAM and ECHO RAM
+            0xFE00..=0xFEFF if self.oam_dma_active() => (), // OAM is blocked during DMA
             0xFE00..=0xFEFF => self.ppu.write(addr, data),  // OAM and prohibited
             
-            0xFF00..=0xFF02 | 0xFF04..=0xFF07 => self.cpu.write(addr, data), // Input, Serial, and Timer/Divider
-            0xFF0F => self.cpu.write(addr, data),                            // Interrupt Flag
+            0xFF00 => {                                                      // Input
+                let lines = self.joypad.lines();
+                self.joypad.write(addr, data);
+                if lines & !self.joypad.lines() != 0 {
+                    self.request_interrupt(Interrupt::Joypad);
+                }
+            },
+            0xFF01..=0xFF02 => self.serial.write(addr, data),                // Serial
+            0xFF04..=0xFF07 => self.timer.write(addr, data),                 // Timer/Divider
+            0xFF0F => self.interrupt_flags = data & 0x1F,                    // Interrupt Flag
             0xFF10..=0xFF26 | 0xFF30..=0xFF3F => self.apu.write(addr, data), // Sound and Wave Pattern
+            0xFF46 => {                                                      // OAM DMA
+                self.dma = data;
+                self.dma_tcycles = Some(0);
+            },
             0xFF40..=0xFF4B | 0xFF4F => self.ppu.write(addr, data),          // PPU controls and VRAM Bank Select
-            0xFF50 => self.boot_disabled = data,                             // Disable boot ROM
-            0xFF51..=0xFF55 | 0xFF68..=0xFF69 => self.ppu.write(addr, data), // VRAM DMA and BG/OBJ Palettes
+            0xFF4D if self.mode == SystemMode::GameboyColorGBC => self.speed_switch_armed = data & 0b1 != 0, // Prepare Speed Switch
+            0xFF4D => (),
+            0xFF50 if self.boot_disabled == 0 => self.boot_disabled = data,  // Disable boot ROM
+            0xFF50 => (),                                                    // Boot ROM can't be re-enabled
+            0xFF51..=0xFF55 if self.mode != SystemMode::GameboyColorGBC => (), // VRAM DMA
+            0xFF51 => self.hdma_src = (self.hdma_src & 0x00FF) | ((data as u16) << 8),
+            0xFF52 => self.hdma_src = (self.hdma_src & 0xFF00) | ((data & 0xF0) as u16),
+            0xFF53 => self.hdma_dst = (self.hdma_dst & 0x00FF) | (((data & 0x1F) as u16) << 8),
+            0xFF54 => self.hdma_dst = (self.hdma_dst & 0xFF00) | ((data & 0xF0) as u16),
+            0xFF55 => self.write_hdma5(data),
+            0xFF68..=0xFF6B => self.ppu.write(addr, data),                   // BG/OBJ Palettes
             0xFF70 => self.mem.write(addr, data),                            // WRAM Bank Select
             0xFF72..=0xFF75 => self.mem.write(addr, data),                   // Undocumented registers
             0xFF76..=0xFF77 => self.apu.write(addr, data),                   // Undocumented registers
             
             0xFF80..=0xFFFE => self.mem.write(addr, data), // HRAM
-            0xFFFF => self.cpu.write(addr, data), // Interrupt Enable
+            0xFFFF => self.interrupt_enable = data, // Interrupt Enable
             
-            _ => unimplemented!(),
+            _ => (), // Unmapped IO, writes are ignored
         }
     }
 
     fn read(&mut self, addr: u16) -> u8 {
         match addr {
-            0x0000..=0x00FF if self.boot_disabled == 0 => self.boot_rom[addr as usize],
+            0x0000..=0x08FF if self.boot_rom_mapped(addr) => self.boot_rom[addr as usize],
             
             0x0000..=0x7FFF => self.cart.read(addr), // Cart ROM bank 00-NN
             0x8000..=0x9FFF => self.ppu.read(addr),  // VRAM
             0xA000..=0xBFFF => self.cart.read(addr), // Cart RAM
             0xC000..=0xFDFF => self.mem.read(addr),  // WRAM and ECHO RAM
+            0xFE00..=0xFEFF if self.oam_dma_active() => 0xFF, // OAM is blocked during DMA
             0xFE00..=0xFEFF => self.ppu.read(addr),  // OAM and prohibited
             
-            0xFF00..=0xFF02 | 0xFF04..=0xFF07 => self.cpu.read(addr), // Input, Serial, and Timer/Divider
-            0xFF0F => self.cpu.read(addr),                            // Interrupt Flag
+            0xFF00 => {                                               // Input
+                let data = self.joypad.read(addr);
+                self.joypad.track_read(self.ppu.frames);
+                data
+            },
+            0xFF01..=0xFF02 => self.serial.read(addr),                // Serial
+            0xFF04..=0xFF07 => self.timer.read(addr),                 // Timer/Divider
+            0xFF0F => self.interrupt_flags | 0xE0,                    // Interrupt Flag, upper 3 bits are unused and read as 1
             0xFF10..=0xFF26 | 0xFF30..=0xFF3F => self.apu.read(addr), // Sound and Wave Pattern
+            0xFF46 => self.dma,                                       // OAM DMA
             0xFF40..=0xFF4B | 0xFF4F => self.ppu.read(addr),          // PPU controls and VRAM Bank Select
+            0xFF4D if self.mode == SystemMode::GameboyColorGBC => {   // Prepare Speed Switch
+                ((self.double_speed as u8) << 7) | 0b01111110 | (self.speed_switch_armed as u8)
+            },
+            0xFF4D => 0xFF,
             0xFF50 => self.boot_disabled,                             // Disable boot ROM
-            0xFF51..=0xFF55 | 0xFF68..=0xFF69 => self.ppu.read(addr), // VRAM DMA and BG/OBJ Palettes
+            0xFF55 if self.mode == SystemMode::GameboyColorGBC => {   // VRAM DMA
+                // Bit 7 reads as 0 while an HBlank DMA is active
+                ((!self.hdma_active as u8) << 7) | self.hdma_len
+            },
+            0xFF51..=0xFF55 => 0xFF,
+            0xFF68..=0xFF6B => self.ppu.read(addr),                   // BG/OBJ Palettes
             0xFF70 => self.mem.read(addr),                            // WRAM Bank Select
             0xFF72..=0xFF75 => self.mem.read(addr),                   // Undocumented registers
             0xFF76..=0xFF77 => self.apu.read(addr),                   // Undocumented registers
             
             0xFF80..=0xFFFE => self.mem.read(addr), // HRAM
-            0xFFFF => self.cpu.read(addr), // Interrupt Enable
+            0xFFFF => self.interrupt_enable, // Interrupt Enable
             
-            _ => unimplemented!(),
+            _ => 0xFF, // Unmapped IO, reads as all 1s
         }
     }
 }
 
+/// The original DMG boot ROM, used by [Gameboy::from_rom()].
+pub const DMG_BOOT_ROM: &[u8; 0x100] = include_bytes!("../bootroms/DMG1.rom");
+
+/// Maximum number of instructions [Gameboy::step_back()] can undo.
+pub const STEP_BACK_LIMIT: usize = 256;
+
+/// Copy of the system taken before an instruction was stepped, for [Gameboy::step_back()].
+#[derive(Clone, Debug)]
+struct Snapshot {
+    cpu: Cpu,
+    /// Everything except the cartridge ROM, which never changes and may be several MiB.
+    bus: Bus,
+    tcycles: usize,
+}
+
+/// Identifies save state data, followed by the format version as a little-endian u16.
+#[cfg(feature = "serde")]
+const STATE_MAGIC: &[u8; 4] = b"GBST";
+/// Bumped whenever the save state format changes, so older states are rejected instead of being
+/// decoded as garbage. Version 1 is the header, the ROM's global checksum (little-endian u16) so a
+/// state isn't loaded into a different game, then the t-cycle count, CPU, and bus.
+#[cfg(feature = "serde")]
+pub const STATE_VERSION: u16 = 1;
+
+/// Why [Gameboy::load_state()] failed.
+#[cfg(feature = "serde")]
 #[derive(Debug)]
+pub enum GbError {
+    /// The data doesn't start with the save state header.
+    NotAState,
+    /// The state was saved in a format version that can't be loaded.
+    IncompatibleStateVersion(u16),
+    /// The state was saved with a ROM whose global checksum doesn't match the loaded one.
+    DifferentRom,
+    Decode(bincode::Error),
+}
+#[cfg(feature = "serde")]
+impl std::fmt::Display for GbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotAState => write!(f, "not a save state"),
+            Self::IncompatibleStateVersion(version) => write!(f, "save state is version {}, but only version {} is supported", version, STATE_VERSION),
+            Self::DifferentRom => write!(f, "save state was made with a different ROM"),
+            Self::Decode(err) => write!(f, "failed to decode save state: {}", err),
+        }
+    }
+}
+#[cfg(feature = "serde")]
+impl std::error::Error for GbError {}
+
+/// Why [Gameboy::run_until_break()] stopped.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BreakReason {
+    /// The CPU is about to fetch the instruction at this breakpoint.
+    Breakpoint(u16),
+    /// The t-cycle budget ran out before any breakpoint was reached.
+    CycleLimit,
+    Error(CpuError),
+}
+
 pub struct Gameboy {
-    pub bus: InfCell<Bus>,
+    pub cpu: Cpu,
+    pub bus: Bus,
     pub tcycles: usize,
+    pub breakpoints: HashSet<u16>,
+    /// Set by frontends to stop running the system, e.g. while their window is unfocused.
+    paused: bool,
+    /// Output of [force_render()].
+    forced_frame: Framebuffer,
+    /// States from before each of the most recently stepped instructions, oldest first.
+    snapshots: VecDeque<Snapshot>,
+    /// Called on every PPU mode change, for debugging PPU timing.
+    pub mode_trace: Option<Box<dyn FnMut(ModeTransition)>>,
+}
+impl std::fmt::Debug for Gameboy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Gameboy")
+            .field("cpu", &self.cpu)
+            .field("bus", &self.bus)
+            .field("tcycles", &self.tcycles)
+            .field("breakpoints", &self.breakpoints)
+            .field("paused", &self.paused)
+            .finish_non_exhaustive()
+    }
 }
 impl Gameboy {
     pub fn new(mode: SystemMode) -> Self { Self {
-        bus: InfCell::new(Bus::new(mode)),
+        cpu: Cpu::new(mode),
+        bus: Bus::new(mode),
         tcycles: 0,
+        breakpoints: HashSet::new(),
+        paused: false,
+        forced_frame: Framebuffer::new(),
+        snapshots: VecDeque::new(),
+        mode_trace: None,
     }}
     
+    /// Creates a system for `rom`, with the mode chosen by [CartridgeHeader::system_mode()].
+    /// 
+    /// Starts from the mode's [SystemMode::builtin_boot_rom()]. No CGB boot ROM is built in, so CGB
+    /// mode skips it with [skip_bootrom()].
+    pub fn from_rom(rom: Vec<u8>) -> Self {
+        let mode = CartridgeHeader::parse(&rom).map_or(SystemMode::Gameboy, |header| header.system_mode());
+        
+        let mut gb = Self::new(mode);
+        match mode.builtin_boot_rom() {
+            Some(boot_rom) => gb.bus.boot_rom = boot_rom.to_vec(),
+            None => gb.skip_bootrom(),
+        }
+        gb.bus.cart.load_rom(rom);
+        
+        gb
+    }
+    
+    pub fn mode(&self) -> SystemMode {
+        self.bus.mode
+    }
+    
+    /// Starts at the cartridge entry point (0x0100) with the boot ROM disabled, as if it had just
+    /// finished. The CPU registers already start with the values it leaves behind, and
+    /// [Bus::skip_bootrom()] sets up the IO registers.
+    pub fn skip_bootrom(&mut self) {
+        self.bus.skip_bootrom();
+        self.cpu.regs.pc = 0x0100;
+    }
+    
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+    
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+    
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+    
+    /// Presses or releases a button, requesting the joypad interrupt if that pulls a selected input
+    /// line low.
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        let frame = self.frame_count();
+        if self.bus.joypad.set_button(button, pressed, frame) {
+            self.bus.request_interrupt(Interrupt::Joypad);
+        }
+    }
+    
+    /// Starts or stops measuring the number of frames between [set_button()] pressing a button and
+    /// software reading it. See [InputLatency].
+    pub fn set_input_latency_tracking(&mut self, enabled: bool) {
+        self.bus.joypad.set_latency_tracking(enabled);
+    }
+    
+    pub fn input_latency(&self) -> Option<&InputLatency> {
+        self.bus.joypad.latency()
+    }
+    
     /// Performs one t-cycle on the system.
     /// 
-    /// Note that some components may not do anything until the first of every 4 cycles. While other
+    /// Note that some components may not do anything until the last of every 4 cycles. While other
     /// components may require the precision of t-cycles.
-    pub fn tcycle(&mut self) {
-        let bus = self.bus.get_mut();
-        let passed_bus = self.bus.get_mut();
+    /// 
+    /// In double speed, this is one t-cycle of the PPU and APU, which don't speed up, during which the
+    /// CPU, OAM DMA, timer, and serial port run for two.
+    /// 
+    /// Returns an error if the CPU hit an opcode it can't execute yet. The rest of the system is
+    /// still clocked for this t-cycle.
+    pub fn tcycle(&mut self) -> Result<(), CpuError> {
+        let bus = &mut self.bus;
         
-        bus.cpu.tcycle(passed_bus);
-        bus.ppu.tcycle(passed_bus);
+        let mut result = if bus.hdma_stalled() { Ok(()) } else { self.cpu.tcycle(bus) };
+        let mode = bus.ppu.mode();
+        let (interrupts, transition) = bus.ppu.tcycle();
+        bus.request_interrupt(interrupts);
+        if let (Some(trace), Some(transition)) = (&mut self.mode_trace, transition) {
+            trace(transition);
+        }
+        // Turning the LCD off also puts the PPU in mode 0, but that isn't an HBlank
+        if mode != 0 && bus.ppu.mode() == 0 && bus.ppu.lcdc & 0b10000000 != 0 {
+            bus.hdma_hblank();
+        }
+        bus.oam_dma_tcycle();
+        bus.apu.tcycle();
+        bus.timer_serial_tcycle();
+        
+        if bus.double_speed {
+            let second = if bus.hdma_stalled() { Ok(()) } else { self.cpu.tcycle(bus) };
+            result = result.and(second);
+            bus.oam_dma_tcycle();
+            bus.timer_serial_tcycle();
+        }
         
         self.tcycles += 1;
+        
+        result
     }
     
     /// Simply calls [tcycle()] 4 times.
-    pub fn mcycle(&mut self) {
-        self.tcycle();
-        self.tcycle();
-        self.tcycle();
-        self.tcycle();
+    pub fn mcycle(&mut self) -> Result<(), CpuError> {
+        self.tcycle()?;
+        self.tcycle()?;
+        self.tcycle()?;
+        self.tcycle()
+    }
+    
+    /// Runs until the PPU finishes drawing the current frame and enters VBlank, returning the frame.
+    /// 
+    /// While the LCD is off no frames are drawn, so this gives up after a frame's worth of t-cycles.
+    /// The returned frame is then whatever was last drawn.
+    pub fn run_frame(&mut self) -> Result<&Framebuffer, CpuError> {
+        let frames = self.frame_count();
+        for _ in 0..FRAME_TCYCLES {
+            self.tcycle()?;
+            if self.frame_count() != frames {
+                break;
+            }
+        }
+        
+        Ok(self.bus.ppu.framebuffer())
+    }
+    
+    /// Calls [run_frame()] `count` times, returning the last frame. Useful for running headless,
+    /// e.g. to compare a test ROM's output after a fixed number of frames.
+    pub fn run_frames(&mut self, count: usize) -> Result<&Framebuffer, CpuError> {
+        for _ in 0..count {
+            self.run_frame()?;
+        }
+        
+        Ok(self.bus.ppu.framebuffer())
+    }
+    
+    /// Runs until the APU has at least `samples` interleaved left/right samples buffered (at most
+    /// [BUFFER_LEN]), stopping on the t-cycle that produces the last of them.
+    pub fn run_audio(&mut self, samples: usize) -> Result<(), CpuError> {
+        let samples = samples.min(BUFFER_LEN);
+        while self.bus.apu.buffered() < samples {
+            self.tcycle()?;
+        }
+        
+        Ok(())
+    }
+    
+    /// Enables or disables audio sample generation, see [Apu::set_generation()].
+    pub fn set_audio_generation(&mut self, enabled: bool) {
+        self.bus.apu.set_generation(enabled);
+    }
+    
+    /// Moves buffered audio into `out` as interleaved left/right samples, returning the number of
+    /// samples written. See [Apu::drain()].
+    pub fn drain_audio(&mut self, out: &mut [f32]) -> usize {
+        self.bus.apu.drain(out)
+    }
+    
+    /// Takes the bytes sent over the serial port since the last call. Test ROMs (e.g. blargg's) print
+    /// their results this way.
+    pub fn take_serial_output(&mut self) -> Vec<u8> {
+        self.bus.serial.take_output()
+    }
+    
+    /// Number of frames the PPU has completed.
+    pub fn frame_count(&self) -> u64 {
+        self.bus.ppu.frames
+    }
+    
+    /// Saves the whole system, except the cartridge ROM, so it can be restored by [load_state()].
+    #[cfg(feature = "serde")]
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut data = STATE_MAGIC.to_vec();
+        data.extend_from_slice(&STATE_VERSION.to_le_bytes());
+        data.extend_from_slice(&self.rom_checksum().to_le_bytes());
+        bincode::serialize_into(&mut data, &(self.tcycles, &self.cpu, &self.bus)).unwrap();
+        
+        data
+    }
+    
+    /// Global checksum of the loaded ROM, as recorded in save states.
+    #[cfg(feature = "serde")]
+    fn rom_checksum(&self) -> u16 {
+        self.bus.cart.global_checksum().unwrap_or(0)
+    }
+    
+    /// Restores a state from [save_state()]. The currently loaded ROM is kept, and must be the same
+    /// ROM the state was saved with. Step back history is discarded.
+    #[cfg(feature = "serde")]
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), GbError> {
+        if data.len() < 8 || &data[0..4] != STATE_MAGIC {
+            return Err(GbError::NotAState);
+        }
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        if version != STATE_VERSION {
+            return Err(GbError::IncompatibleStateVersion(version));
+        }
+        if u16::from_le_bytes([data[6], data[7]]) != self.rom_checksum() {
+            return Err(GbError::DifferentRom);
+        }
+        let (tcycles, cpu, bus): (usize, Cpu, Bus) = bincode::deserialize(&data[8..]).map_err(GbError::Decode)?;
+        
+        let rom = std::mem::take(&mut self.bus.cart.rom);
+        self.cpu = cpu;
+        self.bus = bus;
+        self.bus.cart.rom = rom;
+        self.tcycles = tcycles;
+        self.snapshots.clear();
+        
+        Ok(())
+    }
+    
+    /// Runs m-cycles until the CPU retires one instruction, returning the number of t-cycles used.
+    /// 
+    /// Interrupt dispatch counts as an instruction. If the CPU is halted or locked up, only a single
+    /// m-cycle is run since there may be nothing to retire.
+    /// 
+    /// A snapshot is taken first, so that the instruction can be undone with [step_back()].
+    pub fn step_instruction(&mut self) -> Result<usize, CpuError> {
+        self.take_snapshot();
+        
+        let start = self.tcycles;
+        let count = self.cpu.instr_count;
+        
+        loop {
+            self.mcycle()?;
+            
+            let cpu = &self.cpu;
+            if (cpu.instr_count != count && cpu.is_between_instructions()) || cpu.halted || cpu.locked {
+                break;
+            }
+        }
+        
+        Ok(self.tcycles - start)
+    }
+    
+    /// Restores the system to how it was before the last instruction run by [step_instruction()].
+    /// 
+    /// Returns false if there's nothing to step back to. Only the last [STEP_BACK_LIMIT] steps are
+    /// kept, and running the system any other way since then discards them.
+    pub fn step_back(&mut self) -> bool {
+        let snapshot = match self.snapshots.pop_back() {
+            Some(snapshot) => snapshot,
+            None => return false,
+        };
+        
+        let rom = std::mem::take(&mut self.bus.cart.rom);
+        self.cpu = snapshot.cpu;
+        self.bus = snapshot.bus;
+        self.bus.cart.rom = rom;
+        self.tcycles = snapshot.tcycles;
+        
+        true
+    }
+    
+    fn take_snapshot(&mut self) {
+        if self.snapshots.len() == STEP_BACK_LIMIT {
+            self.snapshots.pop_front();
+        }
+        
+        let rom = std::mem::take(&mut self.bus.cart.rom);
+        self.snapshots.push_back(Snapshot { cpu: self.cpu.clone(), bus: self.bus.clone(), tcycles: self.tcycles });
+        self.bus.cart.rom = rom;
+    }
+    
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.insert(addr);
+    }
+    
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.remove(&addr);
+    }
+    
+    /// Runs up to `max_cycles` t-cycles, stopping early when the CPU is about to fetch an
+    /// instruction at a breakpoint.
+    /// 
+    /// A breakpoint at the current PC is ignored for the first instruction, so calling this again
+    /// after hitting a breakpoint will continue past it.
+    pub fn run_until_break(&mut self, max_cycles: usize) -> BreakReason {
+        self.snapshots.clear();
+        
+        for i in 0..max_cycles {
+            let cpu = &self.cpu;
+            if i != 0 && cpu.is_about_to_fetch() && self.breakpoints.contains(&cpu.regs.pc) {
+                return BreakReason::Breakpoint(cpu.regs.pc);
+            }
+            
+            if let Err(err) = self.tcycle() {
+                return BreakReason::Error(err);
+            }
+        }
+        
+        BreakReason::CycleLimit
+    }
+    
+    /// Renders the current contents of VRAM immediately, regardless of where the PPU is in a frame.
+    /// 
+    /// This is meant for tools and tests which set up VRAM directly, and isn't timing accurate.
+    pub fn force_render(&mut self) -> &Framebuffer {
+        self.bus.ppu.render_immediate(&mut self.forced_frame);
+        
+        &self.forced_frame
+    }
+    
+    /// Draws every tile in VRAM, including bank 1 in CGB mode, using the current palette. See
+    /// [Ppu::render_tile_atlas()].
+    pub fn render_tile_atlas(&self) -> Vec<u32> {
+        self.bus.ppu.render_tile_atlas()
+    }
+    
+    /// Writes the tile data in VRAM bank 0 (0x8000-0x97FF) to `path` as a raw 2bpp dump, along with
+    /// the current BG palette as a JASC-PAL file next to it, with the extension changed to `.pal`.
+    pub fn export_tileset<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        let ppu = &self.bus.ppu;
+        std::fs::write(path.as_ref(), &ppu.vram[..0x1800])?;
+        
+        let mut pal = String::from("JASC-PAL\r\n0100\r\n4\r\n");
+        for color in ppu.bg_colors() {
+            let _ = write!(pal, "{} {} {}\r\n", (color >> 16) & 0xFF, (color >> 8) & 0xFF, color & 0xFF);
+        }
+        std::fs::write(path.as_ref().with_extension("pal"), pal)
+    }
+    
+    /// Summarizes the CPU state and recently executed instructions, for attaching to bug reports.
+    pub fn crash_report(&mut self) -> String {
+        let bus = &mut self.bus;
+        let cpu = &self.cpu;
+        let regs = &cpu.regs;
+        let mut report = String::new();
+        
+        if let Some(pc) = cpu.history.back() {
+            let opcode = match cpu.procedure {
+                Some(proc) => proc.opcode,
+                None => bus.peek(*pc),
+            };
+            writeln!(report, "Last instruction: {:04X} (opcode {:02X})", pc, opcode).unwrap();
+        }
+        writeln!(report, "A: {:02X} F: {:02X} B: {:02X} C: {:02X} D: {:02X} E: {:02X} H: {:02X} L: {:02X} SP: {:04X} PC: {:04X}",
+            regs.a, regs.f.bits(), regs.b, regs.c, regs.d, regs.e, regs.h, regs.l, regs.sp, regs.pc
+        ).unwrap();
+        writeln!(report, "IME: {} IE: {:02X} IF: {:02X} halted: {} locked: {}", cpu.ime, bus.interrupt_enable, bus.interrupt_flags, cpu.halted, cpu.locked).unwrap();
+        writeln!(report, "Procedure: {:?}", cpu.procedure).unwrap();
+        writeln!(report, "T-cycles: {} Instructions: {}", self.tcycles, cpu.instr_count).unwrap();
+        
+        writeln!(report, "\nRecent instructions (oldest first):").unwrap();
+        for pc in &cpu.history {
+            writeln!(report, "  {:04X}: {}", pc, disasm::disassemble(bus, *pc).0).unwrap();
+        }
+        
+        report
+    }
+    
+    /// Decodes `count` instructions starting at `addr`, returning each one's address and mnemonic.
+    pub fn disassemble_at(&mut self, addr: u16, count: usize) -> Vec<(u16, String)> {
+        let bus = &mut self.bus;
+        let mut addr = addr;
+        let mut instrs = Vec::with_capacity(count);
+        
+        for _ in 0..count {
+            let (text, len) = disasm::disassemble(bus, addr);
+            instrs.push((addr, text));
+            addr = addr.wrapping_add(len as u16);
+        }
+        
+        instrs
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::ppu::GRAYSCALE_PALETTE;
+    
+    /// A DMG running a `JR -2` loop, with `checksum` as its global checksum.
+    fn looping_gameboy(checksum: u16) -> Gameboy {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+        rom[0x014E..0x0150].copy_from_slice(&checksum.to_be_bytes());
+        
+        let mut gb = Gameboy::from_rom(rom);
+        gb.skip_bootrom();
+        gb
+    }
+    
+    #[test]
+    #[cfg(feature = "serde")]
+    fn state_round_trip() {
+        let mut gb = looping_gameboy(0x1234);
+        gb.run_frames(2).unwrap();
+        gb.bus.mem.wram[0][0x10] = 0x42;
+        gb.bus.double_speed = true;
+        let state = gb.save_state();
+        
+        let mut loaded = looping_gameboy(0x1234);
+        loaded.load_state(&state).unwrap();
+        assert_eq!(loaded.tcycles, gb.tcycles);
+        assert_eq!(loaded.cpu.regs.pc, gb.cpu.regs.pc);
+        assert_eq!(loaded.bus.mem.wram[0][0x10], 0x42);
+        assert_eq!(loaded.frame_count(), 2);
+        assert!(loaded.bus.double_speed);
+        assert!(!loaded.bus.speed_switch_armed);
+        
+        let mut other = looping_gameboy(0x4321);
+        assert!(matches!(other.load_state(&state), Err(GbError::DifferentRom)));
+    }
+    
+    #[test]
+    #[cfg(feature = "serde")]
+    fn state_incompatible_version() {
+        let mut gb = looping_gameboy(0x1234);
+        let mut state = gb.save_state();
+        for version in [0, STATE_VERSION + 1] {
+            state[4..6].copy_from_slice(&version.to_le_bytes());
+            assert!(matches!(gb.load_state(&state), Err(GbError::IncompatibleStateVersion(v)) if v == version));
+        }
+        
+        assert!(matches!(gb.load_state(b"GBSX\x01\x00\x34\x12"), Err(GbError::NotAState)));
+    }
+    
+    #[test]
+    fn skip_bootrom_registers() {
+        for (cgb_flag, dma, bg_color) in [(0x00, 0xFF, 0x00), (0x80, 0x00, 0xFF)] {
+            let mut rom = vec![0; 0x8000];
+            rom[0x143] = cgb_flag;
+            let mut gb = Gameboy::from_rom(rom);
+            gb.skip_bootrom();
+            
+            let bus = &mut gb.bus;
+            assert_eq!(bus.read(0xFF40), 0x91, "LCDC, CGB flag {:02X}", cgb_flag);
+            assert_eq!(bus.read(0xFF41), 0x85, "STAT, CGB flag {:02X}", cgb_flag);
+            assert_eq!(bus.read(0xFF47), 0xFC, "BGP, CGB flag {:02X}", cgb_flag);
+            assert_eq!(bus.read(0xFF46), dma, "DMA, CGB flag {:02X}", cgb_flag);
+            assert_eq!(bus.ppu.bg_palette_ram, [bg_color; 64]);
+            assert_eq!(bus.boot_disabled, 1);
+            assert_eq!(gb.cpu.regs.pc, 0x0100);
+        }
+    }
+    
+    #[test]
+    fn boot_rom_stays_unmapped() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0000] = 0xAA;
+        let mut gb = Gameboy::from_rom(rom);
+        assert_eq!(gb.bus.read(0x0000), DMG_BOOT_ROM[0]);
+        
+        gb.bus.write(0xFF50, 0x01);
+        assert_eq!(gb.bus.read(0x0000), 0xAA);
+        gb.bus.write(0xFF50, 0x00);
+        assert_eq!(gb.bus.read(0x0000), 0xAA);
+    }
+    
+    #[test]
+    fn disassemble_at() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x10B].copy_from_slice(&[
+            0x00, // NOP
+            0x3E, 0x12, // LD A,0x12
+            0x21, 0x00, 0xC0, // LD HL,0xC000
+            0xCB, 0x37, // SWAP A
+            0x18, 0xFE, // JR -2
+            0xD3, // not an opcode
+        ]);
+        let mut gb = Gameboy::from_rom(rom);
+        gb.skip_bootrom();
+        
+        let expected = [(0x0100, "NOP"), (0x0101, "LD A,$12"), (0x0103, "LD HL,$C000"), (0x0106, "SWAP A"), (0x0108, "JR $0108"), (0x010A, "DB $D3")];
+        let instrs = gb.disassemble_at(0x0100, expected.len());
+        assert_eq!(instrs.iter().map(|(addr, text)| (*addr, text.as_str())).collect::<Vec<_>>(), expected);
+        assert_eq!((gb.cpu.regs.pc, gb.tcycles), (0x0100, 0));
+    }
+    
+    #[test]
+    fn force_render_shows_vram() {
+        let mut gb = looping_gameboy(0);
+        // Tile 1 is solid color 3, and goes in the top-left corner of the BG map at 0x9800
+        gb.bus.ppu.vram[0x0010..0x0020].fill(0xFF);
+        gb.bus.ppu.vram[0x1800] = 0x01;
+        
+        let frame = gb.force_render();
+        for y in 0..16 {
+            for x in 0..16 {
+                let expected = if x < 8 && y < 8 { GRAYSCALE_PALETTE[3] } else { GRAYSCALE_PALETTE[0] };
+                assert_eq!(frame.get(x, y), expected, "{},{}", x, y);
+            }
+        }
+        
+        // The timing accurate frame isn't touched, so it's still blank
+        assert_eq!(gb.bus.ppu.framebuffer().get(8, 0), 0);
+    }
+    
+    #[test]
+    fn step_back_restores_cpu() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x10A].copy_from_slice(&[
+            0x3E, 0x12, // LD A,0x12
+            0x3C, // INC A
+            0xC5, // PUSH BC
+            0xEA, 0x00, 0xC0, // LD (0xC000),A
+            0xCD, 0x00, 0x02, // CALL 0x0200
+        ]);
+        let mut gb = Gameboy::from_rom(rom);
+        gb.skip_bootrom();
+        
+        let mut states = vec![];
+        for _ in 0..5 {
+            states.push((format!("{:?}", gb.cpu), gb.tcycles, gb.bus.read(0xC000), gb.bus.read(0xFFFC)));
+            gb.step_instruction().unwrap();
+        }
+        assert_eq!((gb.cpu.regs.pc, gb.bus.read(0xC000)), (0x0200, 0x13));
+        
+        while let Some(state) = states.pop() {
+            assert!(gb.step_back());
+            assert_eq!((format!("{:?}", gb.cpu), gb.tcycles, gb.bus.read(0xC000), gb.bus.read(0xFFFC)), state, "{} steps in", states.len());
+        }
+        assert!(!gb.step_back());
+    }
+    
+    #[test]
+    fn run_frame_advances_one_frame() {
+        let mut gb = looping_gameboy(0);
+        for _ in 0..5 {
+            let frames = gb.frame_count();
+            gb.run_frame().unwrap();
+            assert_eq!(gb.frame_count(), frames + 1);
+            assert_eq!(gb.bus.ppu.read_ly(), 144);
+        }
+    }
+    
+    #[test]
+    fn export_tileset_matches_vram() {
+        let mut gb = looping_gameboy(0);
+        for (i, byte) in gb.bus.ppu.vram.iter_mut().enumerate() {
+            *byte = (i * 7) as u8;
+        }
+        gb.bus.ppu.bgp = 0b11100100;
+        
+        let path = std::env::temp_dir().join(format!("gbcrs-tileset-{}.2bpp", std::process::id()));
+        gb.export_tileset(&path).unwrap();
+        let dump = std::fs::read(&path).unwrap();
+        let pal = std::fs::read_to_string(path.with_extension("pal")).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(path.with_extension("pal")).unwrap();
+        
+        assert_eq!(dump, gb.bus.ppu.vram[..0x1800]);
+        assert_eq!(pal, "JASC-PAL\r\n0100\r\n4\r\n255 255 255\r\n170 170 170\r\n85 85 85\r\n0 0 0\r\n");
+    }
+    
+    #[test]
+    fn run_frames_returns_rendered_frame() {
+        let mut gb = looping_gameboy(0);
+        for (i, byte) in gb.bus.ppu.vram[..0x1000].iter_mut().enumerate() {
+            *byte = (i * 13) as u8;
+        }
+        for (i, tile) in gb.bus.ppu.vram[0x1800..0x1C00].iter_mut().enumerate() {
+            *tile = (i % 251) as u8;
+        }
+        gb.bus.ppu.bgp = 0b11100100;
+        
+        let mut expected = Framebuffer::new();
+        gb.bus.ppu.render_immediate(&mut expected);
+        let frame = gb.run_frames(3).unwrap();
+        assert_eq!(frame, &expected);
+        assert!(frame.pixels().iter().any(|&pixel| pixel != frame.get(0, 0)));
+    }
+    
+    #[test]
+    fn input_latency_frames() {
+        let mut gb = looping_gameboy(0);
+        // Reads P1 into 0xFF80 once per frame, on LY 16
+        gb.bus.cart.rom[0x100..0x112].copy_from_slice(&[
+            0xF0, 0x44, 0xFE, 0x10, 0x20, 0xFA, // wait for LY 16
+            0xF0, 0x00, 0xE0, 0x80, // LDH A,(P1); LDH (0x80),A
+            0xF0, 0x44, 0xFE, 0x10, 0x28, 0xFA, // wait for LY to move on
+            0x18, 0xEE, // start over
+        ]);
+        gb.set_input_latency_tracking(true);
+        gb.run_frames(3).unwrap();
+        
+        // Pressed in VBlank, the next frame's read sees it before the frame counter moves on
+        gb.set_button(Button::A, true);
+        gb.run_frame().unwrap();
+        assert_eq!(gb.bus.peek(0xFF80) & 0x0F, 0b1110);
+        assert_eq!(gb.input_latency().unwrap().last, Some(0));
+        gb.set_button(Button::A, false);
+        
+        // Pressed after LY 16, it isn't seen until the frame after
+        while gb.bus.ppu.read_ly() != 20 {
+            gb.tcycle().unwrap();
+        }
+        gb.set_button(Button::B, true);
+        gb.run_frame().unwrap();
+        assert_eq!(gb.bus.peek(0xFF80) & 0x0F, 0b1111);
+        assert_eq!(gb.input_latency().unwrap().samples, 1);
+        gb.run_frame().unwrap();
+        assert_eq!(gb.bus.peek(0xFF80) & 0x0F, 0b1101);
+        
+        let latency = gb.input_latency().unwrap();
+        assert_eq!((latency.last, latency.min, latency.max, latency.samples), (Some(1), Some(0), Some(1), 2));
+        assert_eq!(latency.average(), Some(0.5));
     }
-}
\ No newline at end of file
+    
+    #[test]
+    fn unmapped_io() {
+        for mode in [SystemMode::Gameboy, SystemMode::GameboyColorGBC] {
+            let mut gb = looping_gameboy(0);
+            gb.bus.mode = mode;
+            for addr in [0xFF03, 0xFF08, 0xFF0E, 0xFF27, 0xFF2F, 0xFF4C, 0xFF4E, 0xFF56, 0xFF67, 0xFF6C, 0xFF71, 0xFF7F] {
+                gb.bus.write(addr, 0x00);
+                assert_eq!(gb.bus.read(addr), 0xFF, "{:04X} in {:?}", addr, mode);
+            }
+            
+            // The PPU only sees its own registers through the bus, but shouldn't panic on others either
+            gb.bus.ppu.write(0xFF46, 0x00);
+            assert_eq!(gb.bus.ppu.read(0xFF46), 0xFF);
+        }
+    }
+    
+    #[test]
+    fn mode_trace() {
+        let transitions = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+        let mut gb = looping_gameboy(0);
+        let traced = transitions.clone();
+        gb.mode_trace = Some(Box::new(move |transition| traced.borrow_mut().push(transition)));
+        
+        gb.run_frame().unwrap();
+        gb.bus.write(0xFF40, 0x00);
+        gb.tcycle().unwrap();
+        
+        let transitions = transitions.borrow();
+        // skip_bootrom() leaves STAT in VBlank, then each visible line goes from mode 2 to 3 to 0, and
+        // on into the next line or VBlank
+        assert_eq!(transitions[0], ModeTransition { from: 1, to: 2, ly: 0, dot: 0 });
+        assert_eq!(transitions.len(), 1 + 3 * 144 + 1);
+        assert_eq!(transitions[transitions.len() - 2], ModeTransition { from: 0, to: 1, ly: 144, dot: 0 });
+        assert_eq!(transitions[transitions.len() - 1], ModeTransition { from: 1, to: 0, ly: 0, dot: 0 });
+    }
+    
+    /// A CGB running `program` from the cartridge entry point, with an HBlank DMA of `blocks` blocks
+    /// set up to copy from 0xC000, where each byte is its offset, to 0x8000.
+    fn hblank_dma_gameboy(program: &[u8], blocks: u8) -> Gameboy {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..(0x100 + program.len())].copy_from_slice(program);
+        rom[0x143] = 0x80;
+        let mut gb = Gameboy::from_rom(rom);
+        gb.skip_bootrom();
+        
+        for i in 0..0x100 {
+            gb.bus.write(0xC000 + i, i as u8);
+        }
+        for (addr, data) in [(0xFF51, 0xC0), (0xFF52, 0x00), (0xFF53, 0x80), (0xFF54, 0x00), (0xFF55, 0x80 | (blocks - 1))] {
+            gb.bus.write(addr, data);
+        }
+        gb
+    }
+    
+    #[test]
+    fn hblank_dma() {
+        let mut gb = hblank_dma_gameboy(&[0x18, 0xFE], 2); // JR -2
+        assert_eq!(gb.bus.read(0xFF55), 0x01);
+        
+        // One block is copied at the start of each HBlank
+        for (copied, hdma5) in [(0x10, 0x00), (0x20, 0xFF)] {
+            while gb.bus.ppu.mode() == 0 {
+                gb.tcycle().unwrap();
+            }
+            while gb.bus.ppu.mode() != 0 {
+                gb.tcycle().unwrap();
+            }
+            let vram = &gb.bus.ppu.vram;
+            assert!((0..copied).all(|i| vram[i] == i as u8), "{:02X?}", &vram[..0x20]);
+            assert!(vram[copied..0x20].iter().all(|&b| b == 0));
+            assert_eq!(gb.bus.read(0xFF55), hdma5);
+        }
+    }
+    
+    #[test]
+    fn hblank_dma_lcd_off() {
+        let mut gb = hblank_dma_gameboy(&[
+            0x18, 0xFE, // JR -2
+            0xAF, // XOR A
+            0xE0, 0x40, // LDH (0x40),A
+            0x18, 0xFE, // JR -2
+        ], 2);
+        while gb.bus.ppu.mode() != 3 {
+            gb.tcycle().unwrap();
+        }
+        while gb.step_instruction().unwrap() > 0 && gb.cpu.regs.pc != 0x100 {}
+        gb.cpu.regs.pc = 0x102;
+        
+        for _ in 0..1000 {
+            gb.tcycle().unwrap();
+        }
+        assert_eq!(gb.bus.ppu.lcdc & 0b10000000, 0);
+        assert!(gb.bus.ppu.vram[..0x20].iter().all(|&b| b == 0));
+        assert_eq!(gb.bus.read(0xFF55), 0x01);
+    }
+    
+    /// Runs an OAM DMA transfer from `page` to completion, without clocking anything else.
+    fn oam_dma(gb: &mut Gameboy, page: u8) {
+        gb.bus.write(0xFF46, page);
+        while gb.bus.oam_dma_active() {
+            gb.bus.oam_dma_tcycle();
+        }
+    }
+    
+    #[test]
+    fn oam_dma_echo_source() {
+        let mut gb = looping_gameboy(0);
+        for i in 0..0xA0 {
+            gb.bus.write(0xC000 + i, (i as u8) ^ 0x5A);
+        }
+        
+        oam_dma(&mut gb, 0xE0);
+        for i in 0..0xA0 {
+            assert_eq!(gb.bus.ppu.oam[i], (i as u8) ^ 0x5A, "OAM {:02X}", i);
+        }
+    }
+    
+    #[test]
+    fn oam_dma_from_vram_in_mode_3() {
+        let mut gb = looping_gameboy(0);
+        while gb.bus.ppu.mode() != 3 {
+            gb.tcycle().unwrap();
+        }
+        for i in 0..0xA0 {
+            gb.bus.ppu.vram[i] = (i as u8) ^ 0xA5;
+        }
+        assert_eq!(gb.bus.read(0x8000), 0xFF);
+        
+        oam_dma(&mut gb, 0x80);
+        for i in 0..0xA0 {
+            assert_eq!(gb.bus.ppu.oam[i], (i as u8) ^ 0xA5, "OAM {:02X}", i);
+        }
+    }
+    
+    #[test]
+    fn oam_dma_register_read_back() {
+        let mut gb = looping_gameboy(0);
+        gb.bus.write(0xFF46, 0xC0);
+        while gb.bus.oam_dma_active() {
+            assert_eq!(gb.bus.read(0xFF46), 0xC0);
+            gb.bus.oam_dma_tcycle();
+        }
+        assert_eq!(gb.bus.read(0xFF46), 0xC0);
+    }
+}
@@ -1,25 +1,933 @@
-
+use std::collections::VecDeque;
 use crate::arch::{BusAccessable, SystemMode};
 
+/// Rate at which samples are produced for [Apu::drain()], in Hz.
+pub const SAMPLE_RATE: u32 = 48000;
+/// T-cycles per second.
+const CLOCK_RATE: u32 = 4194304;
+/// Maximum number of samples buffered before the oldest are dropped, in left/right pairs.
+pub const BUFFER_LEN: usize = (SAMPLE_RATE as usize) / 4 * 2;
+
+/// How much of the high-pass filter's charge is kept each sample. This is 0.999958 per t-cycle, as
+/// measured on the DMG, raised to the number of t-cycles per sample.
+const HIGH_PASS_CHARGE: f32 = 0.996337;
+
+/// Waveforms for each duty cycle (12.5%, 25%, 50%, 75%), played from the most significant bit.
+const DUTY_PATTERNS: [u8; 4] = [0b00000001, 0b10000001, 0b10000111, 0b01111110];
+
+/// Converts a channel's digital output (0-15) to the analog output of its DAC, from 1.0 down to -1.0.
+/// 
+/// A DAC that's off outputs 0.0. One that's on keeps outputting 1.0 even while its channel is off,
+/// which the high-pass filter then removes.
+fn dac_output(dac_enabled: bool, amplitude: u8) -> f32 {
+    if dac_enabled {
+        1.0 - (amplitude as f32 / 7.5)
+    } else {
+        0.0
+    }
+}
+
+/// Volume envelope shared by the square and noise channels, configured by NRx2.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Envelope {
+    volume: u8,
+    timer: u8,
+}
+impl Envelope {
+    fn trigger(&mut self, nr2: u8) {
+        self.volume = nr2 >> 4;
+        self.timer = nr2 & 0b111;
+    }
+    
+    /// Steps the volume up or down every NRx2 bits 0-2 clocks, stopping at 0 or 15. A period of 0
+    /// leaves the volume alone.
+    fn clock(&mut self, nr2: u8) {
+        let period = nr2 & 0b111;
+        if period == 0 {
+            return;
+        }
+        
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = period;
+            if nr2 & 0b1000 != 0 && self.volume < 15 {
+                self.volume += 1;
+            } else if nr2 & 0b1000 == 0 && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+/// Square wave channel, used for both channel 1 (with sweep) and channel 2 (without).
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Square {
+    /// NRx0: Sweep (channel 1 only)
+    nr0: u8,
+    /// NRx1: Duty cycle and length
+    nr1: u8,
+    /// NRx2: Volume envelope
+    nr2: u8,
+    /// NRx3: Period low
+    nr3: u8,
+    /// NRx4: Trigger, length enable, and period high
+    nr4: u8,
+    enabled: bool,
+    length: u8,
+    envelope: Envelope,
+    /// T-cycles until the next step through the duty pattern.
+    period_timer: u16,
+    duty_step: u8,
+    sweep_enabled: bool,
+    sweep_timer: u8,
+    shadow_period: u16,
+}
+impl Square {
+    fn period(&self) -> u16 {
+        (((self.nr4 & 0b111) as u16) << 8) | (self.nr3 as u16)
+    }
+    
+    fn set_period(&mut self, period: u16) {
+        self.nr3 = period as u8;
+        self.nr4 = (self.nr4 & !0b111) | ((period >> 8) as u8 & 0b111);
+    }
+    
+    /// The DAC is on while the upper 5 bits of NRx2 aren't all 0.
+    fn dac_enabled(&self) -> bool {
+        self.nr2 & 0b11111000 != 0
+    }
+    
+    /// Loads the length timer from the lower 6 bits of NRx1.
+    fn load_length(&mut self, data: u8) {
+        self.length = 64 - (data & 0b00111111);
+    }
+    
+    fn write(&mut self, reg: u8, data: u8) {
+        match reg {
+            0 => self.nr0 = data,
+            1 => {
+                self.nr1 = data;
+                self.load_length(data);
+            },
+            2 => {
+                self.nr2 = data;
+                if !self.dac_enabled() {
+                    self.enabled = false;
+                }
+            },
+            3 => self.nr3 = data,
+            4 => {
+                self.nr4 = data;
+                if data & 0b10000000 != 0 {
+                    self.trigger();
+                }
+            },
+            _ => panic!("unreachable")
+        }
+    }
+    
+    /// Reads a register. Write-only bits read as 1.
+    fn read(&self, reg: u8) -> u8 {
+        match reg {
+            0 => self.nr0 | 0b10000000,
+            1 => self.nr1 | 0b00111111,
+            2 => self.nr2,
+            3 => 0xFF,
+            4 => self.nr4 | 0b10111111,
+            _ => panic!("unreachable")
+        }
+    }
+    
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled();
+        if self.length == 0 {
+            self.length = 64;
+        }
+        self.period_timer = (2048 - self.period()) * 4;
+        self.envelope.trigger(self.nr2);
+        
+        let sweep_period = (self.nr0 & 0b01110000) >> 4;
+        let sweep_shift = self.nr0 & 0b111;
+        self.shadow_period = self.period();
+        self.sweep_timer = if sweep_period == 0 { 8 } else { sweep_period };
+        self.sweep_enabled = sweep_period != 0 || sweep_shift != 0;
+        if sweep_shift != 0 {
+            self.sweep_calculate();
+        }
+    }
+    
+    /// Calculates the next sweep period, disabling the channel if it overflows past 2047.
+    fn sweep_calculate(&mut self) -> u16 {
+        let delta = self.shadow_period >> (self.nr0 & 0b111);
+        let period = if self.nr0 & 0b1000 != 0 {
+            self.shadow_period - delta
+        } else {
+            self.shadow_period + delta
+        };
+        
+        if period > 2047 {
+            self.enabled = false;
+        }
+        
+        period
+    }
+    
+    fn tcycle(&mut self) {
+        if self.period_timer > 0 {
+            self.period_timer -= 1;
+        }
+        if self.period_timer == 0 {
+            self.period_timer = (2048 - self.period()) * 4;
+            self.duty_step = (self.duty_step + 1) % 8;
+        }
+    }
+    
+    fn clock_length(&mut self) {
+        if self.nr4 & 0b01000000 != 0 && self.length > 0 {
+            self.length -= 1;
+            if self.length == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+    
+    fn clock_envelope(&mut self) {
+        self.envelope.clock(self.nr2);
+    }
+    
+    fn clock_sweep(&mut self) {
+        self.sweep_timer = self.sweep_timer.saturating_sub(1);
+        if self.sweep_timer > 0 {
+            return;
+        }
+        
+        let sweep_period = (self.nr0 & 0b01110000) >> 4;
+        self.sweep_timer = if sweep_period == 0 { 8 } else { sweep_period };
+        
+        if self.sweep_enabled && sweep_period != 0 {
+            let period = self.sweep_calculate();
+            if period <= 2047 && self.nr0 & 0b111 != 0 {
+                self.shadow_period = period;
+                self.set_period(period);
+                // The new period is checked for overflow again, but isn't used
+                self.sweep_calculate();
+            }
+        }
+    }
+    
+    /// Current digital output, 0-15.
+    fn amplitude(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        
+        let duty = (self.nr1 >> 6) as usize;
+        let high = (DUTY_PATTERNS[duty] >> (7 - self.duty_step)) & 0b1;
+        
+        high * self.envelope.volume
+    }
+}
+
+/// Wave channel (channel 3), which plays back 32 4-bit samples from wave RAM.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Wave {
+    /// NR30: DAC enable
+    nr0: u8,
+    /// NR31: Length
+    nr1: u8,
+    /// NR32: Output level
+    nr2: u8,
+    /// NR33: Period low
+    nr3: u8,
+    /// NR34: Trigger, length enable, and period high
+    nr4: u8,
+    /// Wave pattern RAM (0xFF30-0xFF3F), two samples per byte with the upper nibble played first.
+    ram: [u8; 16],
+    enabled: bool,
+    length: u16,
+    /// T-cycles until the next sample is read.
+    period_timer: u16,
+    /// Index of the sample being played, 0-31.
+    position: u8,
+    /// Last sample read from wave RAM.
+    sample: u8,
+}
+impl Wave {
+    fn period(&self) -> u16 {
+        (((self.nr4 & 0b111) as u16) << 8) | (self.nr3 as u16)
+    }
+    
+    /// The DAC is on while NR30 bit 7 is set.
+    fn dac_enabled(&self) -> bool {
+        self.nr0 & 0b10000000 != 0
+    }
+    
+    /// Loads the length timer from NR31.
+    fn load_length(&mut self, data: u8) {
+        self.length = 256 - (data as u16);
+    }
+    
+    fn write(&mut self, reg: u8, data: u8) {
+        match reg {
+            0 => {
+                self.nr0 = data;
+                if !self.dac_enabled() {
+                    self.enabled = false;
+                }
+            },
+            1 => {
+                self.nr1 = data;
+                self.load_length(data);
+            },
+            2 => self.nr2 = data,
+            3 => self.nr3 = data,
+            4 => {
+                self.nr4 = data;
+                if data & 0b10000000 != 0 {
+                    self.trigger();
+                }
+            },
+            _ => panic!("unreachable")
+        }
+    }
+    
+    /// Reads a register. Write-only and unused bits read as 1.
+    fn read(&self, reg: u8) -> u8 {
+        match reg {
+            0 => self.nr0 | 0b01111111,
+            1 => 0xFF,
+            2 => self.nr2 | 0b10011111,
+            3 => 0xFF,
+            4 => self.nr4 | 0b10111111,
+            _ => panic!("unreachable")
+        }
+    }
+    
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled();
+        if self.length == 0 {
+            self.length = 256;
+        }
+        self.period_timer = (2048 - self.period()) * 2;
+        self.position = 0;
+    }
+    
+    fn tcycle(&mut self) {
+        if self.period_timer > 0 {
+            self.period_timer -= 1;
+        }
+        if self.period_timer == 0 {
+            self.period_timer = (2048 - self.period()) * 2;
+            self.position = (self.position + 1) % 32;
+            
+            let byte = self.ram[(self.position / 2) as usize];
+            self.sample = if self.position.is_multiple_of(2) { byte >> 4 } else { byte & 0x0F };
+        }
+    }
+    
+    fn clock_length(&mut self) {
+        if self.nr4 & 0b01000000 != 0 && self.length > 0 {
+            self.length -= 1;
+            if self.length == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+    
+    /// Current digital output, 0-15. NR32 bits 5-6 select mute, 100%, 50%, or 25% volume.
+    fn amplitude(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        
+        match (self.nr2 & 0b01100000) >> 5 {
+            0 => 0,
+            shift => self.sample >> (shift - 1),
+        }
+    }
+    
+    /// Index of the wave RAM byte the CPU actually accesses at `addr`.
+    /// 
+    /// While the channel is playing, any access goes to the byte currently being played instead. On
+    /// DMG hardware the access only works on the exact cycle the channel reads it, which isn't
+    /// emulated, so it's blocked entirely (reads return 0xFF).
+    fn ram_index(&self, mode: SystemMode, addr: u16) -> Option<usize> {
+        if !self.enabled {
+            Some((addr - 0xFF30) as usize)
+        } else if mode.is_cgb_hardware() {
+            Some((self.position / 2) as usize)
+        } else {
+            None
+        }
+    }
+}
+
+/// Divisors selected by NR43 bits 0-2, in t-cycles. The LFSR is clocked every `divisor << shift`.
+const NOISE_DIVISORS: [u16; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+/// Noise channel (channel 4), which outputs the low bit of a linear feedback shift register.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Noise {
+    /// NR41: Length
+    nr1: u8,
+    /// NR42: Volume envelope
+    nr2: u8,
+    /// NR43: Clock shift, LFSR width, and clock divider
+    nr3: u8,
+    /// NR44: Trigger and length enable
+    nr4: u8,
+    enabled: bool,
+    length: u8,
+    envelope: Envelope,
+    /// T-cycles until the LFSR is next clocked.
+    period_timer: u32,
+    /// 15-bit linear feedback shift register.
+    lfsr: u16,
+}
+impl Noise {
+    fn period(&self) -> u32 {
+        (NOISE_DIVISORS[(self.nr3 & 0b111) as usize] as u32) << (self.nr3 >> 4)
+    }
+    
+    /// The DAC is on while the upper 5 bits of NR42 aren't all 0.
+    fn dac_enabled(&self) -> bool {
+        self.nr2 & 0b11111000 != 0
+    }
+    
+    /// Loads the length timer from the lower 6 bits of NR41.
+    fn load_length(&mut self, data: u8) {
+        self.length = 64 - (data & 0b00111111);
+    }
+    
+    fn write(&mut self, reg: u8, data: u8) {
+        match reg {
+            1 => {
+                self.nr1 = data;
+                self.load_length(data);
+            },
+            2 => {
+                self.nr2 = data;
+                if !self.dac_enabled() {
+                    self.enabled = false;
+                }
+            },
+            3 => self.nr3 = data,
+            4 => {
+                self.nr4 = data;
+                if data & 0b10000000 != 0 {
+                    self.trigger();
+                }
+            },
+            _ => panic!("unreachable")
+        }
+    }
+    
+    /// Reads a register. Write-only bits read as 1.
+    fn read(&self, reg: u8) -> u8 {
+        match reg {
+            1 => 0xFF,
+            2 => self.nr2,
+            3 => self.nr3,
+            4 => self.nr4 | 0b10111111,
+            _ => panic!("unreachable")
+        }
+    }
+    
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled();
+        if self.length == 0 {
+            self.length = 64;
+        }
+        self.period_timer = self.period();
+        self.envelope.trigger(self.nr2);
+        self.lfsr = 0x7FFF;
+    }
+    
+    /// Shifts the LFSR right by one, feeding bit 0 XOR bit 1 into bit 14. In 7-bit mode (NR43 bit 3)
+    /// it's also fed into bit 6, which shortens the sequence to 127 steps.
+    fn clock_lfsr(&mut self) {
+        let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 0b1;
+        self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+        if self.nr3 & 0b1000 != 0 {
+            self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+        }
+    }
+    
+    fn tcycle(&mut self) {
+        if self.period_timer > 0 {
+            self.period_timer -= 1;
+        }
+        if self.period_timer == 0 {
+            self.period_timer = self.period();
+            self.clock_lfsr();
+        }
+    }
+    
+    fn clock_length(&mut self) {
+        if self.nr4 & 0b01000000 != 0 && self.length > 0 {
+            self.length -= 1;
+            if self.length == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+    
+    fn clock_envelope(&mut self) {
+        self.envelope.clock(self.nr2);
+    }
+    
+    /// Current digital output, 0-15. The channel is high while bit 0 of the LFSR is clear.
+    fn amplitude(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        
+        ((!self.lfsr & 0b1) as u8) * self.envelope.volume
+    }
+}
+
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Apu {
-    
+    mode: SystemMode,
+    ch1: Square,
+    ch2: Square,
+    ch3: Wave,
+    ch4: Noise,
+    /// Master Volume & VIN Panning (0xFF24) (R/W). Bits 4-6 are the left volume, and bits 0-2 the right.
+    pub nr50: u8,
+    /// Sound Panning (0xFF25) (R/W). Bits 4-7 send channels 1-4 to the left, and bits 0-3 to the right.
+    pub nr51: u8,
+    /// Whether the APU is powered on, from NR52 (0xFF26) bit 7.
+    pub power: bool,
+    /// Frame sequencer step, 0-7. Length is clocked on even steps, sweep on 2 and 6, and the
+    /// envelopes on 7.
+    sequencer_step: u8,
+    /// Accumulates [SAMPLE_RATE] each t-cycle, producing a sample every time it passes [CLOCK_RATE].
+    sample_timer: u32,
+    /// Charge left on the left and right outputs' high-pass filter capacitors.
+    capacitors: [f32; 2],
+    /// Interleaved left/right samples from -1.0 to 1.0, oldest first.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    samples: VecDeque<f32>,
+    /// Set by [Apu::set_generation()] to stop clocking the channels and only produce silence.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    generation_disabled: bool,
+    /// Highest amplitude of each channel in the samples produced since the last [Apu::drain()].
+    #[cfg_attr(feature = "serde", serde(skip))]
+    peaks: [f32; 4],
+    /// Each channel's peak in the last batch of samples drained, see [Apu::channel_levels()].
+    #[cfg_attr(feature = "serde", serde(skip))]
+    levels: [f32; 4],
 }
 impl Apu {
-    pub fn new() -> Self { Self {
-        
+    pub fn new(mode: SystemMode) -> Self { Self {
+        mode,
+        ch1: Square::default(),
+        ch2: Square::default(),
+        ch3: Wave::default(),
+        ch4: Noise::default(),
+        nr50: 0,
+        nr51: 0,
+        power: false,
+        sequencer_step: 0,
+        sample_timer: 0,
+        capacitors: [0.0; 2],
+        samples: VecDeque::with_capacity(BUFFER_LEN),
+        generation_disabled: false,
+        peaks: [0.0; 4],
+        levels: [0.0; 4],
     }}
+    
+    /// Performs one t-cycle, advancing the channels and producing samples at [SAMPLE_RATE]. The
+    /// frame sequencer is stepped separately by [Apu::div_apu()].
+    pub fn tcycle(&mut self) {
+        if !self.generation_disabled {
+            self.ch1.tcycle();
+            self.ch2.tcycle();
+            self.ch3.tcycle();
+            self.ch4.tcycle();
+        }
+        
+        self.sample_timer += SAMPLE_RATE;
+        if self.sample_timer >= CLOCK_RATE {
+            self.sample_timer -= CLOCK_RATE;
+            
+            if self.samples.len() == BUFFER_LEN {
+                self.samples.drain(..2);
+            }
+            let (left, right) = if self.generation_disabled { (0.0, 0.0) } else { self.sample() };
+            self.samples.push_back(left);
+            self.samples.push_back(right);
+        }
+    }
+    
+    /// Steps the frame sequencer, which happens each time DIV bit 4 falls. Since it follows DIV,
+    /// writing DIV can also step it early.
+    pub fn div_apu(&mut self) {
+        if self.sequencer_step & 0b1 == 0 {
+            self.ch1.clock_length();
+            self.ch2.clock_length();
+            self.ch3.clock_length();
+            self.ch4.clock_length();
+        }
+        if self.sequencer_step == 2 || self.sequencer_step == 6 {
+            self.ch1.clock_sweep();
+        }
+        if self.sequencer_step == 7 {
+            self.ch1.clock_envelope();
+            self.ch2.clock_envelope();
+            self.ch4.clock_envelope();
+        }
+        
+        self.sequencer_step = (self.sequencer_step + 1) % 8;
+    }
+    
+    /// Mixes the channels' DAC outputs into a left/right pair of samples, using NR51's panning and
+    /// NR50's volumes, then passes them through the high-pass filter. Headroom is left for all four
+    /// channels playing at once.
+    fn sample(&mut self) -> (f32, f32) {
+        let amplitudes = [self.ch1.amplitude(), self.ch2.amplitude(), self.ch3.amplitude(), self.ch4.amplitude()];
+        for (peak, &amplitude) in self.peaks.iter_mut().zip(amplitudes.iter()) {
+            *peak = peak.max(amplitude as f32 / 15.0);
+        }
+        
+        let outputs = [
+            dac_output(self.ch1.dac_enabled(), amplitudes[0]),
+            dac_output(self.ch2.dac_enabled(), amplitudes[1]),
+            dac_output(self.ch3.dac_enabled(), amplitudes[2]),
+            dac_output(self.ch4.dac_enabled(), amplitudes[3]),
+        ];
+        let mix = |pan: u8, volume: u8| {
+            let sum = outputs.iter().enumerate()
+                .filter(|(i, _)| pan & (1 << i) != 0)
+                .map(|(_, &output)| output)
+                .sum::<f32>();
+            
+            (sum / 4.0) * ((volume & 0b111) as f32 + 1.0) / 8.0
+        };
+        
+        let left = self.high_pass(0, mix(self.nr51 >> 4, self.nr50 >> 4));
+        let right = self.high_pass(1, mix(self.nr51 & 0x0F, self.nr50));
+        
+        (left.clamp(-1.0, 1.0), right.clamp(-1.0, 1.0))
+    }
+    
+    /// Removes the DC offset from one output, like the capacitor on the real hardware's output.
+    fn high_pass(&mut self, output: usize, input: f32) -> f32 {
+        let filtered = input - self.capacitors[output];
+        self.capacitors[output] = input - (filtered * HIGH_PASS_CHARGE);
+        
+        filtered
+    }
+    
+    /// Sound on/off (0xFF26). Bit 7 is the power, and bits 0-3 report whether channels 1-4 are on.
+    fn nr52(&self) -> u8 {
+        let channels = (self.ch1.enabled as u8)
+            | ((self.ch2.enabled as u8) << 1)
+            | ((self.ch3.enabled as u8) << 2)
+            | ((self.ch4.enabled as u8) << 3);
+        
+        ((self.power as u8) << 7) | 0b01110000 | channels
+    }
+    
+    /// Powers the APU on or off. Powering off clears every register except wave RAM, and they can't
+    /// be written until it's powered back on. On DMG hardware the length timers are also kept, and
+    /// can still be loaded through NRx1.
+    fn set_power(&mut self, power: bool) {
+        if !power {
+            let ram = self.ch3.ram;
+            let dmg = !self.mode.is_cgb_hardware();
+            self.ch1 = Square { length: if dmg { self.ch1.length } else { 0 }, ..Square::default() };
+            self.ch2 = Square { length: if dmg { self.ch2.length } else { 0 }, ..Square::default() };
+            self.ch3 = Wave { ram, length: if dmg { self.ch3.length } else { 0 }, ..Wave::default() };
+            self.ch4 = Noise { length: if dmg { self.ch4.length } else { 0 }, ..Noise::default() };
+            self.nr50 = 0;
+            self.nr51 = 0;
+        } else if !self.power {
+            self.sequencer_step = 0;
+        }
+        
+        self.power = power;
+    }
+    
+    /// Enables or disables sample generation, which is enabled by default. Disabling it saves time
+    /// when nothing is listening, e.g. running test ROMs headless.
+    /// 
+    /// While disabled, the channels' waveforms aren't advanced and only silence is produced, at the
+    /// usual rate. Registers, triggers, and the frame sequencer still work as normal, so NR52 keeps
+    /// reporting which channels are on. Only PCM12/PCM34 and wave RAM reads while channel 3 is
+    /// playing, which depend on the waveform position, are affected.
+    pub fn set_generation(&mut self, enabled: bool) {
+        self.generation_disabled = !enabled;
+    }
+    
+    /// Number of interleaved left/right samples waiting to be drained.
+    pub fn buffered(&self) -> usize {
+        self.samples.len()
+    }
+    
+    /// Output level of each channel, from 0.0 to 1.0, before panning and mixing. This is the highest
+    /// amplitude each channel reached over the samples produced before the last [Apu::drain()], so a
+    /// frontend can draw a VU meter by reading it after each batch it drains.
+    /// 
+    /// Channels that are off, and every channel while generation is disabled, read 0.0.
+    pub fn channel_levels(&self) -> [f32; 4] {
+        self.levels
+    }
+    
+    /// Moves as many buffered samples as will fit into `out` as interleaved left/right pairs, oldest
+    /// first, returning how many were written.
+    pub fn drain(&mut self, out: &mut [f32]) -> usize {
+        self.levels = std::mem::take(&mut self.peaks);
+        let len = out.len().min(self.samples.len()) & !0b1;
+        for (dst, src) in out.iter_mut().zip(self.samples.drain(..len)) {
+            *dst = src;
+        }
+        
+        len
+    }
 }
 
 impl BusAccessable for Apu {
     fn write(&mut self, addr: u16, data: u8) {
         match addr {
+            // The length timers keep running on DMG hardware while powered off, so they can still be loaded
+            0xFF11 if !self.power && !self.mode.is_cgb_hardware() => self.ch1.load_length(data),
+            0xFF16 if !self.power && !self.mode.is_cgb_hardware() => self.ch2.load_length(data),
+            0xFF1B if !self.power && !self.mode.is_cgb_hardware() => self.ch3.load_length(data),
+            0xFF20 if !self.power && !self.mode.is_cgb_hardware() => self.ch4.load_length(data),
+            0xFF10..=0xFF25 if !self.power => (),
+            0xFF10..=0xFF14 => self.ch1.write((addr - 0xFF10) as u8, data),
+            0xFF16..=0xFF19 => self.ch2.write((addr - 0xFF15) as u8, data),
+            0xFF1A..=0xFF1E => self.ch3.write((addr - 0xFF1A) as u8, data),
+            0xFF20..=0xFF23 => self.ch4.write((addr - 0xFF1F) as u8, data),
+            0xFF24 => self.nr50 = data,
+            0xFF25 => self.nr51 = data,
+            0xFF26 => self.set_power(data & 0b10000000 != 0),
+            0xFF30..=0xFF3F => {
+                if let Some(i) = self.ch3.ram_index(self.mode, addr) {
+                    self.ch3.ram[i] = data;
+                }
+            },
             _ => (),
-            _ => todo!("write {:#04X} to {:#06X}", data, addr)
         }
     }
 
     fn read(&mut self, addr: u16) -> u8 {
-        todo!("read from {:#06X}", addr)
+        match addr {
+            0xFF10..=0xFF14 => self.ch1.read((addr - 0xFF10) as u8),
+            0xFF16..=0xFF19 => self.ch2.read((addr - 0xFF15) as u8),
+            0xFF1A..=0xFF1E => self.ch3.read((addr - 0xFF1A) as u8),
+            0xFF20..=0xFF23 => self.ch4.read((addr - 0xFF1F) as u8),
+            0xFF24 => self.nr50,
+            0xFF25 => self.nr51,
+            0xFF26 => self.nr52(),
+            0xFF30..=0xFF3F => match self.ch3.ram_index(self.mode, addr) {
+                Some(i) => self.ch3.ram[i],
+                None => 0xFF,
+            },
+            0xFF76 => (self.ch2.amplitude() << 4) | self.ch1.amplitude(), // PCM12
+            0xFF77 => (self.ch4.amplitude() << 4) | self.ch3.amplitude(), // PCM34
+            _ => 0xFF,
+        }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn disabled_generation_keeps_nr52_and_outputs_silence() {
+        let mut apu = Apu::new(SystemMode::Gameboy);
+        apu.set_generation(false);
+        apu.write(0xFF26, 0x80);
+        apu.write(0xFF24, 0x77);
+        apu.write(0xFF25, 0xFF);
+        apu.write(0xFF17, 0xF0); // channel 2 at full volume
+        apu.write(0xFF16, 0x80 | 62); // 50% duty, length of 2
+        apu.write(0xFF19, 0x80 | 0x40); // trigger with length enabled
+        assert_eq!(apu.read(0xFF26), 0xF2);
+        
+        for _ in 0..10000 {
+            apu.tcycle();
+        }
+        let mut out = [1.0f32; 512];
+        let len = apu.drain(&mut out);
+        assert!(len > 0);
+        assert!(out[..len].iter().all(|&sample| sample == 0.0));
+        
+        // Length still runs out on the frame sequencer
+        apu.div_apu();
+        assert_eq!(apu.read(0xFF26), 0xF2);
+        apu.div_apu();
+        apu.div_apu();
+        assert_eq!(apu.read(0xFF26), 0xF0);
+    }
+    
+    /// Per channel: NRx1 address, a write enabling the DAC, NRx4 address, and the length loaded by
+    /// writing 0x00 to NRx1.
+    const CHANNELS: [(u16, (u16, u8), u16, u16); 4] = [
+        (0xFF11, (0xFF12, 0xF0), 0xFF14, 64),
+        (0xFF16, (0xFF17, 0xF0), 0xFF19, 64),
+        (0xFF1B, (0xFF1A, 0x80), 0xFF1E, 256),
+        (0xFF20, (0xFF21, 0xF0), 0xFF23, 64),
+    ];
+    
+    /// Length timer clocks after triggering channel `ch` (with length enabled) until it turns off.
+    fn length_clocks(apu: &mut Apu, ch: usize) -> u16 {
+        let (_, (dac, dac_data), nr4, _) = CHANNELS[ch];
+        apu.write(dac, dac_data);
+        apu.write(nr4, 0x80 | 0x40);
+        assert_ne!(apu.read(0xFF26) & (1 << ch), 0, "channel {} didn't start", ch + 1);
+        
+        let mut clocks = 0;
+        while apu.read(0xFF26) & (1 << ch) != 0 {
+            assert!(clocks <= 256, "channel {} never stopped", ch + 1);
+            if apu.sequencer_step & 0b1 == 0 {
+                clocks += 1;
+            }
+            apu.div_apu();
+        }
+        clocks
+    }
+    
+    #[test]
+    fn length_loaded_while_channel_off() {
+        for (ch, &(nr1, _, _, _)) in CHANNELS.iter().enumerate() {
+            let mut apu = Apu::new(SystemMode::Gameboy);
+            apu.write(0xFF26, 0x80);
+            apu.write(nr1, 0x3A);
+            assert_eq!(apu.read(0xFF26) & (1 << ch), 0);
+            
+            let expected = if ch == 2 { 256 - 0x3A } else { 64 - 0x3A };
+            assert_eq!(length_clocks(&mut apu, ch), expected, "channel {}", ch + 1);
+        }
+    }
+    
+    #[test]
+    fn length_loaded_while_powered_off() {
+        for (ch, &(nr1, _, _, _)) in CHANNELS.iter().enumerate() {
+            let mut apu = Apu::new(SystemMode::Gameboy);
+            apu.write(nr1, 0x3A);
+            apu.write(0xFF26, 0x80);
+            
+            let expected = if ch == 2 { 256 - 0x3A } else { 64 - 0x3A };
+            assert_eq!(length_clocks(&mut apu, ch), expected, "channel {}", ch + 1);
+            // Only the length is written, not the duty cycle
+            assert_eq!(apu.read(nr1) & 0b11000000, if ch == 2 || ch == 3 { 0b11000000 } else { 0 });
+        }
+    }
+    
+    #[test]
+    fn length_ignored_while_powered_off_on_cgb() {
+        for (ch, &(nr1, _, _, full)) in CHANNELS.iter().enumerate() {
+            let mut apu = Apu::new(SystemMode::GameboyColorDMG);
+            apu.write(nr1, 0x3A);
+            apu.write(0xFF26, 0x80);
+            
+            // Triggering with a length of 0 loads the full length
+            assert_eq!(length_clocks(&mut apu, ch), full, "channel {}", ch + 1);
+        }
+    }
+    
+    #[test]
+    fn channel_levels() {
+        let mut apu = Apu::new(SystemMode::Gameboy);
+        apu.write(0xFF26, 0x80);
+        apu.write(0xFF17, 0xF0); // channel 2 at full volume
+        apu.write(0xFF16, 0x80); // 50% duty
+        apu.write(0xFF19, 0x80); // trigger
+        
+        for _ in 0..10000 {
+            apu.tcycle();
+        }
+        let mut out = [0.0f32; BUFFER_LEN];
+        apu.drain(&mut out);
+        assert_eq!(apu.channel_levels(), [0.0, 1.0, 0.0, 0.0]);
+        
+        // Silenced channels drop back to 0 on the next batch
+        apu.write(0xFF17, 0x00);
+        for _ in 0..10000 {
+            apu.tcycle();
+        }
+        apu.drain(&mut out);
+        assert_eq!(apu.channel_levels(), [0.0; 4]);
+    }
+    
+    #[test]
+    fn dac_off_clears_nr52_bit() {
+        for (ch, &(_, (dac, dac_data), nr4, _)) in CHANNELS.iter().enumerate() {
+            let mut apu = Apu::new(SystemMode::Gameboy);
+            apu.write(0xFF26, 0x80);
+            apu.write(dac, dac_data);
+            apu.write(nr4, 0x80);
+            assert_eq!(apu.read(0xFF26), 0xF0 | (1 << ch), "channel {} didn't start", ch + 1);
+            
+            // For NRx2, volume 0 with the envelope increasing still leaves the DAC on
+            if ch != 2 {
+                apu.write(dac, 0x08);
+                assert_eq!(apu.read(0xFF26), 0xF0 | (1 << ch), "channel {} stopped by 0x08", ch + 1);
+            }
+            
+            let off = if ch == 2 { 0x00 } else { 0x07 };
+            apu.write(dac, off);
+            assert_eq!(apu.read(0xFF26), 0xF0, "channel {} still on with its DAC off", ch + 1);
+            
+            // Nor can it be triggered again until the DAC is back on
+            apu.write(nr4, 0x80);
+            assert_eq!(apu.read(0xFF26), 0xF0, "channel {} triggered with its DAC off", ch + 1);
+        }
+    }
+    
+    #[test]
+    fn wave_plays_ram_in_order() {
+        // Ramps up from 0 to 15, then back down
+        let ramp: Vec<u8> = (0..16).chain((0..16).rev()).collect();
+        let mut wave = Wave::default();
+        for (i, pair) in ramp.chunks(2).enumerate() {
+            wave.ram[i] = (pair[0] << 4) | pair[1];
+        }
+        wave.write(0, 0x80);
+        wave.write(2, 0x20); // 100% volume
+        wave.write(3, 0xFF);
+        wave.write(4, 0x87); // trigger with a period of 2047, one sample every 2 t-cycles
+        
+        // Triggering doesn't reload the sample, so playback starts at the second one
+        let samples: Vec<u8> = (0..32).map(|_| {
+            wave.tcycle();
+            wave.tcycle();
+            wave.amplitude()
+        }).collect();
+        let expected: Vec<u8> = (0..32).map(|i| ramp[(i + 1) % 32]).collect();
+        assert_eq!(samples, expected);
+    }
+    
+    #[test]
+    fn noise_lfsr_sequence() {
+        // Bit 0 after each of the first 32 clocks, and the length of the full sequence, for each width
+        for (nr3, bits, steps) in [
+            (0x00, "11111111111111000000000000001000", 32767),
+            (0x08, "11111100000010000011000010100011", 127),
+        ] {
+            let mut noise = Noise { nr3, lfsr: 0x7FFF, ..Noise::default() };
+            let pattern: String = (0..32).map(|_| {
+                noise.clock_lfsr();
+                if noise.lfsr & 0b1 != 0 { '1' } else { '0' }
+            }).collect();
+            assert_eq!(pattern, bits, "NR43 {:02X}", nr3);
+            
+            let state = noise.lfsr;
+            let period = (1..).find(|_| {
+                noise.clock_lfsr();
+                noise.lfsr == state
+            }).unwrap();
+            assert_eq!(period, steps, "NR43 {:02X}", nr3);
+        }
+    }
+}
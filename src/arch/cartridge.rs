@@ -1,28 +1,263 @@
 
 use crate::arch::{BusAccessable, SystemMode};
+use crate::arch::mbc::{self, Mbc, NoMbc};
+
+/// Cartridge header fields, parsed from 0x0100-0x014F.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CartridgeHeader {
+    /// Upper case ASCII title, up to 16 characters (fewer on newer cartridges which reuse the tail
+    /// for the manufacturer code and CGB flag).
+    pub title: String,
+    /// 0x0143: 0x80 supports CGB enhancements, 0xC0 is CGB only.
+    pub cgb_flag: u8,
+    /// 0x0146: 0x03 supports SGB functions.
+    pub sgb_flag: u8,
+    /// 0x0147: Memory bank controller and any additional hardware.
+    pub cartridge_type: u8,
+    /// 0x0148: ROM size is `32 KiB << rom_size`.
+    pub rom_size: u8,
+    /// 0x0149: External RAM size code.
+    pub ram_size: u8,
+    /// 0x014A: 0x00 is Japan, 0x01 is overseas.
+    pub destination: u8,
+    /// 0x014D: Checksum of 0x0134-0x014C, which the boot ROM verifies.
+    pub header_checksum: u8,
+    pub header_checksum_valid: bool,
+}
+impl CartridgeHeader {
+    /// Parses the header from a full ROM image, returning None if it's shorter than 0x150 bytes.
+    pub fn parse(rom: &[u8]) -> Option<Self> {
+        let header = rom.get(0x0100..0x0150)?;
+        let byte = |addr: usize| header[addr - 0x0100];
+        
+        let title = header[0x34..0x44].iter()
+            .take_while(|b| **b != 0 && b.is_ascii())
+            .map(|b| *b as char)
+            .collect::<String>()
+            .trim_end()
+            .to_owned();
+        
+        let checksum = header[0x34..=0x4C].iter()
+            .fold(0u8, |x, b| x.wrapping_sub(*b).wrapping_sub(1));
+        
+        Some(Self {
+            title,
+            cgb_flag: byte(0x0143),
+            sgb_flag: byte(0x0146),
+            cartridge_type: byte(0x0147),
+            rom_size: byte(0x0148),
+            ram_size: byte(0x0149),
+            destination: byte(0x014A),
+            header_checksum: byte(0x014D),
+            header_checksum_valid: checksum == byte(0x014D),
+        })
+    }
+    
+    /// Size of the ROM in bytes, as declared by the header.
+    pub fn rom_bytes(&self) -> usize {
+        (32 * 1024) << self.rom_size
+    }
+    
+    /// The system the cartridge is best run on: CGB mode if it supports the CGB (CGB flag 0x80 or 0xC0),
+    /// otherwise a DMG. SGB functions aren't emulated, so SGB enhanced cartridges also get a DMG.
+    pub fn system_mode(&self) -> SystemMode {
+        match self.cgb_flag {
+            0x80 | 0xC0 => SystemMode::GameboyColorGBC,
+            _ => SystemMode::Gameboy,
+        }
+    }
+    
+    /// Size of the external RAM in bytes, as declared by the header.
+    pub fn ram_bytes(&self) -> usize {
+        match self.ram_size {
+            0x02 => 8 * 1024,
+            0x03 => 32 * 1024,
+            0x04 => 128 * 1024,
+            0x05 => 64 * 1024,
+            _ => 0,
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Cartridge {
+    /// Not included in save states, which are only meant to be loaded with the same ROM.
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub rom: Vec<u8>,
+    pub mbc: Box<dyn Mbc>,
 }
 impl Cartridge {
     pub fn new() -> Self { Self {
         rom: vec![],
+        mbc: Box::new(NoMbc::new(0)),
     }}
+    
+    /// Replaces the ROM, selecting the MBC and allocating external RAM according to its header.
+    pub fn load_rom(&mut self, rom: Vec<u8>) {
+        self.rom = rom;
+        self.mbc = match self.header() {
+            Some(header) => mbc::from_header(&header),
+            None => Box::new(NoMbc::new(0)),
+        };
+    }
+    
+    /// Parsed cartridge header, or None if the ROM is too small to contain one.
+    pub fn header(&self) -> Option<CartridgeHeader> {
+        CartridgeHeader::parse(&self.rom)
+    }
+    
+    /// Sets the cartridge's real time clock, returning false if it doesn't have one. See [Rtc::set()](mbc::Rtc::set).
+    pub fn set_rtc(&mut self, seconds: u8, minutes: u8, hours: u8, days: u16) -> bool {
+        match self.mbc.rtc() {
+            Some(rtc) => {
+                rtc.set(seconds, minutes, hours, days);
+                true
+            },
+            None => false,
+        }
+    }
+    
+    /// Current time of the cartridge's real time clock as (seconds, minutes, hours, days), or None if
+    /// it doesn't have one.
+    pub fn rtc_time(&mut self) -> Option<(u8, u8, u8, u16)> {
+        self.mbc.rtc().map(|rtc| rtc.time())
+    }
+    
+    /// Sets the reading of the cartridge's accelerometer, returning false if it doesn't have one. See
+    /// [Accelerometer](mbc::Accelerometer).
+    pub fn set_sensor(&mut self, x: u16, y: u16) -> bool {
+        match self.mbc.accelerometer() {
+            Some(accelerometer) => {
+                accelerometer.x = x;
+                accelerometer.y = y;
+                true
+            },
+            None => false,
+        }
+    }
+    
+    /// Global checksum stored in the header at 0x014E-0x014F (big endian).
+    pub fn global_checksum(&self) -> Option<u16> {
+        Some(((*self.rom.get(0x014E)? as u16) << 8) | (*self.rom.get(0x014F)? as u16))
+    }
+    
+    /// Checks the sum of every ROM byte, except the two checksum bytes, against the global checksum.
+    /// 
+    /// Real hardware never checks this, so a mismatch doesn't prevent a ROM from running.
+    pub fn verify_global_checksum(&self) -> bool {
+        let expected = match self.global_checksum() {
+            Some(checksum) => checksum,
+            None => return false,
+        };
+        
+        let sum = self.rom.iter()
+            .enumerate()
+            .filter(|(i, _)| *i != 0x014E && *i != 0x014F)
+            .fold(0u16, |sum, (_, byte)| sum.wrapping_add(*byte as u16));
+        
+        sum == expected
+    }
+}
+impl Default for Cartridge {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl BusAccessable for Cartridge {
     fn write(&mut self, addr: u16, data: u8) {
         match addr {
-            0x0000..=0x00FF => (),
+            0x0000..=0x7FFF | 0xA000..=0xBFFF => self.mbc.write(addr, data),
             _ => todo!("write {:#04X} to {:#06X}", data, addr)
         }
     }
 
     fn read(&mut self, addr: u16) -> u8 {
         match addr {
-            0x0000..=0x7FFF => *self.rom.get(addr as usize).unwrap_or(&0xFF),
+            0x0000..=0x7FFF | 0xA000..=0xBFFF => self.mbc.read(&self.rom, addr),
             _ => todo!("read from {:#06X}", addr)
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// 32KiB ROM filled with a pattern, with the global checksum set to `checksum`.
+    fn rom_with_checksum(checksum: u16) -> Cartridge {
+        let mut rom = (0..0x8000u32).map(|i| (i * 7) as u8).collect::<Vec<u8>>();
+        rom[0x014E] = (checksum >> 8) as u8;
+        rom[0x014F] = checksum as u8;
+        
+        let mut cart = Cartridge::new();
+        cart.rom = rom;
+        cart
+    }
+    
+    fn rom_sum(cart: &Cartridge) -> u16 {
+        cart.rom.iter()
+            .enumerate()
+            .filter(|(i, _)| *i != 0x014E && *i != 0x014F)
+            .fold(0u16, |sum, (_, byte)| sum.wrapping_add(*byte as u16))
+    }
+    
+    #[test]
+    fn global_checksum_matches() {
+        let sum = rom_sum(&rom_with_checksum(0));
+        let cart = rom_with_checksum(sum);
+        
+        assert_eq!(cart.global_checksum(), Some(sum));
+        assert!(cart.verify_global_checksum());
+    }
+    
+    #[test]
+    fn global_checksum_mismatches() {
+        let sum = rom_sum(&rom_with_checksum(0));
+        let cart = rom_with_checksum(sum.wrapping_add(1));
+        assert!(!cart.verify_global_checksum());
+        
+        // Changing any byte outside the checksum itself invalidates it
+        let mut cart = rom_with_checksum(sum);
+        cart.rom[0x4000] ^= 0xFF;
+        assert!(!cart.verify_global_checksum());
+    }
+    
+    #[test]
+    fn global_checksum_missing() {
+        let mut cart = Cartridge::new();
+        cart.rom = vec![0; 0x0100];
+        
+        assert_eq!(cart.global_checksum(), None);
+        assert!(!cart.verify_global_checksum());
+    }
+    
+    #[test]
+    fn mbc7_reads_sensor() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0147] = 0x22;
+        let mut cart = Cartridge::new();
+        cart.load_rom(rom.clone());
+        let read_axes = |cart: &mut Cartridge| [0xA020, 0xA030, 0xA040, 0xA050].map(|addr| cart.read(addr));
+        
+        assert!(cart.set_sensor(0x81D0 + 0x70, 0x81D0 - 0x20));
+        cart.write(0x0000, 0x0A);
+        cart.write(0x4000, 0x40);
+        cart.write(0xA000, 0x55);
+        assert_eq!(read_axes(&mut cart), [0x00, 0x80, 0x00, 0x80]);
+        cart.write(0xA010, 0xAA);
+        assert_eq!(read_axes(&mut cart), [0x40, 0x82, 0xB0, 0x81]);
+        
+        // The latched reading stays put until it's erased and latched again
+        assert!(cart.set_sensor(0x1234, 0x5678));
+        assert_eq!(read_axes(&mut cart), [0x40, 0x82, 0xB0, 0x81]);
+        cart.write(0xA000, 0x55);
+        cart.write(0xA010, 0xAA);
+        assert_eq!(read_axes(&mut cart), [0x34, 0x12, 0x78, 0x56]);
+        
+        rom[0x0147] = 0x00;
+        cart.load_rom(rom);
+        assert!(!cart.set_sensor(0x1234, 0x5678));
+    }
+}
@@ -1,32 +1,123 @@
 #![allow(non_upper_case_globals)]
 #![allow(unused_variables)]
 
+use std::collections::VecDeque;
 use std::fmt::{Debug, Formatter};
-use crate::arch::{Bus, BusAccessable, SystemMode};
+use crate::arch::{disasm, Bus, BusAccessable, SystemMode};
+use crate::arch::ppu::OamCorruption;
 use bitflags::bitflags;
-use log::{debug, info};
+use log::{debug, error};
 
-#[derive(Copy, Clone)]
+/// The t-cycle within each m-cycle on which the CPU performs its work, including any bus access.
+/// 
+/// Reads and writes land on the final t-cycle of the m-cycle, so a peripheral that changes state
+/// partway through an m-cycle (timer, PPU) has already been clocked for the preceding t-cycles
+/// when the CPU observes it.
+pub const ACCESS_TCYCLE: u8 = 3;
+/// Number of recently decoded instruction addresses kept in [Cpu::history].
+pub const HISTORY_LEN: usize = 64;
+
+/// What an [InstructionProcedure] was created to execute.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ProcedureSource {
+    Opcode,
+    /// An instruction following the 0xCB prefix.
+    Prefixed,
+    /// An interrupt dispatch.
+    Interrupt,
+}
+
+/// The instruction an [InstructionProcedure] executes, one for each step function.
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Op {
+    Interrupt,
+    Nop,
+    Halt,
+    IllegalOpcode,
+    Stop,
+    LdU16Sp,
+    LdU16A,
+    LdAU16,
+    JrD,
+    JrCond,
+    JpU16,
+    JpCond,
+    JpHl,
+    Di,
+    Ei,
+    IncR,
+    DecR,
+    IncRp,
+    DecRp,
+    AddAR,
+    AdcAR,
+    SubAR,
+    SbcAR,
+    AndAR,
+    XorAR,
+    OrAR,
+    CpAR,
+    LdRU8,
+    LdRR,
+    RotA,
+    Daa,
+    Cpl,
+    Scf,
+    Ccf,
+    AddAU8,
+    AdcAU8,
+    SubAU8,
+    SbcAU8,
+    AndAU8,
+    XorAU8,
+    OrAU8,
+    CpAU8,
+    LdToIndirect,
+    LdFromIndirect,
+    Pop,
+    Push,
+    CallCond,
+    CallU16,
+    Rst,
+    Ret,
+    Reti,
+    RetCond,
+    AddHlRp,
+    LdSpHl,
+    AddSpI8,
+    LdHlSpI8,
+    LdRpU16,
+    LdToIoC,
+    LdFromIoC,
+    LdToIoU8,
+    LdFromIoU8,
+    Rot,
+    Bit,
+    Res,
+    Set,
+}
+
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct InstructionProcedure {
     pub done: bool,
-    func: fn(&mut Self, &mut Cpu, &mut Bus),
+    /// The opcode being executed. For CB-prefixed instructions, this is the byte following 0xCB.
+    pub opcode: u8,
+    pub source: ProcedureSource,
+    pub op: Op,
     mcycle: u8,
     tmp0: u8,
     tmp1: u8,
 }
-impl Debug for InstructionProcedure {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("InstructionProcedure")
-         .field("done", &self.done)
-         .field("mcycle", &self.mcycle)
-         .finish()
-    }
-}
 impl InstructionProcedure {
-    pub fn new(step_func: fn(&mut InstructionProcedure, &mut Cpu, &mut Bus)) -> Self {
+    pub fn new(op: Op) -> Self {
         Self {
             done: false,
-            func: step_func,
+            opcode: 0,
+            source: ProcedureSource::Opcode,
+            op,
             mcycle: 1,
             tmp0: 0,
             tmp1: 0,
@@ -34,9 +125,228 @@ impl InstructionProcedure {
     }
     
     pub fn step(&mut self, cpu: &mut Cpu, bus: &mut Bus) {
-        (self.func)(self, cpu, bus);
+        match self.op {
+            Op::Interrupt => interrupt(self, cpu, bus),
+            Op::Nop => nop(self, cpu, bus),
+            Op::Halt => halt(self, cpu, bus),
+            Op::IllegalOpcode => illegal_opcode(self, cpu, bus),
+            Op::Stop => stop(self, cpu, bus),
+            Op::LdU16Sp => ld_u16sp(self, cpu, bus),
+            Op::LdU16A => ld_u16a(self, cpu, bus),
+            Op::LdAU16 => ld_au16(self, cpu, bus),
+            Op::JrD => jr_d(self, cpu, bus),
+            Op::JrCond => jr_cond(self, cpu, bus),
+            Op::JpU16 => jp_u16(self, cpu, bus),
+            Op::JpCond => jp_cond(self, cpu, bus),
+            Op::JpHl => jp_hl(self, cpu, bus),
+            Op::Di => di(self, cpu, bus),
+            Op::Ei => ei(self, cpu, bus),
+            Op::IncR => inc_r(self, cpu, bus),
+            Op::DecR => dec_r(self, cpu, bus),
+            Op::IncRp => inc_rp(self, cpu, bus),
+            Op::DecRp => dec_rp(self, cpu, bus),
+            Op::AddAR => add_ar(self, cpu, bus),
+            Op::AdcAR => adc_ar(self, cpu, bus),
+            Op::SubAR => sub_ar(self, cpu, bus),
+            Op::SbcAR => sbc_ar(self, cpu, bus),
+            Op::AndAR => and_ar(self, cpu, bus),
+            Op::XorAR => xor_ar(self, cpu, bus),
+            Op::OrAR => or_ar(self, cpu, bus),
+            Op::CpAR => cp_ar(self, cpu, bus),
+            Op::LdRU8 => ld_ru8(self, cpu, bus),
+            Op::LdRR => ld_rr(self, cpu, bus),
+            Op::RotA => rot_a(self, cpu, bus),
+            Op::Daa => daa(self, cpu, bus),
+            Op::Cpl => cpl(self, cpu, bus),
+            Op::Scf => scf(self, cpu, bus),
+            Op::Ccf => ccf(self, cpu, bus),
+            Op::AddAU8 => add_au8(self, cpu, bus),
+            Op::AdcAU8 => adc_au8(self, cpu, bus),
+            Op::SubAU8 => sub_au8(self, cpu, bus),
+            Op::SbcAU8 => sbc_au8(self, cpu, bus),
+            Op::AndAU8 => and_au8(self, cpu, bus),
+            Op::XorAU8 => xor_au8(self, cpu, bus),
+            Op::OrAU8 => or_au8(self, cpu, bus),
+            Op::CpAU8 => cp_au8(self, cpu, bus),
+            Op::LdToIndirect => ld_toindirect(self, cpu, bus),
+            Op::LdFromIndirect => ld_fromindirect(self, cpu, bus),
+            Op::Pop => pop(self, cpu, bus),
+            Op::Push => push(self, cpu, bus),
+            Op::CallCond => call_cond(self, cpu, bus),
+            Op::CallU16 => call_u16(self, cpu, bus),
+            Op::Rst => rst(self, cpu, bus),
+            Op::Ret => ret(self, cpu, bus),
+            Op::Reti => reti(self, cpu, bus),
+            Op::RetCond => ret_cond(self, cpu, bus),
+            Op::AddHlRp => add_hlrp(self, cpu, bus),
+            Op::LdSpHl => ld_sphl(self, cpu, bus),
+            Op::AddSpI8 => add_sp_i8(self, cpu, bus),
+            Op::LdHlSpI8 => ld_hl_sp_i8(self, cpu, bus),
+            Op::LdRpU16 => ld_rpu16(self, cpu, bus),
+            Op::LdToIoC => ld_toio_c(self, cpu, bus),
+            Op::LdFromIoC => ld_fromio_c(self, cpu, bus),
+            Op::LdToIoU8 => ld_toio_u8(self, cpu, bus),
+            Op::LdFromIoU8 => ld_fromio_u8(self, cpu, bus),
+            Op::Rot => rot(self, cpu, bus),
+            Op::Bit => bit(self, cpu, bus),
+            Op::Res => res(self, cpu, bus),
+            Op::Set => set(self, cpu, bus),
+        }
         self.mcycle += 1;
     }
+    
+    /// Creates the procedure for an instruction, or None if it isn't implemented yet. For CB-prefixed
+    /// instructions, `opcode` is the byte following 0xCB.
+    pub fn decode(opcode: u8, prefixed: bool) -> Option<Self> {
+        let x = (opcode & 0b11000000) >> 6;
+        let y = (opcode & 0b00111000) >> 3;
+        let z = opcode & 0b00000111;
+        let p = y >> 1;
+        let q = y & 0b1;
+        
+        let mut proc = match opcode {
+            _ if prefixed => match x {
+                0 => InstructionProcedure::new(Op::Rot),
+                1 => InstructionProcedure::new(Op::Bit),
+                2 => InstructionProcedure::new(Op::Res),
+                3 => InstructionProcedure::new(Op::Set),
+                _ => panic!("unreachable")
+            },
+            0xD3 | 0xDB | 0xDD | 0xE3 | 0xE4 | 0xEB | 0xEC | 0xED | 0xF4 | 0xFC | 0xFD => InstructionProcedure::new(Op::IllegalOpcode),
+            _ => match x {
+                0 => match z {
+                    0 => match y {
+                        0 => InstructionProcedure::new(Op::Nop),
+                        1 => InstructionProcedure::new(Op::LdU16Sp),
+                        2 => InstructionProcedure::new(Op::Stop),
+                        3 => InstructionProcedure::new(Op::JrD),
+                        4..=7 => InstructionProcedure::new(Op::JrCond),
+                        _ => panic!("unreachable")
+                    },
+                    1 => match q {
+                        0 => InstructionProcedure::new(Op::LdRpU16),
+                        1 => InstructionProcedure::new(Op::AddHlRp),
+                        _ => panic!("unreachable")
+                    },
+                    2 => match q {
+                        0 => InstructionProcedure::new(Op::LdToIndirect),
+                        1 => InstructionProcedure::new(Op::LdFromIndirect),
+                        _ => panic!("unreachable")
+                    },
+                    3 => match q {
+                        0 => InstructionProcedure::new(Op::IncRp),
+                        1 => InstructionProcedure::new(Op::DecRp),
+                        _ => panic!("unreachable")
+                    }
+                    4 => InstructionProcedure::new(Op::IncR),
+                    5 => InstructionProcedure::new(Op::DecR),
+                    6 => InstructionProcedure::new(Op::LdRU8),
+                    7 => match y {
+                        0..=3 => InstructionProcedure::new(Op::RotA),
+                        4 => InstructionProcedure::new(Op::Daa),
+                        5 => InstructionProcedure::new(Op::Cpl),
+                        6 => InstructionProcedure::new(Op::Scf),
+                        7 => InstructionProcedure::new(Op::Ccf),
+                        _ => panic!("unreachable")
+                    }
+                    _ => panic!("unreachable")
+                },
+                1 => if y == 6 && z == 6 {
+                        InstructionProcedure::new(Op::Halt)
+                    } else {
+                        InstructionProcedure::new(Op::LdRR)
+                },
+                2 => match y {
+                    0 => InstructionProcedure::new(Op::AddAR),
+                    1 => InstructionProcedure::new(Op::AdcAR),
+                    2 => InstructionProcedure::new(Op::SubAR),
+                    3 => InstructionProcedure::new(Op::SbcAR),
+                    4 => InstructionProcedure::new(Op::AndAR),
+                    5 => InstructionProcedure::new(Op::XorAR),
+                    6 => InstructionProcedure::new(Op::OrAR),
+                    7 => InstructionProcedure::new(Op::CpAR),
+                    _ => panic!("unreachable")
+                },
+                3 => match z {
+                    0 => match y {
+                        0..=3 => InstructionProcedure::new(Op::RetCond),
+                        4 => InstructionProcedure::new(Op::LdToIoU8),
+                        5 => InstructionProcedure::new(Op::AddSpI8),
+                        6 => InstructionProcedure::new(Op::LdFromIoU8),
+                        7 => InstructionProcedure::new(Op::LdHlSpI8),
+                        _ => panic!("unreachable")
+                    },
+                    1 => match q {
+                        0 => InstructionProcedure::new(Op::Pop),
+                        1 => match p {
+                            0 => InstructionProcedure::new(Op::Ret),
+                            1 => InstructionProcedure::new(Op::Reti),
+                            2 => InstructionProcedure::new(Op::JpHl),
+                            3 => InstructionProcedure::new(Op::LdSpHl),
+                            _ => panic!("unreachable")
+                        }
+                        _ => panic!("unreachable")
+                    },
+                    2 => match y {
+                        0..=3 => InstructionProcedure::new(Op::JpCond),
+                        4 => InstructionProcedure::new(Op::LdToIoC),
+                        5 => InstructionProcedure::new(Op::LdU16A),
+                        6 => InstructionProcedure::new(Op::LdFromIoC),
+                        7 => InstructionProcedure::new(Op::LdAU16),
+                        _ => panic!("unreachable")
+                    },
+                    3 => match y {
+                        0 => InstructionProcedure::new(Op::JpU16),
+                        1 => panic!("CB prefix"),
+                        2..=5 => panic!("removed opcode"),
+                        6 => InstructionProcedure::new(Op::Di),
+                        7 => InstructionProcedure::new(Op::Ei),
+                        _ => panic!("unreachable")
+                    }
+                    4 => match y {
+                        0..=3 => InstructionProcedure::new(Op::CallCond),
+                        4..=7 => panic!("removed opcode"),
+                        _ => panic!("unreachable"),
+                    }
+                    5 => match q {
+                        0 => InstructionProcedure::new(Op::Push),
+                        1 => match p {
+                            0 => InstructionProcedure::new(Op::CallU16),
+                            1..=3 => panic!("removed opcode"),
+                            _ => panic!("unreachable")
+                        }
+                        _ => panic!("unreachable")
+                    }
+                    6 => match y {
+                        0 => InstructionProcedure::new(Op::AddAU8),
+                        1 => InstructionProcedure::new(Op::AdcAU8),
+                        2 => InstructionProcedure::new(Op::SubAU8),
+                        3 => InstructionProcedure::new(Op::SbcAU8),
+                        4 => InstructionProcedure::new(Op::AndAU8),
+                        5 => InstructionProcedure::new(Op::XorAU8),
+                        6 => InstructionProcedure::new(Op::OrAU8),
+                        7 => InstructionProcedure::new(Op::CpAU8),
+                        _ => panic!("unreachable")
+                    }
+                    7 => InstructionProcedure::new(Op::Rst),
+                    _ => panic!("unreachable")
+                },
+                _ => panic!("unreachable")
+            }
+        };
+        proc.opcode = opcode;
+        proc.source = if prefixed { ProcedureSource::Prefixed } else { ProcedureSource::Opcode };
+        
+        Some(proc)
+    }
+    
+    /// Creates the procedure which dispatches a pending interrupt.
+    pub fn dispatch() -> Self {
+        let mut proc = Self::new(Op::Interrupt);
+        proc.source = ProcedureSource::Interrupt;
+        
+        proc
+    }
 }
 
 bitflags! {
@@ -59,7 +369,32 @@ impl std::fmt::Display for FlagsReg {
     }
 }
 
+#[cfg(feature = "serde")]
+impl serde::Serialize for FlagsReg {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.bits.serialize(serializer)
+    }
+}
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for FlagsReg {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        u8::deserialize(deserializer).map(FlagsReg::from_bits_truncate)
+    }
+}
+
+bitflags! {
+    /// Interrupt bits, as used by both IE (0xFFFF) and IF (0xFF0F). Lower bits have priority.
+    pub struct Interrupt: u8 {
+        const VBlank = 0b00000001;
+        const Stat   = 0b00000010;
+        const Timer  = 0b00000100;
+        const Serial = 0b00001000;
+        const Joypad = 0b00010000;
+    }
+}
+
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Regs {
     pub a: u8,
     pub f: FlagsReg,
@@ -169,7 +504,7 @@ impl Regs {
     #[inline(always)]
     pub fn set_af(&mut self, val: u16) {
         self.a = (val >> 8) as u8;
-        self.f.bits = (val & 0x00FF) as u8;
+        self.f.bits = (val & 0x00F0) as u8; // lower nibble of F is always 0
     }
     
     #[inline(always)]
@@ -210,39 +545,119 @@ impl Regs {
 }
 
 
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CpuError {
+    /// The decoder doesn't have a procedure for this opcode yet.
+    UnimplementedOpcode {
+        opcode: u8,
+        /// Address the opcode was fetched from.
+        pc: u16,
+        /// Raw bytes starting at `pc`, including any operands.
+        bytes: [u8; 3],
+    },
+}
+impl CpuError {
+    fn unimplemented(bus: &mut Bus, pc: u16) -> Self {
+        let bytes = [bus.peek(pc), bus.peek(pc.wrapping_add(1)), bus.peek(pc.wrapping_add(2))];
+        
+        Self::UnimplementedOpcode { opcode: bytes[0], pc, bytes }
+    }
+}
+impl std::fmt::Display for CpuError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnimplementedOpcode { opcode, pc, bytes } => write!(f, "unimplemented opcode {:02X} at {:04X} ({:02X} {:02X} {:02X})",
+                opcode, pc, bytes[0], bytes[1], bytes[2]
+            ),
+        }
+    }
+}
+impl std::error::Error for CpuError {}
+
+/// Kind of bus access made by the CPU, see [Cpu::last_access_log()].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AccessKind {
+    /// Includes opcode and operand fetches.
+    Read,
+    Write,
+}
+
+/// Bus accesses made by the CPU, recorded while [Cpu::set_access_logging()] is enabled.
+#[derive(Clone, Debug, Default)]
+struct AccessLog {
+    /// Accesses made so far by the instruction being executed.
+    current: Vec<(u16, AccessKind, u8)>,
+    /// Accesses made by the last completed instruction.
+    last: Vec<(u16, AccessKind, u8)>,
+    /// Source and opcode of the last completed instruction.
+    last_instruction: Option<(ProcedureSource, u8)>,
+    /// M-cycle of the instruction being executed, starting from 1.
+    mcycle: u8,
+}
+
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Cpu {
     pub instr_count: usize, // debug only
-    mode: SystemMode,
     tcount: u8,
     pub procedure: Option<InstructionProcedure>,
     pub regs: Regs,
-    pub interrupt_flags: u8,
-    pub interrupt_enable: u8,
+    /// Pending EI, and the number of instruction boundaries passed since it executed.
+    /// 
+    /// The count only advances between instructions, so IME is set once the instruction following EI
+    /// has fully completed, however many m-cycles it takes (e.g. `EI; RET`).
     en_ime: (bool, u8),
     pub ime: bool,
+    /// Set while the CPU is stopped by HALT (or STOP), waiting for an enabled interrupt to be requested.
+    pub halted: bool,
+    /// Set when the next opcode fetch should not increment PC (the HALT bug).
+    halt_bug: bool,
+    /// Set once an illegal opcode has been executed. The CPU stays hung until the system is reset.
+    pub locked: bool,
+    /// Addresses of the most recently decoded instructions, oldest first. Used for crash reports.
+    pub history: VecDeque<u16>,
+    /// Only recorded while enabled, see [Cpu::set_access_logging()]. Not part of save states.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    access_log: Option<AccessLog>,
 }
 impl Cpu {
     pub fn new(mode: SystemMode) -> Self { Self {
         instr_count: 1,
-        mode,
         tcount: 0,
         procedure: None,
         regs: Regs::new(mode),
-        interrupt_flags: 0,
-        interrupt_enable: 0,
         en_ime: (false, 0),
         ime: false,
+        halted: false,
+        halt_bug: false,
+        locked: false,
+        history: VecDeque::with_capacity(HISTORY_LEN),
+        access_log: None,
     }}
     
-    pub fn tcycle(&mut self, bus: &mut Bus) {
-        if self.tcount == 0 {
+    pub fn tcycle(&mut self, bus: &mut Bus) -> Result<(), CpuError> {
+        let tcount = self.tcount;
+        self.tcount += 1;
+        if self.tcount == 4 {
+            self.tcount = 0;
+        }
+        
+        if tcount == ACCESS_TCYCLE {
             //debug!("ROW: {:06} | PC: {:04X} = {:02X} | F: {} {:02X} | SP: {:04X} | HL: {:04X}", self.instr_count, self.regs.pc, bus.read(self.regs.pc), self.regs.f, self.regs.f, self.regs.sp, self.regs.hl());
             debug!("{:06}| A: {:02X} F: {:02X} B: {:02X} C: {:02X} D: {:02X} E: {:02X} H: {:02X} L: {:02X} SP: {:04X} PC: 00:{:04X} ({:02X} {:02X} {:02X} {:02X})",
-                self.instr_count, self.regs.a, self.regs.f.bits, self.regs.b, self.regs.c, self.regs.d, self.regs.e, self.regs.h, self.regs.l, self.regs.sp, self.regs.pc, bus.read(self.regs.pc), bus.read(self.regs.pc + 1), bus.read(self.regs.pc + 2), bus.read(self.regs.pc + 3)
+                self.instr_count, self.regs.a, self.regs.f.bits, self.regs.b, self.regs.c, self.regs.d, self.regs.e, self.regs.h, self.regs.l, self.regs.sp, self.regs.pc, bus.peek(self.regs.pc), bus.peek(self.regs.pc.wrapping_add(1)), bus.peek(self.regs.pc.wrapping_add(2)), bus.peek(self.regs.pc.wrapping_add(3))
             );
             
-            if self.procedure.is_none() {
+            if self.halted && bus.pending_interrupts() != 0 {
+                self.halted = false;
+            }
+            
+            if self.procedure.is_none() && !self.halted && !self.locked {
+                if let Some(log) = self.access_log.as_mut() {
+                    log.current.clear();
+                    log.mcycle = 1;
+                }
+                
                 if self.en_ime.0 {
                     self.en_ime.1 += 1;
                     if self.en_ime.1 == 2 {
@@ -251,228 +666,259 @@ impl Cpu {
                     }
                 }
                 
-                let opcode = self.fetch(bus);
-                let x = (opcode & 0b11000000) >> 6;
-                let y = (opcode & 0b00111000) >> 3;
-                let z = opcode & 0b00000111;
-                let p = y >> 1;
-                let q = y & 0b1;
-                debug!("x: {} | z: {} | y: {} | p: {} | q: {}", x, z, y, p, q);
-                
-                self.procedure = Some(match opcode {
-                    0xDD | 0xFD => unimplemented!(),
-                    0xED => unimplemented!(),
-                    0xCB => { 
-                        let opcode = self.fetch(bus);
-                        let x = (opcode & 0b11000000) >> 6;
-                        let y = (opcode & 0b00111000) >> 3;
-                        debug!("op: {:02X} | x: {} | y: {}", opcode, x, y);
-                        
-                        match x {
-                            0 => InstructionProcedure::new(rot),
-                            1 => InstructionProcedure::new(bit),
-                            2 => InstructionProcedure::new(res),
-                            3 => InstructionProcedure::new(set),
-                            _ => panic!("unreachable")
-                        }
-                    },
-                    _ => match x {
-                        0 => match z {
-                            0 => match y {
-                                0 => InstructionProcedure::new(nop),
-                                1 => InstructionProcedure::new(ld_u16sp),
-                                2 => InstructionProcedure::new(stop),
-                                3 => InstructionProcedure::new(jr_d),
-                                4..=7 => InstructionProcedure::new(jr_cond),
-                                _ => panic!("unreachable")
-                            },
-                            1 => match q {
-                                0 => InstructionProcedure::new(ld_rpu16),
-                                1 => InstructionProcedure::new(add_hlrp),
-                                _ => panic!("unreachable")
-                            },
-                            2 => match q {
-                                0 => InstructionProcedure::new(ld_toindirect),
-                                1 => InstructionProcedure::new(ld_fromindirect),
-                                _ => panic!("unreachable")
-                            },
-                            3 => match q {
-                                0 => InstructionProcedure::new(inc_rp),
-                                1 => InstructionProcedure::new(dec_rp),
-                                _ => panic!("unreachable")
-                            }
-                            4 => InstructionProcedure::new(inc_r),
-                            5 => InstructionProcedure::new(dec_r),
-                            6 => InstructionProcedure::new(ld_ru8),
-                            7 => match y {
-                                0 => InstructionProcedure::new(rlca),
-                                1 => InstructionProcedure::new(rrca),
-                                2 => InstructionProcedure::new(rla),
-                                3 => InstructionProcedure::new(rra),
-                                4 => InstructionProcedure::new(daa),
-                                5 => InstructionProcedure::new(cpl),
-                                6 => InstructionProcedure::new(scf),
-                                7 => InstructionProcedure::new(ccf),
-                                _ => panic!("unreachable")
-                            }
-                            _ => todo!()
-                        },
-                        1 => if y == 6 && z == 6 {
-                                todo!() // HALT
-                            } else {
-                                InstructionProcedure::new(ld_rr)
-                        },
-                        2 => match y {
-                            0 => InstructionProcedure::new(add_ar),
-                            2 => InstructionProcedure::new(sub_ar),
-                            4 => InstructionProcedure::new(and_ar),
-                            5 => InstructionProcedure::new(xor_ar),
-                            6 => InstructionProcedure::new(or_ar),
-                            7 => InstructionProcedure::new(cp_ar),
-                            _ => todo!()
-                        },
-                        3 => match z {
-                            0 => match y {
-                                0..=3 => InstructionProcedure::new(ret_cond),
-                                4 => InstructionProcedure::new(ld_toio_u8),
-                                6 => InstructionProcedure::new(ld_fromio_u8),
-                                _ => todo!()
-                            },
-                            1 => match q {
-                                0 => InstructionProcedure::new(pop),
-                                1 => match p {
-                                    0 => InstructionProcedure::new(ret),
-                                    1 => todo!(), // RETI
-                                    2 => InstructionProcedure::new(jp_hl),
-                                    3 => InstructionProcedure::new(ld_sphl),
-                                    _ => panic!("unreachable")
-                                }
-                                _ => panic!("unreachable")
-                            },
-                            2 => match y {
-                                4 => InstructionProcedure::new(ld_toio_c),
-                                5 => InstructionProcedure::new(ld_u16a),
-                                6 => InstructionProcedure::new(ld_fromio_c),
-                                7 => InstructionProcedure::new(ld_au16),
-                                _ => todo!()
-                            },
-                            3 => match y {
-                                0 => InstructionProcedure::new(jp_u16),
-                                1 => panic!("CB prefix"),
-                                2..=5 => panic!("removed opcode"),
-                                6 => InstructionProcedure::new(di),
-                                7 => InstructionProcedure::new(ei),
-                                _ => panic!("unreachable")
-                            }
-                            4 => match y {
-                                0..=3 => InstructionProcedure::new(call_cond),
-                                4..=7 => panic!("removed opcode"),
-                                _ => panic!("unreachable"),
-                            }
-                            5 => match q {
-                                0 => InstructionProcedure::new(push),
-                                1 => match p {
-                                    0 => InstructionProcedure::new(call_u16),
-                                    1..=3 => panic!("removed opcode"),
-                                    _ => panic!("unreachable")
-                                }
-                                _ => panic!("unreachable")
-                            }
-                            6 => match y {
-                                0 => InstructionProcedure::new(add_au8),
-                                1 => InstructionProcedure::new(adc_au8),
-                                2 => InstructionProcedure::new(sub_au8),
-                                //3 => InstructionProcedure::new(sbc_au8),
-                                4 => InstructionProcedure::new(and_au8),
-                                5 => InstructionProcedure::new(xor_au8),
-                                6 => InstructionProcedure::new(or_au8),
-                                7 => InstructionProcedure::new(cp_au8),
-                                _ => todo!("y: {}", y)
-                            }
-                            _ => todo!("x: {} | z: {} | y: {}", x, z, y)
-                        },
-                        _ => panic!("unreachable")
-                    }
-                });
+                if self.ime && bus.pending_interrupts() != 0 {
+                    self.procedure = Some(InstructionProcedure::dispatch());
+                }
             }
             
-            let mut proc = self.procedure.unwrap();
-            proc.step(self, bus);
+            if self.procedure.is_none() && !self.halted && !self.locked {
+                let pc = self.regs.pc;
+                if self.history.len() == HISTORY_LEN {
+                    self.history.pop_front();
+                }
+                self.history.push_back(pc);
+                debug!("{:04X}: {}", pc, disasm::disassemble(bus, pc).0);
+                let mut opcode = self.fetch(bus);
+                let prefixed = opcode == 0xCB;
+                if prefixed {
+                    opcode = self.fetch(bus);
+                }
+                
+                self.procedure = match InstructionProcedure::decode(opcode, prefixed) {
+                    Some(proc) => Some(proc),
+                    None => return Err(CpuError::unimplemented(bus, pc)),
+                };
+            }
             
-            if proc.done {
-                self.procedure = None;
-                self.instr_count += 1;
-            } else {
-                self.procedure = Some(proc);
+            if let Some(mut proc) = self.procedure {
+                if let Some(log) = self.access_log.as_mut() {
+                    log.mcycle = proc.mcycle;
+                }
+                proc.step(self, bus);
+                
+                if proc.done {
+                    if let Some(log) = self.access_log.as_mut() {
+                        log.last = std::mem::take(&mut log.current);
+                        log.last_instruction = Some((proc.source, proc.opcode));
+                    }
+                    self.procedure = None;
+                    self.instr_count += 1;
+                } else {
+                    self.procedure = Some(proc);
+                }
             }
         }
         
-        self.tcount += 1;
-        if self.tcount == 4 {
-            self.tcount = 0;
+        Ok(())
+    }
+    
+    /// True when no instruction is partway through execution, i.e. the next access cycle will
+    /// either fetch a new opcode or begin an interrupt dispatch.
+    pub fn is_between_instructions(&self) -> bool {
+        self.procedure.is_none()
+    }
+    
+    /// True when the next t-cycle will fetch an opcode from PC (barring an interrupt dispatch).
+    pub fn is_about_to_fetch(&self) -> bool {
+        self.procedure.is_none() && self.tcount == ACCESS_TCYCLE && !self.halted && !self.locked
+    }
+    
+    /// Starts or stops recording the bus accesses made by each instruction. Starting again discards
+    /// the previous log.
+    pub fn set_access_logging(&mut self, enabled: bool) {
+        self.access_log = if enabled { Some(AccessLog::default()) } else { None };
+    }
+    
+    /// Bus accesses made by the last completed instruction or interrupt dispatch, as (address, kind,
+    /// t-cycle), where t-cycles are counted from the start of its first m-cycle. Empty unless enabled
+    /// by [Cpu::set_access_logging()].
+    pub fn last_access_log(&self) -> &[(u16, AccessKind, u8)] {
+        self.access_log.as_ref().map_or(&[], |log| &log.last)
+    }
+    
+    /// Source and opcode of the instruction which made the accesses in [Cpu::last_access_log()].
+    pub fn last_logged_instruction(&self) -> Option<(ProcedureSource, u8)> {
+        self.access_log.as_ref().and_then(|log| log.last_instruction)
+    }
+    
+    /// Reads from the bus, recording the access if enabled.
+    fn read(&mut self, bus: &mut Bus, addr: u16) -> u8 {
+        self.log_access(addr, AccessKind::Read);
+        Self::corrupt_oam(bus, addr, OamCorruption::Read);
+        bus.read(addr)
+    }
+    
+    /// Reads from the bus while the register holding `addr` is incremented or decremented in the
+    /// same m-cycle, which matters for the OAM corruption bug.
+    fn read_idu(&mut self, bus: &mut Bus, addr: u16) -> u8 {
+        self.log_access(addr, AccessKind::Read);
+        Self::corrupt_oam(bus, addr, OamCorruption::ReadIncrement);
+        bus.read(addr)
+    }
+    
+    /// Writes to the bus, recording the access if enabled.
+    fn write(&mut self, bus: &mut Bus, addr: u16, data: u8) {
+        self.log_access(addr, AccessKind::Write);
+        Self::corrupt_oam(bus, addr, OamCorruption::Write);
+        bus.write(addr, data);
+    }
+    
+    /// Called when a 16-bit register is incremented or decremented without accessing the bus. The
+    /// old value still ends up on the address bus, so it can corrupt OAM like a write.
+    fn idu(bus: &mut Bus, addr: u16) {
+        Self::corrupt_oam(bus, addr, OamCorruption::Write);
+    }
+    
+    fn corrupt_oam(bus: &mut Bus, addr: u16, kind: OamCorruption) {
+        if (0xFE00..=0xFEFF).contains(&addr) && !bus.oam_dma_active() {
+            bus.ppu.corrupt_oam(kind);
+        }
+    }
+    
+    fn log_access(&mut self, addr: u16, kind: AccessKind) {
+        if let Some(log) = self.access_log.as_mut() {
+            let tcycle = ((log.mcycle - 1) * 4) + ACCESS_TCYCLE;
+            log.current.push((addr, kind, tcycle));
         }
     }
     
     fn fetch(&mut self, bus: &mut Bus) -> u8 {
-        let fetch = bus.read(self.regs.pc);
-        self.regs.pc += 1;
+        let fetch = self.read(bus, self.regs.pc);
+        if self.halt_bug {
+            self.halt_bug = false;
+        } else {
+            self.regs.pc += 1;
+        }
         
         fetch
     }
     
     fn stack_push(&mut self, bus: &mut Bus, data: u8) {
         self.regs.sp = self.regs.sp.wrapping_sub(1);
-        bus.write(self.regs.sp, data);
+        self.write(bus, self.regs.sp, data);
     }
     
     fn stack_pop(&mut self, bus: &mut Bus) -> u8 {
-        let val = bus.read(self.regs.sp);
+        let val = self.read_idu(bus, self.regs.sp);
         self.regs.sp = self.regs.sp.wrapping_add(1);
         
         val
     }
 }
 
-impl BusAccessable for Cpu {
-    fn write(&mut self, addr: u16, data: u8) {
-        match addr {
-            0xFF01 => info!("{}", String::from_utf8_lossy(&[data]).to_string()),
-            0xFF02 => (), //TODO
-            0xFF07 => (), //TODO
-            0xFF0F => self.interrupt_flags = data,
-            0xFFFF => self.interrupt_enable = data,
-            _ => todo!("write {:#04X} to {:#06X}", data, addr)
-        }
-    }
 
-    fn read(&mut self, addr: u16) -> u8 {
-        match addr {
-            0xFF0F => todo!("interrupts not implemented yet"),
-            0xFFFF => self.interrupt_enable,
-            _ => todo!("read from {:#06X}", addr)
-        }
+// Instruction Functions
+
+/// Interrupt dispatch (not an opcode)
+/// 
+/// Two idle mcycles, then PC is pushed high byte first, then PC jumps to the vector. The vector is
+/// only chosen after the high byte is pushed, so if that push overwrites IE (SP at 0x0000) and no
+/// enabled interrupt remains pending, dispatch is cancelled and PC is set to 0x0000 instead.
+/// 
+/// If a HALT bug is still waiting on its fetch (`EI` directly before `HALT`), the pushed address is
+/// the HALT itself, so the HALT is executed again after the handler returns.
+fn interrupt(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
+    match proc.mcycle {
+        1 => {
+            cpu.ime = false;
+            
+            if cpu.halt_bug {
+                cpu.halt_bug = false;
+                cpu.regs.pc = cpu.regs.pc.wrapping_sub(1);
+            }
+        },
+        2 => (),
+        3 => cpu.stack_push(bus, cpu.regs.pchi()),
+        4 => {
+            let pending = bus.pending_interrupts();
+            if pending == 0 {
+                proc.tmp0 = 0xFF;
+            } else {
+                proc.tmp0 = pending.trailing_zeros() as u8;
+                bus.interrupt_flags &= !(1 << proc.tmp0);
+            }
+            
+            cpu.stack_push(bus, cpu.regs.pclo());
+        },
+        5 => {
+            cpu.regs.pc = match proc.tmp0 {
+                0xFF => 0x0000,
+                bit => 0x0040 + ((bit as u16) * 8), // 0x40, 0x48, 0x50, 0x58, 0x60
+            };
+            
+            proc.done = true;
+        },
+        _ => ()
     }
 }
 
-
-// Instruction Functions
-
 /// 0x00
 fn nop(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     proc.done = true;
 }
+/// 0x76
+/// 
+/// With IME set, or with no interrupt pending, the CPU stops fetching until an enabled interrupt
+/// is requested. With IME clear and an interrupt already pending the CPU doesn't halt at all, and
+/// instead fails to increment PC when fetching the next opcode, so that byte is read twice.
+fn halt(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
+    match proc.mcycle {
+        1 => {
+            if !cpu.ime && bus.pending_interrupts() != 0 {
+                cpu.halt_bug = true;
+            } else {
+                cpu.halted = true;
+            }
+            
+            proc.done = true;
+        },
+        _ => ()
+    }
+}
+/// 0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD
+/// 
+/// These opcodes don't exist on the SM83, and executing one hangs the CPU.
+fn illegal_opcode(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
+    match proc.mcycle {
+        1 => {
+            error!("Illegal opcode {:02X} at {:04X}, CPU is now locked up", proc.opcode, cpu.regs.pc.wrapping_sub(1));
+            cpu.locked = true;
+            
+            proc.done = true;
+        },
+        _ => ()
+    }
+}
 /// 0x10
+/// 
+/// With a speed switch armed through KEY1 (CGB mode only), this switches between normal and double
+/// speed. Otherwise the CPU stops until a button is pressed, which is treated like HALT: it waits for
+/// an enabled interrupt, normally the joypad's. Either way DIV is reset, and the byte following the
+/// opcode is skipped.
+/// 
+/// Hardware also pauses the CPU for a while after switching speeds, which isn't emulated.
 fn stop(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
-    panic!("STOP instruction called");
+    match proc.mcycle {
+        1 => {
+            cpu.regs.pc = cpu.regs.pc.wrapping_add(1);
+            bus.timer.write(0xFF04, 0x00);
+            if !bus.switch_speed() {
+                cpu.halted = true;
+            }
+            
+            proc.done = true;
+        },
+        _ => ()
+    }
 }
 /// 0x08
 fn ld_u16sp(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         2 => proc.tmp0 = cpu.fetch(bus),
         3 => proc.tmp1 = cpu.fetch(bus),
-        4 => bus.write(((proc.tmp1 as u16) << 8) | (proc.tmp0 as u16), cpu.regs.splo()),
+        4 => cpu.write(bus, ((proc.tmp1 as u16) << 8) | (proc.tmp0 as u16), cpu.regs.splo()),
         5 => {
-            bus.write(((proc.tmp1 as u16) << 8) | (proc.tmp0 as u16), cpu.regs.sphi());
+            cpu.write(bus, ((proc.tmp1 as u16) << 8) | (proc.tmp0 as u16), cpu.regs.sphi());
             
             proc.done = true;
         },
@@ -486,7 +932,7 @@ fn ld_u16a(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
         3 => proc.tmp1 = cpu.fetch(bus),
         4 => {
             let addr = ((proc.tmp1 as u16) << 8) | (proc.tmp0 as u16);
-            bus.write(addr, cpu.regs.a);
+            cpu.write(bus, addr, cpu.regs.a);
             
             proc.done = true;
         },
@@ -500,7 +946,7 @@ fn ld_au16(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
         3 => proc.tmp1 = cpu.fetch(bus),
         4 => {
             let addr = ((proc.tmp1 as u16) << 8) | (proc.tmp0 as u16);
-            cpu.regs.a = bus.read(addr);
+            cpu.regs.a = cpu.read(bus, addr);
             
             proc.done = true;
         },
@@ -521,15 +967,16 @@ fn jr_d(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     }
 }
 /// 0x20, 0x28, 0x30, 0x38
+/// 
+/// 2 mcycles when not taken, 3 when taken. The target wraps around the address space and there's
+/// no extra cycle for crossing a page.
 fn jr_cond(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         2 => {
-            let opcode = bus.read(cpu.regs.pc - 1);
-            proc.tmp0 = ((opcode & 0b00111000) >> 3) - 4; // y-4
-            proc.tmp1 = cpu.fetch(bus); // d (displacement)
-        },
-        3 => {
-            let cond = match proc.tmp0 {
+            let y = (proc.opcode & 0b00111000) >> 3;
+            proc.tmp0 = cpu.fetch(bus); // d (displacement)
+            
+            let cond = match y - 4 {
                 0 => !cpu.regs.f.contains(FlagsReg::Zero),
                 1 => cpu.regs.f.contains(FlagsReg::Zero),
                 2 => !cpu.regs.f.contains(FlagsReg::Carry),
@@ -541,9 +988,8 @@ fn jr_cond(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
                 proc.done = true;
             }
         },
-        4 => (),
-        5 => {
-            cpu.regs.pc = cpu.regs.pc.wrapping_add(proc.tmp1 as i8 as u16);
+        3 => {
+            cpu.regs.pc = cpu.regs.pc.wrapping_add(proc.tmp0 as i8 as u16);
             
             proc.done = true;
         },
@@ -564,6 +1010,35 @@ fn jp_u16(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
         _ => ()
     }
 }
+/// 0xC2, 0xCA, 0xD2, 0xDA
+fn jp_cond(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
+    match proc.mcycle {
+        2 => proc.tmp0 = cpu.fetch(bus),
+        3 => {
+            proc.tmp1 = cpu.fetch(bus);
+            
+            let opcode = proc.opcode;
+            let cond = match (opcode & 0b00111000) >> 3 { // cc[y]
+                0 => !cpu.regs.f.contains(FlagsReg::Zero),
+                1 => cpu.regs.f.contains(FlagsReg::Zero),
+                2 => !cpu.regs.f.contains(FlagsReg::Carry),
+                3 => cpu.regs.f.contains(FlagsReg::Carry),
+                _ => panic!("unreachable")
+            };
+            
+            if !cond {
+                proc.done = true;
+            }
+        },
+        4 => {
+            cpu.regs.set_pclo(proc.tmp0);
+            cpu.regs.set_pchi(proc.tmp1);
+            
+            proc.done = true;
+        },
+        _ => ()
+    }
+}
 /// 0xE9
 fn jp_hl(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
@@ -581,6 +1056,7 @@ fn di(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         1 => {
             cpu.ime = false;
+            cpu.en_ime = (false, 0); // also cancels an EI which hasn't taken effect yet
             
             proc.done = true;
         }
@@ -603,7 +1079,7 @@ fn ei(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
 fn inc_r(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         1 => {
-            let opcode = bus.read(cpu.regs.pc - 1);
+            let opcode = proc.opcode;
             
             let reg = match (opcode & 0b00111000) >> 3 { // r[y]
                 0 => &mut cpu.regs.b,
@@ -625,12 +1101,12 @@ fn inc_r(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
             proc.done = true;
         },
         2 => {
-            let (result, _, _, half, _) = alu_add(bus.read(cpu.regs.hl()), 1);
+            let (result, _, _, half, _) = alu_add(cpu.read(bus, cpu.regs.hl()), 1);
             proc.tmp0 = result;
             proc.tmp1 = half as u8;
         },
         3 => {
-            bus.write(cpu.regs.hl(), proc.tmp0);
+            cpu.write(bus, cpu.regs.hl(), proc.tmp0);
             cpu.regs.f.set(FlagsReg::Zero, proc.tmp0 == 0);
             cpu.regs.f.set(FlagsReg::Negative, false);
             cpu.regs.f.set(FlagsReg::HalfCarry, proc.tmp1 != 0);
@@ -644,7 +1120,7 @@ fn inc_r(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
 fn dec_r(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         1 => {
-            let opcode = bus.read(cpu.regs.pc - 1);
+            let opcode = proc.opcode;
             
             let reg = match (opcode & 0b00111000) >> 3 { // r[y]
                 0 => &mut cpu.regs.b,
@@ -666,12 +1142,12 @@ fn dec_r(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
             proc.done = true;
         },
         2 => {
-            let (result, _, _, half, _) = alu_sub(bus.read(cpu.regs.hl()), 1);
+            let (result, _, _, half, _) = alu_sub(cpu.read(bus, cpu.regs.hl()), 1);
             proc.tmp0 = result;
             proc.tmp1 = half as u8;
         },
         3 => {
-            bus.write(cpu.regs.hl(), proc.tmp0);
+            cpu.write(bus, cpu.regs.hl(), proc.tmp0);
             cpu.regs.f.set(FlagsReg::Zero, proc.tmp0 == 0);
             cpu.regs.f.set(FlagsReg::Negative, true);
             cpu.regs.f.set(FlagsReg::HalfCarry, proc.tmp1 != 0);
@@ -685,16 +1161,18 @@ fn dec_r(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
 fn inc_rp(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         1 => {
-            let opcode = bus.read(cpu.regs.pc - 1);
+            let opcode = proc.opcode;
             proc.tmp0 = (opcode & 0b00110000) >> 4; // p
             
-            let result = match proc.tmp0 { // calculate new val
-                0 => cpu.regs.bc().wrapping_add(1),
-                1 => cpu.regs.de().wrapping_add(1),
-                2 => cpu.regs.hl().wrapping_add(1),
-                3 => cpu.regs.sp.wrapping_add(1),
+            let val = match proc.tmp0 {
+                0 => cpu.regs.bc(),
+                1 => cpu.regs.de(),
+                2 => cpu.regs.hl(),
+                3 => cpu.regs.sp,
                 _ => panic!("unreachable")
             };
+            Cpu::idu(bus, val);
+            let result = val.wrapping_add(1); // calculate new val
             proc.tmp1 = (result >> 8) as u8; // store upper
             
             match proc.tmp0 { // write lower
@@ -722,16 +1200,18 @@ fn inc_rp(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
 fn dec_rp(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         1 => {
-            let opcode = bus.read(cpu.regs.pc - 1);
+            let opcode = proc.opcode;
             proc.tmp0 = (opcode & 0b00110000) >> 4; // p
             
-            let result = match proc.tmp0 { // calculate new val
-                0 => cpu.regs.bc().wrapping_sub(1),
-                1 => cpu.regs.de().wrapping_sub(1),
-                2 => cpu.regs.hl().wrapping_sub(1),
-                3 => cpu.regs.sp.wrapping_sub(1),
+            let val = match proc.tmp0 {
+                0 => cpu.regs.bc(),
+                1 => cpu.regs.de(),
+                2 => cpu.regs.hl(),
+                3 => cpu.regs.sp,
                 _ => panic!("unreachable")
             };
+            Cpu::idu(bus, val);
+            let result = val.wrapping_sub(1); // calculate new val
             proc.tmp1 = (result >> 8) as u8; // store upper
             
             match proc.tmp0 { // write lower
@@ -740,34 +1220,110 @@ fn dec_rp(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
                 2 => cpu.regs.l = result as u8,
                 3 => cpu.regs.set_splo(result as u8),
                 _ => panic!("unreachable")
-            }
+            }
+        },
+        2 => {
+            match proc.tmp0 { // write upper
+                0 => cpu.regs.b = proc.tmp1,
+                1 => cpu.regs.d = proc.tmp1,
+                2 => cpu.regs.h = proc.tmp1,
+                3 => cpu.regs.set_sphi(proc.tmp1),
+                _ => panic!("unreachable")
+            }
+            
+            proc.done = true;
+        },
+        _ => ()
+    }
+}
+
+/// 0x80 - 0x87
+fn add_ar(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
+    match proc.mcycle {
+        1 => {
+            let opcode = proc.opcode;
+            
+            let (result, zer, _, half, carry) = alu_add(cpu.regs.a, match opcode & 0b00000111 {
+                0 => cpu.regs.b,
+                1 => cpu.regs.c,
+                2 => cpu.regs.d,
+                3 => cpu.regs.e,
+                4 => cpu.regs.h,
+                5 => cpu.regs.l,
+                6 => return, // wait another mcycle for special case 'A,(HL)'
+                7 => cpu.regs.a,
+                _ => panic!("unreachable")
+            });
+            cpu.regs.a = result;
+            cpu.regs.f.set(FlagsReg::Zero, zer);
+            cpu.regs.f.set(FlagsReg::Negative, false);
+            cpu.regs.f.set(FlagsReg::HalfCarry, half);
+            cpu.regs.f.set(FlagsReg::Carry, carry);
+            
+            proc.done = true;
+        },
+        2 => {
+            let (result, zer, _, half, carry) = alu_add(cpu.regs.a, cpu.read(bus, cpu.regs.hl()));
+            cpu.regs.a = result;
+            cpu.regs.f.set(FlagsReg::Zero, zer);
+            cpu.regs.f.set(FlagsReg::Negative, false);
+            cpu.regs.f.set(FlagsReg::HalfCarry, half);
+            cpu.regs.f.set(FlagsReg::Carry, carry);
+            
+            proc.done = true;
+        },
+        _ => ()
+    }
+}
+/// 0x88 - 0x8F
+fn adc_ar(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
+    match proc.mcycle {
+        1 => {
+            let opcode = proc.opcode;
+            
+            let (result, zer, _, half, carry) = alu_adc(cpu.regs.a, match opcode & 0b00000111 {
+                0 => cpu.regs.b,
+                1 => cpu.regs.c,
+                2 => cpu.regs.d,
+                3 => cpu.regs.e,
+                4 => cpu.regs.h,
+                5 => cpu.regs.l,
+                6 => return, // wait another mcycle for special case 'A,(HL)'
+                7 => cpu.regs.a,
+                _ => panic!("unreachable")
+            }, cpu.regs.f.contains(FlagsReg::Carry));
+            cpu.regs.a = result;
+            cpu.regs.f.set(FlagsReg::Zero, zer);
+            cpu.regs.f.set(FlagsReg::Negative, false);
+            cpu.regs.f.set(FlagsReg::HalfCarry, half);
+            cpu.regs.f.set(FlagsReg::Carry, carry);
+            
+            proc.done = true;
         },
         2 => {
-            match proc.tmp0 >> 4 { // write upper
-                0 => cpu.regs.b = proc.tmp1,
-                1 => cpu.regs.d = proc.tmp1,
-                2 => cpu.regs.h = proc.tmp1,
-                3 => cpu.regs.set_sphi(proc.tmp1),
-                _ => panic!("unreachable")
-            }
+            let (result, zer, _, half, carry) = alu_adc(cpu.regs.a, cpu.read(bus, cpu.regs.hl()), cpu.regs.f.contains(FlagsReg::Carry));
+            cpu.regs.a = result;
+            cpu.regs.f.set(FlagsReg::Zero, zer);
+            cpu.regs.f.set(FlagsReg::Negative, false);
+            cpu.regs.f.set(FlagsReg::HalfCarry, half);
+            cpu.regs.f.set(FlagsReg::Carry, carry);
             
             proc.done = true;
         },
         _ => ()
     }
 }
-
-/// 0x80 - 0x87
-fn add_ar(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
+/// 0x90 - 0x97
+fn sub_ar(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         1 => {
-            let opcode = bus.read(cpu.regs.pc - 1);
+            let opcode = proc.opcode;
             
-            let (result, zer, _, half, carry) = alu_add(cpu.regs.a, match opcode & 0b00000111 {
+            let (result, zer, _, half, carry) = alu_sub(cpu.regs.a, match opcode & 0b00000111 {
                 0 => cpu.regs.b,
                 1 => cpu.regs.c,
                 2 => cpu.regs.d,
-                3 => cpu.regs.d,
+                3 => cpu.regs.e,
                 4 => cpu.regs.h,
                 5 => cpu.regs.l,
                 6 => return, // wait another mcycle for special case 'A,(HL)'
@@ -776,17 +1332,17 @@ fn add_ar(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
             });
             cpu.regs.a = result;
             cpu.regs.f.set(FlagsReg::Zero, zer);
-            cpu.regs.f.set(FlagsReg::Negative, false);
+            cpu.regs.f.set(FlagsReg::Negative, true);
             cpu.regs.f.set(FlagsReg::HalfCarry, half);
             cpu.regs.f.set(FlagsReg::Carry, carry);
             
             proc.done = true;
         },
         2 => {
-            let (result, zer, _, half, carry) = alu_add(cpu.regs.a, bus.read(cpu.regs.hl()));
+            let (result, zer, _, half, carry) = alu_sub(cpu.regs.a, cpu.read(bus, cpu.regs.hl()));
             cpu.regs.a = result;
             cpu.regs.f.set(FlagsReg::Zero, zer);
-            cpu.regs.f.set(FlagsReg::Negative, false);
+            cpu.regs.f.set(FlagsReg::Negative, true);
             cpu.regs.f.set(FlagsReg::HalfCarry, half);
             cpu.regs.f.set(FlagsReg::Carry, carry);
             
@@ -795,23 +1351,23 @@ fn add_ar(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
         _ => ()
     }
 }
-/// 0x90 - 0x97
-fn sub_ar(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
+/// 0x98 - 0x9F
+fn sbc_ar(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         1 => {
-            let opcode = bus.read(cpu.regs.pc - 1);
+            let opcode = proc.opcode;
             
-            let (result, zer, _, half, carry) = alu_sub(cpu.regs.a, match opcode & 0b00000111 {
+            let (result, zer, _, half, carry) = alu_sbc(cpu.regs.a, match opcode & 0b00000111 {
                 0 => cpu.regs.b,
                 1 => cpu.regs.c,
                 2 => cpu.regs.d,
-                3 => cpu.regs.d,
+                3 => cpu.regs.e,
                 4 => cpu.regs.h,
                 5 => cpu.regs.l,
                 6 => return, // wait another mcycle for special case 'A,(HL)'
                 7 => cpu.regs.a,
                 _ => panic!("unreachable")
-            });
+            }, cpu.regs.f.contains(FlagsReg::Carry));
             cpu.regs.a = result;
             cpu.regs.f.set(FlagsReg::Zero, zer);
             cpu.regs.f.set(FlagsReg::Negative, true);
@@ -821,7 +1377,7 @@ fn sub_ar(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
             proc.done = true;
         },
         2 => {
-            let (result, zer, _, half, carry) = alu_sub(cpu.regs.a, bus.read(cpu.regs.hl()));
+            let (result, zer, _, half, carry) = alu_sbc(cpu.regs.a, cpu.read(bus, cpu.regs.hl()), cpu.regs.f.contains(FlagsReg::Carry));
             cpu.regs.a = result;
             cpu.regs.f.set(FlagsReg::Zero, zer);
             cpu.regs.f.set(FlagsReg::Negative, true);
@@ -837,13 +1393,13 @@ fn sub_ar(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
 fn and_ar(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         1 => {
-            let opcode = bus.read(cpu.regs.pc - 1);
+            let opcode = proc.opcode;
             
             cpu.regs.a &= match opcode & 0b00000111 {
                 0 => cpu.regs.b,
                 1 => cpu.regs.c,
                 2 => cpu.regs.d,
-                3 => cpu.regs.d,
+                3 => cpu.regs.e,
                 4 => cpu.regs.h,
                 5 => cpu.regs.l,
                 6 => return, // wait another mcycle for special case 'A,(HL)'
@@ -856,7 +1412,7 @@ fn and_ar(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
             proc.done = true;
         },
         2 => {
-            cpu.regs.a &= bus.read(cpu.regs.hl());
+            cpu.regs.a &= cpu.read(bus, cpu.regs.hl());
             cpu.regs.f.bits = 0;
             cpu.regs.f.set(FlagsReg::Zero, cpu.regs.a == 0);
             cpu.regs.f.set(FlagsReg::HalfCarry, true);
@@ -870,13 +1426,13 @@ fn and_ar(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
 fn xor_ar(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         1 => {
-            let opcode = bus.read(cpu.regs.pc - 1);
+            let opcode = proc.opcode;
             
             cpu.regs.a ^= match opcode & 0b00000111 {
                 0 => cpu.regs.b,
                 1 => cpu.regs.c,
                 2 => cpu.regs.d,
-                3 => cpu.regs.d,
+                3 => cpu.regs.e,
                 4 => cpu.regs.h,
                 5 => cpu.regs.l,
                 6 => return, // wait another mcycle for special case 'A,(HL)'
@@ -889,7 +1445,7 @@ fn xor_ar(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
             proc.done = true;
         },
         2 => {
-            cpu.regs.a ^= bus.read(cpu.regs.hl());
+            cpu.regs.a ^= cpu.read(bus, cpu.regs.hl());
             cpu.regs.f.bits = 0;
             cpu.regs.f.set(FlagsReg::Zero, cpu.regs.a == 0);
             
@@ -902,13 +1458,13 @@ fn xor_ar(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
 fn or_ar(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         1 => {
-            let opcode = bus.read(cpu.regs.pc - 1);
+            let opcode = proc.opcode;
             
             cpu.regs.a |= match opcode & 0b00000111 {
                 0 => cpu.regs.b,
                 1 => cpu.regs.c,
                 2 => cpu.regs.d,
-                3 => cpu.regs.d,
+                3 => cpu.regs.e,
                 4 => cpu.regs.h,
                 5 => cpu.regs.l,
                 6 => return, // wait another mcycle for special case 'A,(HL)'
@@ -921,7 +1477,7 @@ fn or_ar(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
             proc.done = true;
         },
         2 => {
-            cpu.regs.a |= bus.read(cpu.regs.hl());
+            cpu.regs.a |= cpu.read(bus, cpu.regs.hl());
             cpu.regs.f.bits = 0;
             cpu.regs.f.set(FlagsReg::Zero, cpu.regs.a == 0);
             
@@ -934,13 +1490,13 @@ fn or_ar(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
 fn cp_ar(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         1 => {
-            let opcode = bus.read(cpu.regs.pc - 1);
+            let opcode = proc.opcode;
             
             let (result, zer, _, half, carry) = alu_sub(cpu.regs.a, match opcode & 0b00000111 {
                 0 => cpu.regs.b,
                 1 => cpu.regs.c,
                 2 => cpu.regs.d,
-                3 => cpu.regs.d,
+                3 => cpu.regs.e,
                 4 => cpu.regs.h,
                 5 => cpu.regs.l,
                 6 => return, // wait another mcycle for special case 'A,(HL)'
@@ -955,7 +1511,7 @@ fn cp_ar(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
             proc.done = true;
         },
         2 => {
-            let (result, zer, _, half, carry) = alu_sub(cpu.regs.a, bus.read(cpu.regs.hl()));
+            let (result, zer, _, half, carry) = alu_sub(cpu.regs.a, cpu.read(bus, cpu.regs.hl()));
             cpu.regs.f.set(FlagsReg::Zero, zer);
             cpu.regs.f.set(FlagsReg::Negative, true);
             cpu.regs.f.set(FlagsReg::HalfCarry, half);
@@ -968,10 +1524,13 @@ fn cp_ar(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
 }
 
 /// 0x06, 0x16, 0x26, 0x36, 0x0E, 0x1E, 0x2E, 0x3E
+/// 
+/// Register destinations take 2 m-cycles. `LD (HL),d8` takes 3, as the immediate is fetched in
+/// m-cycle 2 and the write to (HL) needs an m-cycle of its own.
 fn ld_ru8(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         2 => {
-            let opcode = bus.read(cpu.regs.pc - 1);
+            let opcode = proc.opcode;
             proc.tmp0 = cpu.fetch(bus);
             
             match (opcode & 0b00111000) >> 3 {
@@ -989,7 +1548,7 @@ fn ld_ru8(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
             proc.done = true;
         },
         3 => {
-            bus.write(cpu.regs.hl(), proc.tmp0);
+            cpu.write(bus, cpu.regs.hl(), proc.tmp0);
             
             proc.done = true;
         },
@@ -1000,7 +1559,7 @@ fn ld_ru8(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
 fn ld_rr(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         1 => {
-            let opcode = bus.read(cpu.regs.pc - 1);
+            let opcode = proc.opcode;
             let y = (opcode & 0b00111000) >> 3;
             let z = opcode & 0b00000111;
             proc.tmp0 = y;
@@ -1037,7 +1596,7 @@ fn ld_rr(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
             let z = proc.tmp1;
             
             if y == 6 {
-                bus.write(cpu.regs.hl(), match z {
+                cpu.write(bus, cpu.regs.hl(), match z {
                     0 => cpu.regs.b,
                     1 => cpu.regs.c,
                     2 => cpu.regs.d,
@@ -1049,7 +1608,7 @@ fn ld_rr(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
                     _ => panic!("unreachable")
                 })
             } else if z == 6 {
-                let val = bus.read(cpu.regs.hl());
+                let val = cpu.read(bus, cpu.regs.hl());
                 match y {
                     0 => cpu.regs.b = val,
                     1 => cpu.regs.c = val,
@@ -1069,52 +1628,17 @@ fn ld_rr(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     }
 }
 
-/// 0x07
-fn rlca(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
-    match proc.mcycle {
-        1 => {
-            cpu.regs.f.bits = 0;
-            cpu.regs.f.set(FlagsReg::Carry, (cpu.regs.a & 0x80) != 0);
-            cpu.regs.a = cpu.regs.a.rotate_left(1);
-            
-            proc.done = true;
-        },
-        _ => ()
-    }
-}
-/// 0x0F
-fn rrca(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
-    match proc.mcycle {
-        1 => {
-            cpu.regs.f.bits = 0;
-            cpu.regs.f.set(FlagsReg::Carry, (cpu.regs.a & 0x01) != 0);
-            cpu.regs.a = cpu.regs.a.rotate_right(1);
-            
-            proc.done = true;
-        },
-        _ => ()
-    }
-}
-/// 0x17
-fn rla(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
+/// 0x07, 0x0F, 0x17, 0x1F
+/// 
+/// Unlike the CB-prefixed rotates, these always clear the Zero flag, even when the result is zero.
+fn rot_a(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         1 => {
-            let carry = (cpu.regs.a & 0x80) != 0;
-            cpu.regs.a = (cpu.regs.a.rotate_left(1) & 0xFE) | (cpu.regs.f.intersects(FlagsReg::Carry) as u8);
-            cpu.regs.f.bits = 0;
-            cpu.regs.f.set(FlagsReg::Carry, carry);
+            let opcode = proc.opcode;
+            let y = (opcode & 0b00111000) >> 3;
             
-            proc.done = true;
-        },
-        _ => ()
-    }
-}
-/// 0x1F
-fn rra(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
-    match proc.mcycle {
-        1 => {
-            let carry = (cpu.regs.a & 0x01) != 0;
-            cpu.regs.a = (cpu.regs.a.rotate_right(1) & 0x7F) | ((cpu.regs.f.intersects(FlagsReg::Carry) as u8) << 7);
+            let (result, carry) = alu_rot(y, cpu.regs.a, cpu.regs.f.intersects(FlagsReg::Carry));
+            cpu.regs.a = result;
             cpu.regs.f.bits = 0;
             cpu.regs.f.set(FlagsReg::Carry, carry);
             
@@ -1127,7 +1651,31 @@ fn rra(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
 fn daa(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         1 => {
-            todo!();
+            let mut a = cpu.regs.a;
+            let mut carry = cpu.regs.f.contains(FlagsReg::Carry);
+            let half = cpu.regs.f.contains(FlagsReg::HalfCarry);
+            
+            if !cpu.regs.f.contains(FlagsReg::Negative) { // previous op was an addition
+                if carry || a > 0x99 {
+                    a = a.wrapping_add(0x60);
+                    carry = true;
+                }
+                if half || (a & 0x0F) > 0x09 {
+                    a = a.wrapping_add(0x06);
+                }
+            } else { // previous op was a subtraction
+                if carry {
+                    a = a.wrapping_sub(0x60);
+                }
+                if half {
+                    a = a.wrapping_sub(0x06);
+                }
+            }
+            
+            cpu.regs.a = a;
+            cpu.regs.f.set(FlagsReg::Zero, a == 0);
+            cpu.regs.f.set(FlagsReg::HalfCarry, false);
+            cpu.regs.f.set(FlagsReg::Carry, carry);
             
             proc.done = true;
         },
@@ -1138,7 +1686,9 @@ fn daa(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
 fn cpl(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         1 => {
-            todo!();
+            cpu.regs.a = !cpu.regs.a;
+            cpu.regs.f.set(FlagsReg::Negative, true);
+            cpu.regs.f.set(FlagsReg::HalfCarry, true);
             
             proc.done = true;
         },
@@ -1149,7 +1699,9 @@ fn cpl(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
 fn scf(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         1 => {
-            todo!();
+            cpu.regs.f.set(FlagsReg::Negative, false);
+            cpu.regs.f.set(FlagsReg::HalfCarry, false);
+            cpu.regs.f.set(FlagsReg::Carry, true);
             
             proc.done = true;
         },
@@ -1160,7 +1712,9 @@ fn scf(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
 fn ccf(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         1 => {
-            todo!();
+            cpu.regs.f.set(FlagsReg::Negative, false);
+            cpu.regs.f.set(FlagsReg::HalfCarry, false);
+            cpu.regs.f.toggle(FlagsReg::Carry);
             
             proc.done = true;
         },
@@ -1217,6 +1771,22 @@ fn sub_au8(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
         _ => ()
     }
 }
+/// 0xDE
+fn sbc_au8(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
+    match proc.mcycle {
+        2 => {
+            let (result, zer, _, half, carry) = alu_sbc(cpu.regs.a, cpu.fetch(bus), cpu.regs.f.contains(FlagsReg::Carry));
+            cpu.regs.a = result;
+            cpu.regs.f.set(FlagsReg::Zero, zer);
+            cpu.regs.f.set(FlagsReg::Negative, true);
+            cpu.regs.f.set(FlagsReg::HalfCarry, half);
+            cpu.regs.f.set(FlagsReg::Carry, carry);
+            
+            proc.done = true;
+        },
+        _ => ()
+    }
+}
 /// 0xE6
 fn and_au8(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
@@ -1279,7 +1849,7 @@ fn cp_au8(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
 fn ld_toindirect(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         1 => {
-            let opcode = bus.read(cpu.regs.pc - 1);
+            let opcode = proc.opcode;
             proc.tmp0 = (opcode & 0b00110000) >> 4; // p
         },
         2 => {
@@ -1301,7 +1871,7 @@ fn ld_toindirect(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus)
                 _ => panic!("unreachable")
             };
             
-            bus.write(addr, cpu.regs.a);
+            cpu.write(bus, addr, cpu.regs.a);
             
             proc.done = true;
         },
@@ -1314,7 +1884,7 @@ fn ld_toindirect(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus)
 fn ld_fromindirect(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         1 => {
-            let opcode = bus.read(cpu.regs.pc - 1);
+            let opcode = proc.opcode;
             proc.tmp0 = (opcode & 0b00110000) >> 4; // p
         },
         2 => {
@@ -1336,7 +1906,7 @@ fn ld_fromindirect(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus
                 _ => panic!("unreachable")
             };
             
-            cpu.regs.a = bus.read(addr);
+            cpu.regs.a = if proc.tmp0 >= 2 { cpu.read_idu(bus, addr) } else { cpu.read(bus, addr) };
             
             proc.done = true;
         },
@@ -1349,7 +1919,7 @@ fn ld_fromindirect(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus
 fn pop(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         1 => {
-            let opcode = bus.read(cpu.regs.pc - 1);
+            let opcode = proc.opcode;
             proc.tmp0 = (opcode & 0b00110000) >> 4;
         },
         2 => match proc.tmp0 {
@@ -1377,10 +1947,10 @@ fn pop(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
 fn push(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         1 => {
-            let opcode = bus.read(cpu.regs.pc - 1);
+            let opcode = proc.opcode;
             proc.tmp0 = (opcode & 0b00110000) >> 4;
         },
-        2 => (),
+        2 => Cpu::idu(bus, cpu.regs.sp),
         3 => match proc.tmp0 {
             0 => cpu.stack_push(bus, cpu.regs.b),
             1 => cpu.stack_push(bus, cpu.regs.d),
@@ -1410,7 +1980,7 @@ fn call_cond(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
         3 => {
             proc.tmp1 = cpu.fetch(bus);
             
-            let opcode = bus.read(cpu.regs.pc - 3);
+            let opcode = proc.opcode;
             let cond = match (opcode & 0b00111000) >> 3 { // cc[y]
                 0 => !cpu.regs.f.contains(FlagsReg::Zero),
                 1 => cpu.regs.f.contains(FlagsReg::Zero),
@@ -1454,6 +2024,20 @@ fn call_u16(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
         _ => ()
     }
 }
+/// 0xC7, 0xCF, 0xD7, 0xDF, 0xE7, 0xEF, 0xF7, 0xFF
+fn rst(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
+    match proc.mcycle {
+        2 => Cpu::idu(bus, cpu.regs.sp),
+        3 => cpu.stack_push(bus, cpu.regs.pchi()),
+        4 => {
+            cpu.stack_push(bus, cpu.regs.pclo());
+            cpu.regs.pc = (proc.opcode & 0b00111000) as u16;
+            
+            proc.done = true;
+        },
+        _ => ()
+    }
+}
 
 fn ret(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
@@ -1468,11 +2052,28 @@ fn ret(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
         _ => ()
     }
 }
+/// 0xD9
+/// 
+/// Unlike EI, interrupts are enabled immediately rather than after the next instruction.
+fn reti(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
+    match proc.mcycle {
+        2 => proc.tmp0 = cpu.stack_pop(bus),
+        3 => proc.tmp1 = cpu.stack_pop(bus),
+        4 => {
+            cpu.regs.set_pclo(proc.tmp0);
+            cpu.regs.set_pchi(proc.tmp1);
+            cpu.ime = true;
+            
+            proc.done = true;
+        },
+        _ => ()
+    }
+}
 /// 0xC0, 0xC8, 0xD0, 0xD8
 fn ret_cond(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         2 => {
-            let opcode = bus.read(cpu.regs.pc - 1);
+            let opcode = proc.opcode;
             let cond = match (opcode & 0b00111000) >> 3 { // cc[y]
                 0 => !cpu.regs.f.contains(FlagsReg::Zero),
                 1 => cpu.regs.f.contains(FlagsReg::Zero),
@@ -1498,10 +2099,13 @@ fn ret_cond(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
 }
 
 /// 0x09, 0x19, 0x29, 0x39
+/// 
+/// Half-carry is from bit 11 and carry from bit 15. Zero is left untouched. Both operands are read
+/// before L is written, so ADD HL,HL doubles the original value.
 fn add_hlrp(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         1 => {
-            let opcode = bus.read(cpu.regs.pc - 1);
+            let opcode = proc.opcode;
             let p = (opcode & 0b00110000) >> 4; // p
             
             let val = match p {
@@ -1539,11 +2143,50 @@ fn ld_sphl(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     }
 }
 
+/// 0xE8
+/// 
+/// Flags are computed from the unsigned addition of the immediate to the low byte of SP.
+fn add_sp_i8(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
+    match proc.mcycle {
+        2 => proc.tmp0 = cpu.fetch(bus),
+        3 => {
+            let (_, _, _, half, carry) = alu_add(cpu.regs.splo(), proc.tmp0);
+            cpu.regs.f.bits = 0;
+            cpu.regs.f.set(FlagsReg::HalfCarry, half);
+            cpu.regs.f.set(FlagsReg::Carry, carry);
+        },
+        4 => {
+            cpu.regs.sp = cpu.regs.sp.wrapping_add(proc.tmp0 as i8 as u16);
+            
+            proc.done = true;
+        },
+        _ => ()
+    }
+}
+/// 0xF8
+/// 
+/// Flags are computed from the unsigned addition of the immediate to the low byte of SP.
+fn ld_hl_sp_i8(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
+    match proc.mcycle {
+        2 => proc.tmp0 = cpu.fetch(bus),
+        3 => {
+            let (_, _, _, half, carry) = alu_add(cpu.regs.splo(), proc.tmp0);
+            cpu.regs.f.bits = 0;
+            cpu.regs.f.set(FlagsReg::HalfCarry, half);
+            cpu.regs.f.set(FlagsReg::Carry, carry);
+            cpu.regs.set_hl(cpu.regs.sp.wrapping_add(proc.tmp0 as i8 as u16));
+            
+            proc.done = true;
+        },
+        _ => ()
+    }
+}
+
 /// 0x01, 0x11, 0x21, 0x31
 fn ld_rpu16(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         1 => {
-            let opcode = bus.read(cpu.regs.pc - 1);
+            let opcode = proc.opcode;
             proc.tmp0 = (opcode & 0b00110000) >> 4;
         },
         2 => match proc.tmp0 {
@@ -1572,7 +2215,7 @@ fn ld_rpu16(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
 fn ld_toio_c(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         2 => {
-            bus.write(0xFF00 + (cpu.regs.c as u16), cpu.regs.a);
+            cpu.write(bus, 0xFF00 + (cpu.regs.c as u16), cpu.regs.a);
             
             proc.done = true;
         },
@@ -1583,7 +2226,7 @@ fn ld_toio_c(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
 fn ld_fromio_c(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         2 => {
-            cpu.regs.a = bus.read(0xFF00 + (cpu.regs.c as u16));
+            cpu.regs.a = cpu.read(bus, 0xFF00 + (cpu.regs.c as u16));
             
             proc.done = true;
         },
@@ -1595,7 +2238,7 @@ fn ld_toio_u8(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         2 => proc.tmp0 = cpu.fetch(bus),
         3 => {
-            bus.write(0xFF00 + (proc.tmp0 as u16), cpu.regs.a);
+            cpu.write(bus, 0xFF00 + (proc.tmp0 as u16), cpu.regs.a);
             
             proc.done = true;
         },
@@ -1607,7 +2250,7 @@ fn ld_fromio_u8(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         2 => proc.tmp0 = cpu.fetch(bus),
         3 => {
-            cpu.regs.a = bus.read(0xFF00 + (proc.tmp0 as u16));
+            cpu.regs.a = cpu.read(bus, 0xFF00 + (proc.tmp0 as u16));
             
             proc.done = true;
         },
@@ -1622,10 +2265,14 @@ fn ld_fromio_u8(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
 //   This quirk shouldn't be a problem unless we're fetching from something that could be manipulated
 // between fetch cycles.
 
-fn rot(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) { //TODO: Test this instruction to make sure everything is accurate
+/// 0b00nn_nnnn
+/// 
+/// Sets Zero if the result is zero (unlike RLCA/RRCA/RLA/RRA), clears Negative and HalfCarry, and
+/// sets Carry to the bit shifted out.
+fn rot(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         2 => {
-            let opcode = bus.read(cpu.regs.pc - 1);
+            let opcode = proc.opcode;
             let y = (opcode & 0b00111000) >> 3;
             let z = opcode & 0b00000111;
             proc.tmp0 = y;
@@ -1642,45 +2289,23 @@ fn rot(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) { //TODO:
                 _ => panic!("unreachable")
             };
             
-            let carry = cpu.regs.f.intersects(FlagsReg::Carry) as u8;
-            let (carry, result) = match y { // rot[y]
-                0 => (*reg & 0x80, reg.rotate_left(1)), // RLC - Rotate Left
-                1 => (*reg & 0x01, reg.rotate_right(1)), // RRC - Rotate Right
-                2 => (*reg & 0x80, (*reg << 1) | carry), // RL  - Rotate Left Through Carry
-                3 => (*reg & 0x01, (*reg >> 1) | (carry << 7)), // RR  - Rotate Right Through Carry
-                4 => (*reg & 0x80, *reg << 1), // SLA - Shift Left Arithmetic
-                5 => (*reg & 0x01, (*reg >> 1) | (*reg & 0x80)), // SRA - Shift Right Arithmetic
-                6 => (carry, (*reg >> 4) | (*reg & 0xF0)), // SWAP - Swap Nibbles
-                7 => (*reg & 0x01, *reg >> 1), // SRL - Shift Right Logical
-                _ => panic!("unreachable")
-            };
+            let (result, carry) = alu_rot(y, *reg, cpu.regs.f.intersects(FlagsReg::Carry));
             cpu.regs.f.bits = 0;
             cpu.regs.f.set(FlagsReg::Zero, result == 0);
-            cpu.regs.f.set(FlagsReg::Carry, carry != 0);
+            cpu.regs.f.set(FlagsReg::Carry, carry);
             *reg = result;
             
             proc.done = true;
         },
-        3 => proc.tmp1 = bus.read(cpu.regs.hl()),
+        3 => proc.tmp1 = cpu.read(bus, cpu.regs.hl()),
         4 => {
             let val = proc.tmp1;
             
-            let carry = cpu.regs.f.intersects(FlagsReg::Carry) as u8;
-            let (carry, result) = match proc.tmp0 { // rot[y]
-                0 => (val & 0x80, val.rotate_left(1)), // RLC - Rotate Left
-                1 => (val & 0x01, val.rotate_right(1)), // RRC - Rotate Right
-                2 => (val & 0x80, (val << 1) | carry), // RL  - Rotate Left Through Carry
-                3 => (val & 0x01, (val >> 1) | (carry << 7)), // RR  - Rotate Right Through Carry
-                4 => (val & 0x80, val << 1), // SLA - Shift Left Arithmetic
-                5 => (val & 0x01, (val >> 1) | (val & 0x80)), // SRA - Shift Right Arithmetic
-                6 => (carry, (val >> 4) | (val & 0xF0)), // SWAP - Swap Nibbles
-                7 => (val & 0x01, val >> 1), // SRL - Shift Right Logical
-                _ => panic!("unreachable")
-            };
+            let (result, carry) = alu_rot(proc.tmp0, val, cpu.regs.f.intersects(FlagsReg::Carry));
             cpu.regs.f.bits = 0;
             cpu.regs.f.set(FlagsReg::Zero, result == 0);
-            cpu.regs.f.set(FlagsReg::Carry, carry != 0);
-            bus.write(cpu.regs.hl(), val);
+            cpu.regs.f.set(FlagsReg::Carry, carry);
+            cpu.write(bus, cpu.regs.hl(), result);
             
             proc.done = true;
         },
@@ -1689,10 +2314,12 @@ fn rot(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) { //TODO:
 }
 
 /// 0b01nn_nnnn
+/// 
+/// Sets Zero if bit `y` is clear, clears Negative, sets HalfCarry, and always preserves Carry.
 fn bit(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         2 => {
-            let opcode = bus.read(cpu.regs.pc - 1);
+            let opcode = proc.opcode;
             let y = (opcode & 0b00111000) >> 3;
             let z = opcode & 0b00000111;
             proc.tmp0 = y;
@@ -1716,7 +2343,7 @@ fn bit(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
             proc.done = true;
         },
         3 => {
-            let val = bus.read(cpu.regs.hl()) & (1 << proc.tmp0);
+            let val = cpu.read(bus, cpu.regs.hl()) & (1 << proc.tmp0);
             
             cpu.regs.f.set(FlagsReg::Zero, val == 0);
             cpu.regs.f.set(FlagsReg::Negative, false);
@@ -1728,10 +2355,11 @@ fn bit(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     }
 }
 
+/// 0b10nn_nnnn
 fn res(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         2 => {
-            let opcode = bus.read(cpu.regs.pc - 1);
+            let opcode = proc.opcode;
             let y = (opcode & 0b00111000) >> 3;
             let z = opcode & 0b00000111;
             proc.tmp0 = y;
@@ -1750,9 +2378,9 @@ fn res(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
             
             proc.done = true;
         },
-        3 => proc.tmp1 = bus.read(cpu.regs.hl()),
+        3 => proc.tmp1 = cpu.read(bus, cpu.regs.hl()),
         4 => {
-            bus.write(cpu.regs.hl(), proc.tmp1 & (!(1 << proc.tmp0)) );
+            cpu.write(bus, cpu.regs.hl(), proc.tmp1 & (!(1 << proc.tmp0)) );
             
             proc.done = true;
         },
@@ -1760,10 +2388,11 @@ fn res(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     }
 }
 
+/// 0b11nn_nnnn
 fn set(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     match proc.mcycle {
         2 => {
-            let opcode = bus.read(cpu.regs.pc - 1);
+            let opcode = proc.opcode;
             let y = (opcode & 0b00111000) >> 3;
             let z = opcode & 0b00000111;
             proc.tmp0 = y;
@@ -1782,9 +2411,9 @@ fn set(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
             
             proc.done = true;
         },
-        3 => proc.tmp1 = bus.read(cpu.regs.hl()),
+        3 => proc.tmp1 = cpu.read(bus, cpu.regs.hl()),
         4 => {
-            bus.write(cpu.regs.hl(), proc.tmp1 | (1 << proc.tmp0) );
+            cpu.write(bus, cpu.regs.hl(), proc.tmp1 | (1 << proc.tmp0) );
             
             proc.done = true;
         },
@@ -1793,6 +2422,25 @@ fn set(proc: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
 }
 
 // ALU Utilities
+
+/// Performs the rotate/shift operation `rot[op]`, returning the result and the new carry.
+#[inline(always)]
+fn alu_rot(op: u8, val: u8, carry: bool) -> (u8, bool) {
+    let carry = carry as u8;
+    let (carry, result) = match op { // rot[y]
+        0 => (val & 0x80, val.rotate_left(1)), // RLC - Rotate Left
+        1 => (val & 0x01, val.rotate_right(1)), // RRC - Rotate Right
+        2 => (val & 0x80, (val << 1) | carry), // RL  - Rotate Left Through Carry
+        3 => (val & 0x01, (val >> 1) | (carry << 7)), // RR  - Rotate Right Through Carry
+        4 => (val & 0x80, val << 1), // SLA - Shift Left Arithmetic
+        5 => (val & 0x01, (val >> 1) | (val & 0x80)), // SRA - Shift Right Arithmetic
+        6 => (0, val.rotate_left(4)), // SWAP - Swap Nibbles
+        7 => (val & 0x01, val >> 1), // SRL - Shift Right Logical
+        _ => panic!("unreachable")
+    };
+    
+    (result, carry != 0)
+}
 #[inline(always)]
 fn alu_add(lhs: u8, rhs: u8) -> (u8, bool, bool, bool, bool) {
     let result = lhs.wrapping_add(rhs);
@@ -1812,7 +2460,7 @@ fn alu_add_u16(lhs: u16, rhs: u16) -> (u16, bool, bool, bool, bool) {
         result,
         result == 0,
         (result as i16).is_negative(),
-        ((lhs & 0x0FFF).wrapping_add(rhs & 0x0FFF) & 0x1000) != 0, //TODO: check if this is correct for 'add'
+        ((lhs & 0x0FFF).wrapping_add(rhs & 0x0FFF) & 0x1000) != 0,
         lhs.overflowing_add(rhs).1,
     )
 }
@@ -1839,4 +2487,468 @@ fn alu_adc(lhs: u8, rhs: u8, carry: bool) -> (u8, bool, bool, bool, bool) {
         ((lhs & 0x0F).wrapping_add(rhs & 0x0F).wrapping_add(carry as u8) & 0x10) != 0, //TODO: check if this is correct for 'adc'
         lhs.overflowing_add(rhs).1 || (carry && result == 0x00),
     )
-}
\ No newline at end of file
+}
+
+#[inline(always)]
+fn alu_sbc(lhs: u8, rhs: u8, carry: bool) -> (u8, bool, bool, bool, bool) {
+    let result = lhs.wrapping_sub(rhs).wrapping_sub(carry as u8);
+    (
+        result,
+        result == 0,
+        (result as i8).is_negative(),
+        (lhs & 0x0F) < (rhs & 0x0F) + carry as u8,
+        (lhs as u16) < rhs as u16 + carry as u16,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::Gameboy;
+    
+    /// A DMG system about to run `program` from the cartridge entry point, with the boot ROM skipped.
+    fn gameboy(program: &[u8]) -> Gameboy {
+        gameboy_with_cgb_flag(program, 0x00)
+    }
+    
+    /// Like [gameboy()], but for a cartridge with the given CGB flag (0x0143), e.g. 0x80 for CGB mode.
+    fn gameboy_with_cgb_flag(program: &[u8], cgb_flag: u8) -> Gameboy {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..(0x100 + program.len())].copy_from_slice(program);
+        rom[0x143] = cgb_flag;
+        let mut gb = Gameboy::from_rom(rom);
+        gb.skip_bootrom();
+        gb
+    }
+    
+    #[test]
+    fn set_res_register_round_trip() {
+        let mut gb = gameboy(&[
+            0xCB, 0xF8, // SET 7,B
+            0xCB, 0xB8, // RES 7,B
+        ]);
+        gb.cpu.regs.b = 0x12;
+        let flags = gb.cpu.regs.f.bits();
+        
+        gb.step_instruction().unwrap();
+        assert_eq!(gb.cpu.regs.b, 0x92);
+        gb.step_instruction().unwrap();
+        assert_eq!(gb.cpu.regs.b, 0x12);
+        assert_eq!(gb.cpu.regs.f.bits(), flags);
+    }
+    
+    #[test]
+    fn set_res_indirect_round_trip() {
+        let mut gb = gameboy(&[
+            0xCB, 0xDE, // SET 3,(HL)
+            0xCB, 0x9E, // RES 3,(HL)
+        ]);
+        gb.cpu.regs.set_hl(0xC000);
+        gb.bus.write(0xC000, 0x41);
+        gb.cpu.set_access_logging(true);
+        
+        gb.step_instruction().unwrap();
+        assert_eq!(gb.bus.read(0xC000), 0x49);
+        let accesses: Vec<_> = gb.cpu.last_access_log().iter().filter(|(addr, _, _)| *addr == 0xC000).map(|&(_, kind, _)| kind).collect();
+        assert_eq!(accesses, [AccessKind::Read, AccessKind::Write]);
+        
+        gb.step_instruction().unwrap();
+        assert_eq!(gb.bus.read(0xC000), 0x41);
+    }
+    
+    #[test]
+    fn bit_7_a() {
+        // Zero comes from the tested bit, Negative is cleared, HalfCarry is set, and Carry is kept
+        for (a, f, expected) in [(0x80, 0x50, 0x30), (0x00, 0x40, 0xA0), (0x7F, 0x10, 0xB0)] {
+            let mut gb = gameboy(&[0xCB, 0x7F]); // BIT 7,A
+            gb.cpu.regs.a = a;
+            gb.cpu.regs.f.bits = f;
+            
+            gb.step_instruction().unwrap();
+            assert_eq!(gb.cpu.regs.f.bits(), expected, "A={:02X} F={:02X}", a, f);
+            assert_eq!(gb.cpu.regs.a, a);
+        }
+    }
+    
+    #[test]
+    fn adc_sbc() {
+        // Carry goes into both the result and HalfCarry. E is used for the E operand, not D.
+        for (program, a, f, expected_a, expected_f, tcycles) in [
+            (&[0x8B][..], 0x00, 0x10, 0x10, 0x20, 4), // ADC A,E
+            (&[0x8E][..], 0x00, 0x10, 0x00, 0xB0, 8), // ADC A,(HL)
+            (&[0x9B][..], 0x10, 0x10, 0x00, 0xE0, 4), // SBC A,E
+            (&[0x9E][..], 0x10, 0x00, 0x11, 0x70, 8), // SBC A,(HL)
+            (&[0xDE, 0x00][..], 0x00, 0x10, 0xFF, 0x70, 8), // SBC A,0x00
+        ] {
+            let mut gb = gameboy(program);
+            gb.cpu.regs.a = a;
+            gb.cpu.regs.d = 0xAA;
+            gb.cpu.regs.e = 0x0F;
+            gb.cpu.regs.set_hl(0xC000);
+            gb.bus.write(0xC000, 0xFF);
+            gb.cpu.regs.f.bits = f;
+            
+            assert_eq!(gb.step_instruction().unwrap(), tcycles, "opcode {:02X}", program[0]);
+            assert_eq!(gb.cpu.regs.a, expected_a, "opcode {:02X}", program[0]);
+            assert_eq!(gb.cpu.regs.f.bits(), expected_f, "opcode {:02X}", program[0]);
+        }
+    }
+    
+    #[test]
+    fn jp_cond_and_rst() {
+        // JP Z,0x1234 falls through in 3 m-cycles while Zero is clear, and jumps in 4 once it's set
+        let mut gb = gameboy(&[0xCA, 0x34, 0x12, 0xCA, 0x34, 0x12]);
+        gb.cpu.regs.f.bits = 0x00;
+        assert_eq!(gb.step_instruction().unwrap(), 12);
+        assert_eq!(gb.cpu.regs.pc, 0x0103);
+        gb.cpu.regs.f.bits = 0x80;
+        assert_eq!(gb.step_instruction().unwrap(), 16);
+        assert_eq!(gb.cpu.regs.pc, 0x1234);
+        
+        let mut gb = gameboy(&[0xEF]); // RST 0x28
+        gb.cpu.regs.sp = 0xDFFE;
+        assert_eq!(gb.step_instruction().unwrap(), 16);
+        assert_eq!(gb.cpu.regs.pc, 0x0028);
+        assert_eq!(gb.cpu.regs.sp, 0xDFFC);
+        assert_eq!((gb.bus.read(0xDFFC), gb.bus.read(0xDFFD)), (0x01, 0x01));
+    }
+    
+    #[test]
+    fn add_hl_hl() {
+        // Zero is kept, Negative is cleared, and both carries are set. 0x8800 doubled is 0x11000,
+        // truncated to 0x1000.
+        for (hl, f, expected_hl, expected_f) in [(0x8800, 0xC0, 0x1000, 0xB0), (0x8800, 0x40, 0x1000, 0x30), (0x8880, 0x40, 0x1100, 0x30)] {
+            let mut gb = gameboy(&[0x29]); // ADD HL,HL
+            gb.cpu.regs.set_hl(hl);
+            gb.cpu.regs.f.bits = f;
+            
+            assert_eq!(gb.step_instruction().unwrap(), 8);
+            assert_eq!(gb.cpu.regs.hl(), expected_hl, "HL={:04X}", hl);
+            assert_eq!(gb.cpu.regs.f.bits(), expected_f, "HL={:04X} F={:02X}", hl, f);
+        }
+    }
+    
+    #[test]
+    fn ld_d8_timing() {
+        let mut gb = gameboy(&[
+            0x36, 0x42, // LD (HL),0x42
+            0x06, 0x42, // LD B,0x42
+        ]);
+        gb.cpu.regs.set_hl(0xC000);
+        
+        assert_eq!(gb.step_instruction().unwrap(), 12);
+        assert_eq!(gb.bus.read(0xC000), 0x42);
+        assert_eq!(gb.step_instruction().unwrap(), 8);
+        assert_eq!(gb.cpu.regs.b, 0x42);
+    }
+    
+    #[test]
+    fn ei_ret() {
+        // EI only takes effect after the following instruction, so IME stays clear for all of RET
+        let mut gb = gameboy(&[
+            0xFB, // EI
+            0xC9, // RET
+        ]);
+        gb.cpu.regs.sp = 0xDFFE;
+        gb.bus.write(0xDFFE, 0x00);
+        gb.bus.write(0xDFFF, 0x02);
+        
+        gb.step_instruction().unwrap();
+        assert!(!gb.cpu.ime);
+        for _ in 0..4 {
+            gb.mcycle().unwrap();
+            assert!(!gb.cpu.ime, "IME set during RET");
+        }
+        assert_eq!(gb.cpu.regs.pc, 0x0200);
+        
+        gb.step_instruction().unwrap(); // NOP
+        assert!(gb.cpu.ime);
+    }
+    
+    /// Arms a speed switch through KEY1, then runs STOP. Changing the value written (byte 1) to 0x00
+    /// leaves it unarmed.
+    const SPEED_SWITCH: [u8; 8] = [
+        0x3E, 0x01, // LD A,0x01
+        0xE0, 0x4D, // LDH (0x4D),A
+        0x10, 0x00, // STOP
+        0x18, 0xFE, // JR -2
+    ];
+    
+    #[test]
+    fn stop_switches_speed() {
+        let mut gb = gameboy_with_cgb_flag(&SPEED_SWITCH, 0x80);
+        assert_eq!(gb.bus.read(0xFF4D), 0x7E);
+        gb.step_instruction().unwrap();
+        gb.step_instruction().unwrap();
+        assert_eq!(gb.bus.read(0xFF4D), 0x7F);
+        
+        gb.step_instruction().unwrap();
+        assert!(gb.bus.double_speed);
+        assert!(!gb.cpu.halted);
+        assert_eq!(gb.bus.read(0xFF4D), 0xFE);
+        assert_eq!(gb.cpu.regs.pc, 0x0106);
+        
+        // Without a switch armed, STOP waits like HALT, and KEY1 doesn't exist outside CGB mode
+        for cgb_flag in [0x80, 0x00] {
+            let mut program = SPEED_SWITCH;
+            program[1] = 0x00;
+            let mut gb = gameboy_with_cgb_flag(&program, cgb_flag);
+            for _ in 0..3 {
+                gb.step_instruction().unwrap();
+            }
+            assert!(!gb.bus.double_speed);
+            assert!(gb.cpu.halted);
+            assert_eq!(gb.bus.read(0xFF4D), if cgb_flag == 0x80 { 0x7E } else { 0xFF });
+        }
+    }
+    
+    #[test]
+    fn div_rate_follows_speed() {
+        for (arm, div_increments) in [(0x00, 16), (0x01, 32)] {
+            let mut program = SPEED_SWITCH;
+            program[1] = arm;
+            let mut gb = gameboy_with_cgb_flag(&program, 0x80);
+            for _ in 0..3 {
+                gb.step_instruction().unwrap();
+            }
+            
+            // STOP resets DIV, which then counts at the CPU's speed
+            let start = gb.bus.timer.counter;
+            assert!(start < 0x100);
+            for _ in 0..4096 {
+                gb.tcycle().unwrap();
+            }
+            assert_eq!(gb.bus.timer.counter - start, 4096 * (1 + arm as u16), "double speed: {}", arm != 0);
+            assert_eq!(gb.bus.read(0xFF04), div_increments);
+        }
+    }
+    
+    #[test]
+    fn dec_rp() {
+        // DEC BC, DEC DE, DEC HL, DEC SP, with each borrowing into the upper byte
+        for opcode in [0x0B, 0x1B, 0x2B, 0x3B] {
+            let mut gb = gameboy(&[opcode]);
+            gb.cpu.regs.set_bc(0x1200);
+            gb.cpu.regs.set_de(0x3400);
+            gb.cpu.regs.set_hl(0x5600);
+            gb.cpu.regs.sp = 0x7800;
+            
+            gb.step_instruction().unwrap();
+            let expected = match opcode {
+                0x0B => [0x11FF, 0x3400, 0x5600, 0x7800],
+                0x1B => [0x1200, 0x33FF, 0x5600, 0x7800],
+                0x2B => [0x1200, 0x3400, 0x55FF, 0x7800],
+                _ => [0x1200, 0x3400, 0x5600, 0x77FF],
+            };
+            let regs = &gb.cpu.regs;
+            assert_eq!([regs.bc(), regs.de(), regs.hl(), regs.sp], expected, "opcode {:02X}", opcode);
+        }
+    }
+    
+    #[test]
+    fn loads_keep_flags() {
+        let loads = (0x40..=0x7F).filter(|&opcode| opcode != 0x76) // LD r,r' and LD r,(HL), minus HALT
+            .chain([0x06, 0x0E, 0x16, 0x1E, 0x26, 0x2E, 0x36, 0x3E]) // LD r,d8
+            .chain([0x01, 0x11, 0x21, 0x31]) // LD rp,d16
+            .chain([0x02, 0x12, 0x22, 0x32, 0x0A, 0x1A, 0x2A, 0x3A]) // LD (rp),A and LD A,(rp)
+            .chain([0x08, 0xE0, 0xF0, 0xE2, 0xF2, 0xEA, 0xFA, 0xF9]) // everything else, except LD HL,SP+e8
+            .chain([0xC1, 0xD1, 0xE1]); // POP rp, except POP AF which loads F itself
+        
+        for opcode in loads {
+            // Operands address 0xC080, or HRAM for LDH
+            let mut gb = gameboy(&[opcode, 0x80, 0xC0]);
+            gb.cpu.regs.set_bc(0xC080);
+            gb.cpu.regs.set_de(0xC0A0);
+            gb.cpu.regs.set_hl(0xC0C0);
+            gb.cpu.regs.sp = 0xDFF0;
+            gb.cpu.regs.f.bits = 0xB0;
+            
+            gb.step_instruction().unwrap();
+            assert_eq!(gb.cpu.regs.f.bits(), 0xB0, "opcode {:02X}", opcode);
+        }
+    }
+    
+    #[test]
+    fn rlca_vs_rlc_zero() {
+        // RLCA always clears Zero, while the CB rotates set it from the result
+        let mut gb = gameboy(&[
+            0x07, // RLCA
+            0xCB, 0x00, // RLC B
+        ]);
+        gb.cpu.regs.a = 0x00;
+        gb.cpu.regs.b = 0x00;
+        gb.cpu.regs.f.bits = 0xF0;
+        
+        gb.step_instruction().unwrap();
+        assert_eq!(gb.cpu.regs.f.bits(), 0x00);
+        gb.cpu.regs.f.bits = 0x70;
+        gb.step_instruction().unwrap();
+        assert_eq!(gb.cpu.regs.f.bits(), 0x80);
+    }
+    
+    #[test]
+    fn interrupt_dispatch() {
+        let mut gb = gameboy(&[]);
+        gb.cpu.regs.pc = 0x1234;
+        gb.cpu.regs.sp = 0xDFFE;
+        gb.cpu.ime = true;
+        gb.bus.write(0xFFFF, Interrupt::Timer.bits());
+        gb.bus.write(0xFF0F, Interrupt::Timer.bits());
+        
+        assert_eq!(gb.step_instruction().unwrap(), 20);
+        assert_eq!(gb.cpu.regs.sp, 0xDFFC);
+        assert_eq!((gb.bus.read(0xDFFC), gb.bus.read(0xDFFD)), (0x34, 0x12));
+        assert_eq!(gb.cpu.regs.pc, 0x0050);
+        assert!(!gb.cpu.ime);
+    }
+    
+    #[test]
+    fn halt_bug_reads_next_byte_twice() {
+        // With IME clear and an interrupt already pending, HALT doesn't halt, and the byte after it is
+        // read twice. LD A,0x14 becomes LD A,0x3E followed by INC D (0x14).
+        let mut gb = gameboy(&[
+            0x76, // HALT
+            0x3E, 0x14, // LD A,0x14
+        ]);
+        gb.bus.interrupt_enable = Interrupt::VBlank.bits();
+        gb.bus.interrupt_flags = Interrupt::VBlank.bits();
+        assert!(!gb.cpu.ime);
+        gb.cpu.regs.d = 0x00;
+        
+        gb.step_instruction().unwrap();
+        assert!(!gb.cpu.halted);
+        assert_eq!(gb.cpu.regs.pc, 0x0101);
+        gb.step_instruction().unwrap();
+        assert_eq!((gb.cpu.regs.a, gb.cpu.regs.pc), (0x3E, 0x0102));
+        gb.step_instruction().unwrap();
+        assert_eq!((gb.cpu.regs.d, gb.cpu.regs.pc), (0x01, 0x0103));
+    }
+    
+    #[test]
+    fn halt_bug_runs_inc_a_twice() {
+        let mut gb = gameboy(&[
+            0x76, // HALT
+            0x3C, // INC A
+            0x18, 0xFE, // JR -2
+        ]);
+        gb.bus.interrupt_enable = Interrupt::Timer.bits();
+        gb.bus.interrupt_flags = Interrupt::Timer.bits();
+        gb.cpu.regs.a = 0x10;
+        
+        for _ in 0..3 {
+            gb.step_instruction().unwrap();
+        }
+        assert_eq!((gb.cpu.regs.a, gb.cpu.regs.pc), (0x12, 0x0102));
+        
+        // The bug only applies once
+        gb.step_instruction().unwrap();
+        assert_eq!((gb.cpu.regs.a, gb.cpu.regs.pc), (0x12, 0x0102));
+    }
+    
+    #[test]
+    fn daa_after_add_and_sub() {
+        // DAA corrects the binary result of the previous ADD or SUB to BCD, using its N, H, and C flags
+        for (op, a, operand, expected_a, expected_f) in [
+            (0xC6, 0x45, 0x38, 0x83, 0x00), // ADD A,d8: 45 + 38 = 83
+            (0xC6, 0x99, 0x01, 0x00, 0x90), // 99 + 01 = 100
+            (0xC6, 0x90, 0x90, 0x80, 0x10), // 90 + 90 = 180
+            (0xD6, 0x83, 0x38, 0x45, 0x40), // SUB d8: 83 - 38 = 45
+            (0xD6, 0x00, 0x01, 0x99, 0x50), // 00 - 01 = -1, borrowing
+            (0xD6, 0x45, 0x45, 0x00, 0xC0), // 45 - 45 = 0
+        ] {
+            let mut gb = gameboy(&[op, operand, 0x27]);
+            gb.cpu.regs.a = a;
+            
+            gb.step_instruction().unwrap();
+            assert_eq!(gb.step_instruction().unwrap(), 4);
+            assert_eq!((gb.cpu.regs.a, gb.cpu.regs.f.bits), (expected_a, expected_f), "{:02X} {:02X},{:02X}", op, a, operand);
+        }
+    }
+    
+    #[test]
+    fn swap_hl() {
+        for (val, expected, expected_f) in [(0xA5, 0x5A, 0x00), (0x00, 0x00, 0x80)] {
+            let mut gb = gameboy(&[0xCB, 0x36]); // SWAP (HL)
+            gb.cpu.regs.set_hl(0xC000);
+            gb.cpu.regs.f.bits = 0x70;
+            gb.bus.write(0xC000, val);
+            
+            assert_eq!(gb.step_instruction().unwrap(), 16);
+            assert_eq!(gb.bus.read(0xC000), expected, "SWAP {:02X}", val);
+            assert_eq!(gb.cpu.regs.f.bits, expected_f);
+        }
+    }
+    
+    #[test]
+    fn sp_plus_negative_i8() {
+        // H and C come from adding the immediate to SP's low byte as unsigned values, even though the
+        // immediate is negative and the 16-bit result borrows
+        for (imm, result, expected_f) in [(0xFF, 0xFFFE, 0x30), (0x80, 0xFF7F, 0x10), (0xF0, 0xFFEF, 0x10)] {
+            let mut gb = gameboy(&[0xE8, imm]); // ADD SP,i8
+            gb.cpu.regs.sp = 0xFFFF;
+            gb.cpu.regs.f.bits = 0xC0;
+            assert_eq!(gb.step_instruction().unwrap(), 16);
+            assert_eq!((gb.cpu.regs.sp, gb.cpu.regs.f.bits), (result, expected_f), "ADD SP,{:02X}", imm);
+            
+            let mut gb = gameboy(&[0xF8, imm]); // LD HL,SP+i8
+            gb.cpu.regs.sp = 0xFFFF;
+            gb.cpu.regs.f.bits = 0xC0;
+            assert_eq!(gb.step_instruction().unwrap(), 12);
+            assert_eq!((gb.cpu.regs.hl(), gb.cpu.regs.f.bits), (result, expected_f), "LD HL,SP+{:02X}", imm);
+            assert_eq!(gb.cpu.regs.sp, 0xFFFF);
+        }
+    }
+    
+    #[test]
+    fn pop_af_masks_f() {
+        let mut gb = gameboy(&[
+            0x01, 0xFF, 0xFF, // LD BC,0xFFFF
+            0xC5, // PUSH BC
+            0xF1, // POP AF
+            0x37, // SCF
+            0x3F, // CCF
+        ]);
+        gb.cpu.regs.sp = 0xE000;
+        
+        gb.step_instruction().unwrap();
+        gb.step_instruction().unwrap();
+        assert_eq!(gb.step_instruction().unwrap(), 12);
+        assert_eq!((gb.cpu.regs.a, gb.cpu.regs.f.bits, gb.cpu.regs.sp), (0xFF, 0xF0, 0xE000));
+        
+        // The flags still work normally afterwards, without the low nibble coming back
+        gb.step_instruction().unwrap();
+        assert_eq!(gb.cpu.regs.f.bits, 0x90);
+        gb.step_instruction().unwrap();
+        assert_eq!(gb.cpu.regs.f.bits, 0x80);
+    }
+    
+    #[test]
+    fn jr_wraps_below_0x0000() {
+        // JR NZ at 0x0000, so the displacement is added to 0x0002. Taking it is 3 m-cycles, and not
+        // taking it is 2, whatever page the target is on.
+        for (f, expected_pc, expected_tcycles) in [(0x00, 0xFFFE, 12), (0x80, 0x0002, 8)] {
+            let mut gb = gameboy(&[]);
+            gb.bus.cart.rom[0x0000..0x0002].copy_from_slice(&[0x20, 0xFC]); // JR NZ,-4
+            gb.cpu.regs.pc = 0x0000;
+            gb.cpu.regs.f.bits = f;
+            
+            assert_eq!(gb.step_instruction().unwrap(), expected_tcycles, "F={:02X}", f);
+            assert_eq!(gb.cpu.regs.pc, expected_pc);
+        }
+    }
+    
+    #[test]
+    fn read_lands_on_access_tcycle() {
+        // LDH A,(0x04) reads DIV on the last t-cycle of its third m-cycle, 11 t-cycles in. The timer
+        // runs after the CPU within each t-cycle, so by then it's been clocked 11 times.
+        assert_eq!(ACCESS_TCYCLE, 3);
+        for (counter, expected) in [(0x00F4, 0x00), (0x00F5, 0x01)] {
+            let mut gb = gameboy(&[0xF0, 0x04]); // LDH A,(0x04)
+            assert_eq!(gb.cpu.tcount, 0);
+            gb.bus.timer.counter = counter;
+            
+            gb.step_instruction().unwrap();
+            assert_eq!(gb.cpu.regs.a, expected, "counter {:04X}", counter);
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use crate::arch::Bus;
+
+const R: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const RP: [&str; 4] = ["BC", "DE", "HL", "SP"];
+const RP2: [&str; 4] = ["BC", "DE", "HL", "AF"];
+const CC: [&str; 4] = ["NZ", "Z", "NC", "C"];
+const ALU: [&str; 8] = ["ADD A,", "ADC A,", "SUB ", "SBC A,", "AND ", "XOR ", "OR ", "CP "];
+const ROT: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+
+/// Decodes the instruction at `addr` into its mnemonic (e.g. `"LD A,(HL+)"`) and its length in bytes.
+/// 
+/// Memory is only peeked, so disassembling never disturbs the state of the system. Bytes which
+/// aren't valid opcodes are returned as a single-byte `DB` directive.
+pub fn disassemble(bus: &mut Bus, addr: u16) -> (String, u8) {
+    let opcode = bus.peek(addr);
+    let x = (opcode & 0b11000000) >> 6;
+    let y = (opcode & 0b00111000) >> 3;
+    let z = opcode & 0b00000111;
+    let p = (y >> 1) as usize;
+    let q = y & 0b1;
+    let y = y as usize;
+    let z = z as usize;
+    
+    let n = bus.peek(addr.wrapping_add(1));
+    let nn = ((bus.peek(addr.wrapping_add(2)) as u16) << 8) | (n as u16);
+    let rel = addr.wrapping_add(2).wrapping_add(n as i8 as u16); // JR target
+    
+    let (text, len) = match x {
+        0 => match z {
+            0 => match y {
+                0 => ("NOP".to_owned(), 1),
+                1 => (format!("LD (${:04X}),SP", nn), 3),
+                2 => ("STOP".to_owned(), 2),
+                3 => (format!("JR ${:04X}", rel), 2),
+                _ => (format!("JR {},${:04X}", CC[y - 4], rel), 2),
+            },
+            1 => match q {
+                0 => (format!("LD {},${:04X}", RP[p], nn), 3),
+                _ => (format!("ADD HL,{}", RP[p]), 1),
+            },
+            2 => {
+                let indirect = ["(BC)", "(DE)", "(HL+)", "(HL-)"][p];
+                match q {
+                    0 => (format!("LD {},A", indirect), 1),
+                    _ => (format!("LD A,{}", indirect), 1),
+                }
+            },
+            3 => match q {
+                0 => (format!("INC {}", RP[p]), 1),
+                _ => (format!("DEC {}", RP[p]), 1),
+            },
+            4 => (format!("INC {}", R[y]), 1),
+            5 => (format!("DEC {}", R[y]), 1),
+            6 => (format!("LD {},${:02X}", R[y], n), 2),
+            _ => (["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"][y].to_owned(), 1),
+        },
+        1 => if y == 6 && z == 6 {
+                ("HALT".to_owned(), 1)
+            } else {
+                (format!("LD {},{}", R[y], R[z]), 1)
+        },
+        2 => (format!("{}{}", ALU[y], R[z]), 1),
+        _ => match z {
+            0 => match y {
+                0..=3 => (format!("RET {}", CC[y]), 1),
+                4 => (format!("LDH (${:04X}),A", 0xFF00 | (n as u16)), 2),
+                5 => (format!("ADD SP,{}", n as i8), 2),
+                6 => (format!("LDH A,(${:04X})", 0xFF00 | (n as u16)), 2),
+                _ => (format!("LD HL,SP{:+}", n as i8), 2),
+            },
+            1 => match q {
+                0 => (format!("POP {}", RP2[p]), 1),
+                _ => (["RET", "RETI", "JP HL", "LD SP,HL"][p].to_owned(), 1),
+            },
+            2 => match y {
+                0..=3 => (format!("JP {},${:04X}", CC[y], nn), 3),
+                4 => ("LD ($FF00+C),A".to_owned(), 1),
+                5 => (format!("LD (${:04X}),A", nn), 3),
+                6 => ("LD A,($FF00+C)".to_owned(), 1),
+                _ => (format!("LD A,(${:04X})", nn), 3),
+            },
+            3 => match y {
+                0 => (format!("JP ${:04X}", nn), 3),
+                1 => {
+                    let y = ((n & 0b00111000) >> 3) as usize;
+                    let z = (n & 0b00000111) as usize;
+                    match n >> 6 {
+                        0 => (format!("{} {}", ROT[y], R[z]), 2),
+                        1 => (format!("BIT {},{}", y, R[z]), 2),
+                        2 => (format!("RES {},{}", y, R[z]), 2),
+                        _ => (format!("SET {},{}", y, R[z]), 2),
+                    }
+                },
+                6 => ("DI".to_owned(), 1),
+                7 => ("EI".to_owned(), 1),
+                _ => (format!("DB ${:02X}", opcode), 1),
+            },
+            4 => match y {
+                0..=3 => (format!("CALL {},${:04X}", CC[y], nn), 3),
+                _ => (format!("DB ${:02X}", opcode), 1),
+            },
+            5 => match (q, p) {
+                (0, _) => (format!("PUSH {}", RP2[p]), 1),
+                (_, 0) => (format!("CALL ${:04X}", nn), 3),
+                _ => (format!("DB ${:02X}", opcode), 1),
+            },
+            6 => (format!("{}${:02X}", ALU[y], n), 2),
+            _ => (format!("RST ${:02X}", y * 8), 1),
+        },
+    };
+    
+    (text, len)
+}
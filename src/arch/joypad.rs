@@ -0,0 +1,158 @@
+use crate::arch::BusAccessable;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+/// Number of frames between the frontend pressing a button and software first reading it from P1.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InputLatency {
+    /// Frame each button was pressed on, until software reads it.
+    pending: [Option<u64>; 8],
+    /// Latency of the most recently read press.
+    pub last: Option<u64>,
+    pub min: Option<u64>,
+    pub max: Option<u64>,
+    /// Number of presses measured.
+    pub samples: u64,
+    total: u64,
+}
+impl InputLatency {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Mean latency over every measured press.
+    pub fn average(&self) -> Option<f64> {
+        if self.samples == 0 {
+            return None;
+        }
+        
+        Some(self.total as f64 / self.samples as f64)
+    }
+    
+    fn record(&mut self, latency: u64) {
+        self.last = Some(latency);
+        self.min = Some(self.min.map_or(latency, |min| min.min(latency)));
+        self.max = Some(self.max.map_or(latency, |max| max.max(latency)));
+        self.samples += 1;
+        self.total += latency;
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Joypad {
+    /// Select bits written to P1 (0xFF00), bit 4 for directions and bit 5 for actions. A line group is
+    /// selected when its bit is 0.
+    select: u8,
+    /// One bit per button in [Button] order, set while pressed. Low nibble is directions, high nibble
+    /// is actions.
+    pressed: u8,
+    /// Only measured while enabled, see [Joypad::set_latency_tracking()]. Not part of save states.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    latency: Option<InputLatency>,
+}
+impl Joypad {
+    pub fn new() -> Self { Self {
+        select: 0x00,
+        pressed: 0,
+        latency: None,
+    }}
+    
+    /// Starts or stops measuring input latency. Starting again discards previous measurements.
+    pub fn set_latency_tracking(&mut self, enabled: bool) {
+        self.latency = if enabled { Some(InputLatency::new()) } else { None };
+    }
+    
+    /// Input latency measured so far, or None if it isn't being tracked.
+    pub fn latency(&self) -> Option<&InputLatency> {
+        self.latency.as_ref()
+    }
+    
+    /// Current state of the four input lines (low nibble of P1), with pressed buttons reading as 0.
+    pub fn lines(&self) -> u8 {
+        let mut lines = 0x0F;
+        if self.select & 0b00010000 == 0 {
+            lines &= !self.pressed & 0x0F;
+        }
+        if self.select & 0b00100000 == 0 {
+            lines &= !(self.pressed >> 4) & 0x0F;
+        }
+        
+        lines
+    }
+    
+    /// Updates a button's state, returning true if any input line went from high to low, which
+    /// requests the joypad interrupt.
+    /// 
+    /// `frame` is the current frame number, used to measure input latency if it's being tracked.
+    pub fn set_button(&mut self, button: Button, pressed: bool, frame: u64) -> bool {
+        let lines = self.lines();
+        
+        let bit = 1 << (button as u8);
+        if let Some(latency) = self.latency.as_mut() {
+            let pending = &mut latency.pending[button as usize];
+            if !pressed {
+                *pending = None;
+            } else if self.pressed & bit == 0 {
+                *pending = Some(frame);
+            }
+        }
+        if pressed {
+            self.pressed |= bit;
+        } else {
+            self.pressed &= !bit;
+        }
+        
+        lines & !self.lines() != 0
+    }
+    
+    /// Called when software reads P1 on `frame`, to measure the latency of any pending presses it
+    /// can see with the current select bits.
+    pub fn track_read(&mut self, frame: u64) {
+        let visible = match self.select & 0b00110000 {
+            0b00000000 => 0xFF,
+            0b00010000 => 0xF0,
+            0b00100000 => 0x0F,
+            _ => 0x00,
+        };
+        let latency = match self.latency.as_mut() {
+            Some(latency) => latency,
+            None => return,
+        };
+        
+        for i in 0..8 {
+            if visible & (1 << i) == 0 {
+                continue;
+            }
+            if let Some(pressed_on) = latency.pending[i].take() {
+                latency.record(frame.saturating_sub(pressed_on));
+            }
+        }
+    }
+}
+
+impl BusAccessable for Joypad {
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0xFF00 => self.select = data & 0b00110000,
+            _ => panic!("unreachable")
+        }
+    }
+    
+    fn read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0xFF00 => 0b11000000 | self.select | self.lines(),
+            _ => panic!("unreachable")
+        }
+    }
+}
@@ -0,0 +1,833 @@
+use std::fmt::Debug;
+use std::time::{Duration, Instant};
+use log::warn;
+use crate::arch::cartridge::CartridgeHeader;
+
+/// Memory bank controller. Handles every cartridge access (0x0000-0x7FFF and 0xA000-0xBFFF), and
+/// owns the cartridge's external RAM.
+pub trait Mbc: Debug {
+    fn read(&mut self, rom: &[u8], addr: u16) -> u8;
+    fn write(&mut self, addr: u16, data: u8);
+    
+    /// External RAM, including any RAM built into the MBC itself.
+    fn ram(&self) -> &[u8];
+    fn ram_mut(&mut self) -> &mut [u8];
+    
+    fn rtc(&mut self) -> Option<&mut Rtc> {
+        None
+    }
+    
+    fn accelerometer(&mut self) -> Option<&mut Accelerometer> {
+        None
+    }
+    
+    fn box_clone(&self) -> Box<dyn Mbc>;
+    
+    /// Copy of this controller for serializing, tagged with which controller it is.
+    #[cfg(feature = "serde")]
+    fn state(&self) -> MbcState;
+}
+impl Clone for Box<dyn Mbc> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+/// Serialized form of a boxed [Mbc].
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub enum MbcState {
+    NoMbc(NoMbc),
+    Mbc1(Mbc1),
+    Mbc2(Mbc2),
+    Mbc3(Mbc3),
+    Mbc5(Mbc5),
+    Mbc7(Mbc7),
+}
+#[cfg(feature = "serde")]
+impl serde::Serialize for Box<dyn Mbc> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.state().serialize(serializer)
+    }
+}
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Box<dyn Mbc> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match MbcState::deserialize(deserializer)? {
+            MbcState::NoMbc(mbc) => Box::new(mbc),
+            MbcState::Mbc1(mbc) => Box::new(mbc),
+            MbcState::Mbc2(mbc) => Box::new(mbc),
+            MbcState::Mbc3(mbc) => Box::new(mbc),
+            MbcState::Mbc5(mbc) => Box::new(mbc),
+            MbcState::Mbc7(mbc) => Box::new(mbc),
+        })
+    }
+}
+
+/// Selects and creates the controller for a cartridge, based on its header.
+pub fn from_header(header: &CartridgeHeader) -> Box<dyn Mbc> {
+    let ram = header.ram_bytes();
+    match header.cartridge_type {
+        0x00 | 0x08 | 0x09 => Box::new(NoMbc::new(ram)),
+        0x01..=0x03 => Box::new(Mbc1::new(ram)),
+        0x05 | 0x06 => Box::new(Mbc2::new()),
+        0x0F..=0x13 => Box::new(Mbc3::new(ram)),
+        0x19..=0x1E => Box::new(Mbc5::new(ram)),
+        0x22 => Box::new(Mbc7::new()),
+        _ => {
+            warn!("Unsupported cartridge type {:02X}, treating as ROM only", header.cartridge_type);
+            Box::new(NoMbc::new(ram))
+        }
+    }
+}
+
+/// Reads from a 16 KiB ROM bank. Bank numbers beyond the size of the ROM wrap around.
+fn rom_byte(rom: &[u8], bank: usize, addr: u16) -> u8 {
+    let banks = (rom.len() / 0x4000).max(1);
+    *rom.get(((bank % banks) * 0x4000) + (addr & 0x3FFF) as usize).unwrap_or(&0xFF)
+}
+
+/// Index into external RAM for an 8 KiB RAM bank, or None if there's no RAM.
+fn ram_index(ram: &[u8], bank: usize, addr: u16) -> Option<usize> {
+    if ram.is_empty() {
+        return None;
+    }
+    
+    Some(((bank * 0x2000) + (addr & 0x1FFF) as usize) % ram.len())
+}
+
+/// Reads from a RAM bank, or returns 0xFF if RAM is disabled or missing.
+fn read_ram(ram: &[u8], bank: Option<usize>, addr: u16) -> u8 {
+    match bank.and_then(|bank| ram_index(ram, bank, addr)) {
+        Some(i) => ram[i],
+        None => 0xFF,
+    }
+}
+
+/// Writes to a RAM bank, unless RAM is disabled or missing.
+fn write_ram(ram: &mut [u8], bank: Option<usize>, addr: u16, data: u8) {
+    if let Some(i) = bank.and_then(|bank| ram_index(ram, bank, addr)) {
+        ram[i] = data;
+    }
+}
+
+/// 32 KiB of ROM mapped directly, with no banking. Some cartridges also have up to 8 KiB of RAM.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NoMbc {
+    ram: Vec<u8>,
+}
+impl NoMbc {
+    pub fn new(ram_size: usize) -> Self { Self {
+        ram: vec![0u8; ram_size],
+    }}
+}
+impl Mbc for NoMbc {
+    fn read(&mut self, rom: &[u8], addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x7FFF => *rom.get(addr as usize).unwrap_or(&0xFF),
+            0xA000..=0xBFFF => read_ram(&self.ram, Some(0), addr),
+            _ => panic!("unreachable")
+        }
+    }
+    
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x0000..=0x7FFF => (),
+            0xA000..=0xBFFF => write_ram(&mut self.ram, Some(0), addr, data),
+            _ => panic!("unreachable")
+        }
+    }
+    
+    fn ram(&self) -> &[u8] { &self.ram }
+    fn ram_mut(&mut self) -> &mut [u8] { &mut self.ram }
+    fn box_clone(&self) -> Box<dyn Mbc> { Box::new(self.clone()) }
+    #[cfg(feature = "serde")]
+    fn state(&self) -> MbcState { MbcState::NoMbc(self.clone()) }
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Mbc1 {
+    ram: Vec<u8>,
+    /// RAM enable (0x0000-0x1FFF), set by writing 0x0A to the low nibble.
+    pub ram_enabled: bool,
+    /// Lower 5 bits of the ROM bank number (0x2000-0x3FFF). Never 0.
+    pub bank1: u8,
+    /// RAM bank, or bits 5-6 of the ROM bank number (0x4000-0x5FFF).
+    pub bank2: u8,
+    /// Banking mode (0x6000-0x7FFF). When set, `bank2` also applies to 0x0000-0x3FFF and RAM.
+    pub mode: bool,
+}
+impl Mbc1 {
+    pub fn new(ram_size: usize) -> Self { Self {
+        ram: vec![0u8; ram_size],
+        ram_enabled: false,
+        bank1: 1,
+        bank2: 0,
+        mode: false,
+    }}
+    
+    fn ram_bank(&self) -> Option<usize> {
+        match (self.ram_enabled, self.mode) {
+            (false, _) => None,
+            (true, false) => Some(0),
+            (true, true) => Some(self.bank2 as usize),
+        }
+    }
+}
+impl Mbc for Mbc1 {
+    fn read(&mut self, rom: &[u8], addr: u16) -> u8 {
+        let bank2 = (self.bank2 as usize) << 5;
+        match addr {
+            0x0000..=0x3FFF => rom_byte(rom, if self.mode { bank2 } else { 0 }, addr),
+            // Bank 0 can't be selected here, so 0x20/0x40/0x60 map to 0x21/0x41/0x61
+            0x4000..=0x7FFF => rom_byte(rom, bank2 | self.bank1 as usize, addr),
+            0xA000..=0xBFFF => read_ram(&self.ram, self.ram_bank(), addr),
+            _ => panic!("unreachable")
+        }
+    }
+    
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = data & 0x0F == 0x0A,
+            0x2000..=0x3FFF => self.bank1 = if data & 0x1F == 0 { 1 } else { data & 0x1F },
+            0x4000..=0x5FFF => self.bank2 = data & 0b11,
+            0x6000..=0x7FFF => self.mode = data & 0b1 != 0,
+            0xA000..=0xBFFF => {
+                let bank = self.ram_bank();
+                write_ram(&mut self.ram, bank, addr, data);
+            },
+            _ => panic!("unreachable")
+        }
+    }
+    
+    fn ram(&self) -> &[u8] { &self.ram }
+    fn ram_mut(&mut self) -> &mut [u8] { &mut self.ram }
+    fn box_clone(&self) -> Box<dyn Mbc> { Box::new(self.clone()) }
+    #[cfg(feature = "serde")]
+    fn state(&self) -> MbcState { MbcState::Mbc1(self.clone()) }
+}
+
+/// Up to 256 KiB of ROM, with 512 half-bytes of RAM built into the MBC.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Mbc2 {
+    ram: Vec<u8>,
+    /// RAM enable, written to 0x0000-0x3FFF with address bit 8 clear.
+    pub ram_enabled: bool,
+    /// 4-bit ROM bank number, written to 0x0000-0x3FFF with address bit 8 set. Never 0.
+    pub rom_bank: u8,
+}
+impl Mbc2 {
+    pub fn new() -> Self { Self {
+        ram: vec![0u8; 0x200],
+        ram_enabled: false,
+        rom_bank: 1,
+    }}
+}
+impl Default for Mbc2 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Mbc for Mbc2 {
+    fn read(&mut self, rom: &[u8], addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => rom_byte(rom, 0, addr),
+            0x4000..=0x7FFF => rom_byte(rom, self.rom_bank as usize, addr),
+            // Only the lower 9 address bits are used, so RAM repeats throughout 0xA000-0xBFFF
+            0xA000..=0xBFFF if self.ram_enabled => self.ram[(addr & 0x1FF) as usize] | 0xF0,
+            0xA000..=0xBFFF => 0xFF,
+            _ => panic!("unreachable")
+        }
+    }
+    
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x0000..=0x3FFF if addr & 0x100 == 0 => self.ram_enabled = data & 0x0F == 0x0A,
+            0x0000..=0x3FFF => self.rom_bank = if data & 0x0F == 0 { 1 } else { data & 0x0F },
+            0x4000..=0x7FFF => (),
+            0xA000..=0xBFFF if self.ram_enabled => self.ram[(addr & 0x1FF) as usize] = data & 0x0F,
+            0xA000..=0xBFFF => (),
+            _ => panic!("unreachable")
+        }
+    }
+    
+    fn ram(&self) -> &[u8] { &self.ram }
+    fn ram_mut(&mut self) -> &mut [u8] { &mut self.ram }
+    fn box_clone(&self) -> Box<dyn Mbc> { Box::new(self.clone()) }
+    #[cfg(feature = "serde")]
+    fn state(&self) -> MbcState { MbcState::Mbc2(self.clone()) }
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Mbc3 {
+    ram: Vec<u8>,
+    /// RAM and RTC enable (0x0000-0x1FFF), set by writing 0x0A to the low nibble.
+    pub ram_enabled: bool,
+    /// 7-bit ROM bank number (0x2000-0x3FFF). Never 0.
+    pub rom_bank: u8,
+    /// RAM bank 0-3, or RTC register 0x08-0x0C (0x4000-0x5FFF).
+    pub ram_bank: u8,
+    /// Last value written to 0x6000-0x7FFF. Writing 0x00 then 0x01 latches the clock.
+    latch: u8,
+    pub rtc: Rtc,
+}
+impl Mbc3 {
+    pub fn new(ram_size: usize) -> Self { Self {
+        ram: vec![0u8; ram_size],
+        ram_enabled: false,
+        rom_bank: 1,
+        ram_bank: 0,
+        latch: 0xFF,
+        rtc: Rtc::new(),
+    }}
+    
+    fn ram_bank(&self) -> Option<usize> {
+        if self.ram_enabled && self.ram_bank <= 0x03 { Some(self.ram_bank as usize) } else { None }
+    }
+    
+    /// True when an RTC register is mapped to 0xA000-0xBFFF.
+    fn rtc_mapped(&self) -> bool {
+        self.ram_enabled && (0x08..=0x0C).contains(&self.ram_bank)
+    }
+}
+impl Mbc for Mbc3 {
+    fn read(&mut self, rom: &[u8], addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => rom_byte(rom, 0, addr),
+            0x4000..=0x7FFF => rom_byte(rom, self.rom_bank as usize, addr),
+            0xA000..=0xBFFF if self.rtc_mapped() => self.rtc.read(self.ram_bank),
+            0xA000..=0xBFFF => read_ram(&self.ram, self.ram_bank(), addr),
+            _ => panic!("unreachable")
+        }
+    }
+    
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = data & 0x0F == 0x0A,
+            0x2000..=0x3FFF => self.rom_bank = if data & 0x7F == 0 { 1 } else { data & 0x7F },
+            0x4000..=0x5FFF => self.ram_bank = data & 0x0F,
+            0x6000..=0x7FFF => {
+                if self.latch == 0x00 && data == 0x01 {
+                    self.rtc.latch();
+                }
+                self.latch = data;
+            },
+            0xA000..=0xBFFF if self.rtc_mapped() => self.rtc.write(self.ram_bank, data),
+            0xA000..=0xBFFF => {
+                let bank = self.ram_bank();
+                write_ram(&mut self.ram, bank, addr, data);
+            },
+            _ => panic!("unreachable")
+        }
+    }
+    
+    fn ram(&self) -> &[u8] { &self.ram }
+    fn ram_mut(&mut self) -> &mut [u8] { &mut self.ram }
+    fn rtc(&mut self) -> Option<&mut Rtc> { Some(&mut self.rtc) }
+    fn box_clone(&self) -> Box<dyn Mbc> { Box::new(self.clone()) }
+    #[cfg(feature = "serde")]
+    fn state(&self) -> MbcState { MbcState::Mbc3(self.clone()) }
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Mbc5 {
+    ram: Vec<u8>,
+    /// RAM enable (0x0000-0x1FFF), set by writing 0x0A to the low nibble.
+    pub ram_enabled: bool,
+    /// 9-bit ROM bank number, low 8 bits at 0x2000-0x2FFF and bit 8 at 0x3000-0x3FFF. Bank 0 can be
+    /// selected.
+    pub rom_bank: u16,
+    /// 4-bit RAM bank (0x4000-0x5FFF).
+    pub ram_bank: u8,
+}
+impl Mbc5 {
+    pub fn new(ram_size: usize) -> Self { Self {
+        ram: vec![0u8; ram_size],
+        ram_enabled: false,
+        rom_bank: 1,
+        ram_bank: 0,
+    }}
+    
+    fn ram_bank(&self) -> Option<usize> {
+        if self.ram_enabled { Some(self.ram_bank as usize) } else { None }
+    }
+}
+impl Mbc for Mbc5 {
+    fn read(&mut self, rom: &[u8], addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => rom_byte(rom, 0, addr),
+            0x4000..=0x7FFF => rom_byte(rom, self.rom_bank as usize, addr),
+            0xA000..=0xBFFF => read_ram(&self.ram, self.ram_bank(), addr),
+            _ => panic!("unreachable")
+        }
+    }
+    
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = data & 0x0F == 0x0A,
+            0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | data as u16,
+            0x3000..=0x3FFF => self.rom_bank = (self.rom_bank & 0xFF) | (((data & 0b1) as u16) << 8),
+            0x4000..=0x5FFF => self.ram_bank = data & 0x0F,
+            0x6000..=0x7FFF => (),
+            0xA000..=0xBFFF => {
+                let bank = self.ram_bank();
+                write_ram(&mut self.ram, bank, addr, data);
+            },
+            _ => panic!("unreachable")
+        }
+    }
+    
+    fn ram(&self) -> &[u8] { &self.ram }
+    fn ram_mut(&mut self) -> &mut [u8] { &mut self.ram }
+    fn box_clone(&self) -> Box<dyn Mbc> { Box::new(self.clone()) }
+    #[cfg(feature = "serde")]
+    fn state(&self) -> MbcState { MbcState::Mbc5(self.clone()) }
+}
+
+/// Up to 2 MiB of ROM, with an accelerometer and a 256 byte serial EEPROM instead of RAM.
+/// 
+/// The EEPROM isn't emulated. Its port always reports that it's ready, and nothing written to it is
+/// kept, so games can run but can't save.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Mbc7 {
+    /// First RAM enable (0x0000-0x1FFF), set by writing 0x0A.
+    pub ram_enabled: bool,
+    /// Second RAM enable (0x4000-0x5FFF), set by writing 0x40. Both are needed to access the
+    /// registers at 0xA000-0xAFFF.
+    pub ram_enabled2: bool,
+    /// ROM bank number (0x2000-0x3FFF).
+    pub rom_bank: u8,
+    pub accelerometer: Accelerometer,
+}
+impl Mbc7 {
+    pub fn new() -> Self { Self {
+        ram_enabled: false,
+        ram_enabled2: false,
+        rom_bank: 1,
+        accelerometer: Accelerometer::new(),
+    }}
+}
+impl Default for Mbc7 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Mbc for Mbc7 {
+    fn read(&mut self, rom: &[u8], addr: u16) -> u8 {
+        let latched = self.accelerometer.latched;
+        match addr {
+            0x0000..=0x3FFF => rom_byte(rom, 0, addr),
+            0x4000..=0x7FFF => rom_byte(rom, self.rom_bank as usize, addr),
+            // Registers are selected by address bits 4-7, and repeat throughout 0xA000-0xAFFF
+            0xA000..=0xAFFF if self.ram_enabled && self.ram_enabled2 => match (addr >> 4) & 0x0F {
+                0x2 => latched.0 as u8,
+                0x3 => (latched.0 >> 8) as u8,
+                0x4 => latched.1 as u8,
+                0x5 => (latched.1 >> 8) as u8,
+                0x6 => 0x00,
+                0x8 => 0b1, // EEPROM data out, high while it's ready
+                _ => 0xFF,
+            },
+            0xA000..=0xBFFF => 0xFF,
+            _ => panic!("unreachable")
+        }
+    }
+    
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = data == 0x0A,
+            0x2000..=0x3FFF => self.rom_bank = data,
+            0x4000..=0x5FFF => self.ram_enabled2 = data == 0x40,
+            0x6000..=0x7FFF => (),
+            0xA000..=0xAFFF if self.ram_enabled && self.ram_enabled2 => match ((addr >> 4) & 0x0F, data) {
+                (0x0, 0x55) => self.accelerometer.erase(),
+                (0x1, 0xAA) => self.accelerometer.latch(),
+                _ => (),
+            },
+            0xA000..=0xBFFF => (),
+            _ => panic!("unreachable")
+        }
+    }
+    
+    fn ram(&self) -> &[u8] { &[] }
+    fn ram_mut(&mut self) -> &mut [u8] { &mut [] }
+    fn accelerometer(&mut self) -> Option<&mut Accelerometer> { Some(&mut self.accelerometer) }
+    fn box_clone(&self) -> Box<dyn Mbc> { Box::new(self.clone()) }
+    #[cfg(feature = "serde")]
+    fn state(&self) -> MbcState { MbcState::Mbc7(self.clone()) }
+}
+
+/// MBC7 two axis accelerometer, set by the frontend through [Cartridge::set_sensor()](crate::arch::cartridge::Cartridge::set_sensor()).
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Accelerometer {
+    /// Current reading on each axis. Level is [Accelerometer::CENTER], and 1g of tilt is roughly
+    /// 0x70 away from it.
+    pub x: u16,
+    pub y: u16,
+    /// Reading taken by the last latch, which is what software reads.
+    latched: (u16, u16),
+    /// Set by erasing the latched reading, which must be done before it can be latched again.
+    erased: bool,
+}
+impl Accelerometer {
+    pub const CENTER: u16 = 0x81D0;
+    
+    fn new() -> Self { Self {
+        x: Self::CENTER,
+        y: Self::CENTER,
+        latched: (0x8000, 0x8000),
+        erased: false,
+    }}
+    
+    /// Resets the latched reading, written as 0x55 to register 0.
+    fn erase(&mut self) {
+        self.latched = (0x8000, 0x8000);
+        self.erased = true;
+    }
+    
+    /// Copies the current reading into the latched one, written as 0xAA to register 1. Only works
+    /// once after each erase.
+    fn latch(&mut self) {
+        if self.erased {
+            self.latched = (self.x, self.y);
+            self.erased = false;
+        }
+    }
+}
+
+/// MBC3 real time clock, driven by the host's clock.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Rtc {
+    pub seconds: u8,
+    pub minutes: u8,
+    pub hours: u8,
+    /// 9-bit day counter.
+    pub days: u16,
+    pub halted: bool,
+    /// Set when the day counter overflows, and stays set until cleared by software.
+    pub day_carry: bool,
+    /// Copy of the registers taken by the last latch, which is what software reads.
+    latched: [u8; 5],
+    /// Host time the registers were last brought up to date. Not saved, so a restored clock carries
+    /// on from the saved time.
+    #[cfg_attr(feature = "serde", serde(skip, default = "Instant::now"))]
+    last_update: Instant,
+}
+impl Rtc {
+    fn new() -> Self { Self {
+        seconds: 0,
+        minutes: 0,
+        hours: 0,
+        days: 0,
+        halted: false,
+        day_carry: false,
+        latched: [0u8; 5],
+        last_update: Instant::now(),
+    }}
+    
+    /// Advances the registers by however many whole seconds have passed on the host.
+    pub fn update(&mut self) {
+        let now = Instant::now();
+        if self.halted {
+            self.last_update = now;
+            return;
+        }
+        
+        let secs = now.duration_since(self.last_update).as_secs();
+        self.last_update += Duration::from_secs(secs);
+        self.advance(secs);
+    }
+    
+    /// Advances the registers by `secs` seconds, as if that much time had passed while running.
+    pub fn advance(&mut self, secs: u64) {
+        let total = self.seconds as u64 + secs;
+        self.seconds = (total % 60) as u8;
+        let total = self.minutes as u64 + (total / 60);
+        self.minutes = (total % 60) as u8;
+        let total = self.hours as u64 + (total / 60);
+        self.hours = (total % 24) as u8;
+        let total = self.days as u64 + (total / 24);
+        if total > 0x1FF {
+            self.day_carry = true;
+        }
+        self.days = (total & 0x1FF) as u16;
+    }
+    
+    /// Sets the clock to the given time. Values are masked to the width of their registers like writes
+    /// from software are, and the sub-second counter is reset.
+    pub fn set(&mut self, seconds: u8, minutes: u8, hours: u8, days: u16) {
+        self.seconds = seconds & 0x3F;
+        self.minutes = minutes & 0x3F;
+        self.hours = hours & 0x1F;
+        self.days = days & 0x1FF;
+        self.last_update = Instant::now();
+    }
+    
+    /// Current time as (seconds, minutes, hours, days), brought up to date first.
+    pub fn time(&mut self) -> (u8, u8, u8, u16) {
+        self.update();
+        (self.seconds, self.minutes, self.hours, self.days)
+    }
+    
+    /// Copies the current time into the latched registers.
+    pub fn latch(&mut self) {
+        self.update();
+        self.latched = [self.seconds, self.minutes, self.hours, self.days as u8, self.day_high()];
+    }
+    
+    /// Day counter bit 8, halt flag (bit 6), and day counter carry (bit 7).
+    fn day_high(&self) -> u8 {
+        ((self.days >> 8) as u8 & 0b1) | if self.halted { 0x40 } else { 0 } | if self.day_carry { 0x80 } else { 0 }
+    }
+    
+    /// Reads a latched register, 0x08-0x0C.
+    pub fn read(&self, reg: u8) -> u8 {
+        self.latched[(reg - 0x08) as usize]
+    }
+    
+    /// Writes a live register, 0x08-0x0C.
+    pub fn write(&mut self, reg: u8, data: u8) {
+        self.update();
+        match reg {
+            0x08 => {
+                self.seconds = data & 0x3F;
+                self.last_update = Instant::now(); // Writing seconds resets the sub-second counter
+            },
+            0x09 => self.minutes = data & 0x3F,
+            0x0A => self.hours = data & 0x1F,
+            0x0B => self.days = (self.days & 0x100) | data as u16,
+            0x0C => {
+                self.days = (self.days & 0xFF) | (((data & 0b1) as u16) << 8);
+                self.halted = data & 0x40 != 0;
+                self.day_carry = data & 0x80 != 0;
+            },
+            _ => panic!("unreachable")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// ROM with `banks` 16 KiB banks, each filled with its own bank number.
+    fn banked_rom(banks: usize) -> Vec<u8> {
+        (0..banks).flat_map(|bank| [bank as u8; 0x4000]).collect()
+    }
+    
+    /// How an MBC is expected to behave, for [mbc_contract()].
+    struct Contract {
+        mbc: Box<dyn Mbc>,
+        /// Written before anything else.
+        setup: &'static [(u16, u8)],
+        /// Where 0x0A enables RAM, or None if it's always enabled.
+        ram_enable: Option<u16>,
+        /// Where the bank for 0x4000-0x7FFF is selected, or None if it's fixed to bank 1.
+        rom_bank: Option<u16>,
+        /// Bank mapped to 0x4000-0x7FFF after selecting bank 0.
+        bank_0: u8,
+        /// Where the RAM bank is selected, or None if there's only one.
+        ram_bank: Option<u16>,
+        /// Bits set in every RAM read, where RAM is narrower than a byte.
+        ram_unused: u8,
+    }
+    
+    #[test]
+    fn mbc_contract() {
+        let contracts = [
+            ("NoMbc", Contract { mbc: Box::new(NoMbc::new(0x2000)), setup: &[], ram_enable: None, rom_bank: None, bank_0: 1, ram_bank: None, ram_unused: 0x00 }),
+            ("Mbc1", Contract { mbc: Box::new(Mbc1::new(0x8000)), setup: &[(0x6000, 0x01)], ram_enable: Some(0x0000), rom_bank: Some(0x2000), bank_0: 1, ram_bank: Some(0x4000), ram_unused: 0x00 }),
+            ("Mbc2", Contract { mbc: Box::new(Mbc2::new()), setup: &[], ram_enable: Some(0x0000), rom_bank: Some(0x0100), bank_0: 1, ram_bank: None, ram_unused: 0xF0 }),
+            ("Mbc3", Contract { mbc: Box::new(Mbc3::new(0x8000)), setup: &[], ram_enable: Some(0x0000), rom_bank: Some(0x2000), bank_0: 1, ram_bank: Some(0x4000), ram_unused: 0x00 }),
+            ("Mbc5", Contract { mbc: Box::new(Mbc5::new(0x8000)), setup: &[], ram_enable: Some(0x0000), rom_bank: Some(0x2000), bank_0: 0, ram_bank: Some(0x4000), ram_unused: 0x00 }),
+        ];
+        let rom = banked_rom(8);
+        
+        for (name, mut contract) in contracts {
+            let mbc = &mut contract.mbc;
+            for &(addr, data) in contract.setup {
+                mbc.write(addr, data);
+            }
+            
+            // RAM can't be touched until it's enabled
+            mbc.write(0xA000, 0x5A);
+            if contract.ram_enable.is_some() {
+                assert_eq!(mbc.read(&rom, 0xA000), 0xFF, "{}: RAM readable before being enabled", name);
+            }
+            if let Some(addr) = contract.ram_enable {
+                mbc.write(addr, 0x0A);
+            }
+            mbc.write(0xA000, 0x5A);
+            assert_eq!(mbc.read(&rom, 0xA000), 0x5A | contract.ram_unused, "{}", name);
+            
+            if let Some(addr) = contract.ram_enable {
+                mbc.write(addr, 0x00);
+                mbc.write(0xA000, 0x33);
+                assert_eq!(mbc.read(&rom, 0xA000), 0xFF, "{}: RAM readable after being disabled", name);
+                mbc.write(addr, 0x0A);
+                assert_eq!(mbc.read(&rom, 0xA000), 0x5A | contract.ram_unused, "{}: RAM written while disabled", name);
+            }
+            
+            if let Some(addr) = contract.ram_bank {
+                mbc.write(addr, 0x01);
+                mbc.write(0xA000, 0x11);
+                mbc.write(addr, 0x00);
+                assert_eq!(mbc.read(&rom, 0xA000), 0x5A, "{}: RAM bank 0", name);
+                mbc.write(addr, 0x01);
+                assert_eq!(mbc.read(&rom, 0xA000), 0x11, "{}: RAM bank 1", name);
+                mbc.write(addr, 0x00);
+            }
+            
+            // 0x0000-0x3FFF stays on bank 0, and 0x4000-0x7FFF starts on bank 1
+            assert_eq!(mbc.read(&rom, 0x0000), 0, "{}", name);
+            assert_eq!(mbc.read(&rom, 0x4000), 1, "{}", name);
+            if let Some(addr) = contract.rom_bank {
+                mbc.write(addr, 0x05);
+                assert_eq!((mbc.read(&rom, 0x4000), mbc.read(&rom, 0x7FFF)), (5, 5), "{}: ROM bank 5", name);
+                mbc.write(addr, 0x00);
+                assert_eq!(mbc.read(&rom, 0x4000), contract.bank_0, "{}: ROM bank 0", name);
+            }
+        }
+    }
+    
+    #[test]
+    fn mbc1_bank_0_remap() {
+        let rom = banked_rom(128);
+        let mut mbc = Mbc1::new(0);
+        
+        for bank2 in 0..4 {
+            mbc.write(0x4000, bank2);
+            mbc.write(0x2000, 0x00);
+            // 0x00/0x20/0x40/0x60 can't be selected, and read as the bank after
+            assert_eq!(mbc.read(&rom, 0x4000), (bank2 << 5) | 1, "bank {:02X}", bank2 << 5);
+            
+            mbc.write(0x2000, 0x02);
+            assert_eq!(mbc.read(&rom, 0x7FFF), (bank2 << 5) | 2);
+        }
+    }
+    
+    #[test]
+    fn mbc1_mode_1_banks_lower_rom() {
+        let rom = banked_rom(128);
+        let mut mbc = Mbc1::new(0);
+        mbc.write(0x4000, 0b10);
+        assert_eq!(mbc.read(&rom, 0x0000), 0x00);
+        
+        // In mode 1, 0x0000-0x3FFF is the remapped bank itself
+        mbc.write(0x6000, 0x01);
+        assert_eq!(mbc.read(&rom, 0x0000), 0x40);
+        assert_eq!(mbc.read(&rom, 0x4000), 0x41);
+    }
+    
+    #[test]
+    fn mbc5_bank_0x100() {
+        // 8 MiB, the most MBC5 can address. Each bank's last byte is bit 8 of its number.
+        let mut rom = banked_rom(512);
+        for bank in 0..512 {
+            rom[(bank * 0x4000) + 0x3FFF] = (bank >> 8) as u8;
+        }
+        let mut mbc = Mbc5::new(0);
+        
+        mbc.write(0x3000, 0x01);
+        mbc.write(0x2000, 0x00);
+        assert_eq!(mbc.rom_bank, 0x100);
+        assert_eq!((mbc.read(&rom, 0x4000), mbc.read(&rom, 0x7FFF)), (0x00, 0x01));
+        mbc.write(0x2000, 0xFF);
+        assert_eq!((mbc.read(&rom, 0x4000), mbc.read(&rom, 0x7FFF)), (0xFF, 0x01));
+        
+        // Clearing bit 8 leaves the low bits alone
+        mbc.write(0x3000, 0x00);
+        assert_eq!((mbc.read(&rom, 0x4000), mbc.read(&rom, 0x7FFF)), (0xFF, 0x00));
+        assert_eq!((mbc.read(&rom, 0x0000), mbc.read(&rom, 0x3FFF)), (0x00, 0x00));
+    }
+    
+    /// Pretends `secs` seconds have passed on the host since the clock was last updated.
+    fn elapse(rtc: &mut Rtc, secs: u64) {
+        rtc.last_update -= Duration::from_secs(secs);
+    }
+    
+    /// Reads RTC registers 0x08-0x0C through 0xA000.
+    fn read_rtc(mbc: &mut Mbc3) -> [u8; 5] {
+        [0x08, 0x09, 0x0A, 0x0B, 0x0C].map(|reg| {
+            mbc.write(0x4000, reg);
+            mbc.read(&[], 0xA000)
+        })
+    }
+    
+    #[test]
+    fn mbc3_rtc_latch() {
+        let mut mbc = Mbc3::new(0x2000);
+        mbc.write(0x0000, 0x0A);
+        mbc.rtc.set(10, 20, 3, 0x123);
+        
+        // Nothing is latched until 0x00 then 0x01 is written
+        assert_eq!(read_rtc(&mut mbc), [0; 5]);
+        mbc.write(0x6000, 0x01);
+        assert_eq!(read_rtc(&mut mbc), [0; 5]);
+        mbc.write(0x6000, 0x00);
+        mbc.write(0x6000, 0x01);
+        assert_eq!(read_rtc(&mut mbc), [10, 20, 3, 0x23, 0x01]);
+        
+        // The latched registers stay put while the clock runs, until latched again
+        elapse(&mut mbc.rtc, 65);
+        assert_eq!(read_rtc(&mut mbc), [10, 20, 3, 0x23, 0x01]);
+        mbc.write(0x6000, 0x00);
+        mbc.write(0x6000, 0x01);
+        assert_eq!(read_rtc(&mut mbc), [15, 21, 3, 0x23, 0x01]);
+        
+        // RAM banks are still reachable alongside the clock, and nothing is mapped while disabled
+        mbc.write(0x4000, 0x00);
+        mbc.write(0xA000, 0x42);
+        assert_eq!(mbc.read(&[], 0xA000), 0x42);
+        mbc.write(0x0000, 0x00);
+        mbc.write(0x4000, 0x08);
+        assert_eq!(mbc.read(&[], 0xA000), 0xFF);
+    }
+    
+    #[test]
+    fn cartridge_rtc_set_latch_and_halt() {
+        use crate::arch::cartridge::Cartridge;
+        use crate::arch::BusAccessable;
+        
+        let latch = |cart: &mut Cartridge| {
+            cart.write(0x6000, 0x00);
+            cart.write(0x6000, 0x01);
+            [0x08, 0x09, 0x0A, 0x0B, 0x0C].map(|reg| {
+                cart.write(0x4000, reg);
+                cart.read(0xA000)
+            })
+        };
+        
+        let mut cart = Cartridge::new();
+        assert!(!cart.set_rtc(0, 0, 0, 0));
+        assert_eq!(cart.rtc_time(), None);
+        
+        cart.mbc = Box::new(Mbc3::new(0));
+        cart.write(0x0000, 0x0A);
+        assert!(cart.set_rtc(30, 59, 23, 0x1FF));
+        assert_eq!(cart.rtc_time(), Some((30, 59, 23, 0x1FF)));
+        assert_eq!(latch(&mut cart), [30, 59, 23, 0xFF, 0x01]);
+        
+        // Halted, the clock ignores host time
+        cart.write(0x4000, 0x0C);
+        cart.write(0xA000, 0x41);
+        elapse(cart.mbc.rtc().unwrap(), 100);
+        assert_eq!(cart.rtc_time(), Some((30, 59, 23, 0x1FF)));
+        assert_eq!(latch(&mut cart), [30, 59, 23, 0xFF, 0x41]);
+        
+        // Resumed, it rolls over past the last day and sets the carry
+        cart.write(0x4000, 0x0C);
+        cart.write(0xA000, 0x01);
+        elapse(cart.mbc.rtc().unwrap(), 30);
+        assert_eq!(cart.rtc_time(), Some((0, 0, 0, 0)));
+        assert_eq!(latch(&mut cart), [0, 0, 0, 0x00, 0x80]);
+    }
+}
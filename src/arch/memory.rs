@@ -1,20 +1,25 @@
 use std::cmp::max;
 use log::info;
 use crate::arch::{BusAccessable, SystemMode};
-use crate::SystemMode::GameboyColorGBC;
+use crate::arch::SystemMode::GameboyColorGBC;
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Memory {
     mode: SystemMode,
-    pub wram: [[u8; 0x1000]; 8],
+    /// Boxed so that moving a [Bus](crate::arch::Bus) around (e.g. while a save state is decoded)
+    /// doesn't need large stack frames.
+    #[cfg_attr(feature = "serde", serde(with = "crate::util::byte_array::banks"))]
+    pub wram: Box<[[u8; 0x1000]; 8]>,
     pub wbank: u8,
     pub undoc_regs: [u8; 4],
+    #[cfg_attr(feature = "serde", serde(with = "crate::util::byte_array"))]
     pub hram: [u8; 0x7F],
 }
 impl Memory {
     pub fn new(mode: SystemMode) -> Self { Self {
         mode,
-        wram: [[0u8; 0x1000]; 8],
+        wram: Box::new([[0u8; 0x1000]; 8]),
         wbank: 0,
         undoc_regs: [0u8; 4],
         hram: [0u8; 0x7F],
@@ -29,10 +34,12 @@ impl BusAccessable for Memory {
             0xE000..=0xFDFF => self.write(addr - 0x2000, data), // ECHO RAM
             0xFF70 => self.wbank = data & 0x07, // WRAM Bank Select
             
-            0xFF72 if self.mode == GameboyColorGBC => self.undoc_regs[0] = data,
-            0xFF73 if self.mode == GameboyColorGBC => self.undoc_regs[1] = data,
+            // Undocumented registers only exist on CGB hardware, and 0xFF74 is locked outside of CGB mode
+            0xFF72 if self.mode.is_cgb_hardware() => self.undoc_regs[0] = data,
+            0xFF73 if self.mode.is_cgb_hardware() => self.undoc_regs[1] = data,
             0xFF74 if self.mode == GameboyColorGBC => self.undoc_regs[2] = data,
-            0xFF75 if self.mode == GameboyColorGBC => self.undoc_regs[3] = data & 0b01110000,
+            0xFF75 if self.mode.is_cgb_hardware() => self.undoc_regs[3] = data & 0b01110000,
+            0xFF72..=0xFF75 => (),
             
             0xFF80..=0xFFFE => self.hram[(addr & 0x7F) as usize] = data,
             
@@ -60,15 +67,38 @@ impl BusAccessable for Memory {
             0xE000..=0xFDFF => self.read(addr - 0x2000), // ECHO RAM
             0xFF70 => self.wbank & 0x07, // WRAM Bank Select
             
-            0xFF72 if self.mode == GameboyColorGBC => self.undoc_regs[0],
-            0xFF73 if self.mode == GameboyColorGBC => self.undoc_regs[1],
+            0xFF72 if self.mode.is_cgb_hardware() => self.undoc_regs[0],
+            0xFF73 if self.mode.is_cgb_hardware() => self.undoc_regs[1],
             0xFF74 if self.mode == GameboyColorGBC => self.undoc_regs[2],
-            0xFF74 => 0xFF,
-            0xFF75 if self.mode == GameboyColorGBC => self.undoc_regs[3] & 0b01110000,
+            0xFF75 if self.mode.is_cgb_hardware() => self.undoc_regs[3] | 0b10001111, // Only bits 4-6 exist
+            0xFF72..=0xFF75 => 0xFF,
             
             0xFF80..=0xFFFE => self.hram[(addr & 0x7F) as usize],
             
             _ => unimplemented!()
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn undocumented_registers() {
+        // Values read back from 0xFF72-0xFF75 after writing 0x5A to each of them
+        let cases = [
+            (SystemMode::Gameboy, [0xFF, 0xFF, 0xFF, 0xFF]),
+            (SystemMode::GameboyColorDMG, [0x5A, 0x5A, 0xFF, 0xDF]),
+            (SystemMode::GameboyColorGBC, [0x5A, 0x5A, 0x5A, 0xDF]),
+        ];
+        for (mode, expected) in cases {
+            let mut mem = Memory::new(mode);
+            for addr in 0xFF72..=0xFF75 {
+                mem.write(addr, 0x5A);
+            }
+            
+            let actual = [0xFF72, 0xFF73, 0xFF74, 0xFF75].map(|addr| mem.read(addr));
+            assert_eq!(actual, expected, "{:?}", mode);
+        }
+    }
+}
@@ -1,15 +1,212 @@
-use log::info;
-use crate::arch::{Bus, BusAccessable, SystemMode};
+use log::trace;
+use crate::arch::{BusAccessable, SystemMode};
+use crate::arch::cpu::Interrupt;
+
+/// Dots per scanline, including HBlank. One dot is one t-cycle in single speed.
+const LINE_DOTS: u16 = 456;
+/// Scanlines per frame, including the 10 lines of VBlank.
+const FRAME_LINES: u8 = 154;
+/// First line of VBlank.
+const VBLANK_LINE: u8 = 144;
+/// Dots into the last line (153) after which LY already reads 0, ahead of the next frame.
+const LAST_LINE_LY_DOTS: u16 = 4;
+/// Length of mode 2 (OAM scan).
+const OAM_SCAN_DOTS: u16 = 80;
+/// Length of mode 3 (drawing). This is the minimum; sprites, SCX, and the window would extend it.
+const DRAWING_DOTS: u16 = 172;
+/// T-cycles per frame.
+pub const FRAME_TCYCLES: usize = (LINE_DOTS as usize) * (FRAME_LINES as usize);
+/// Size of each VRAM bank in [Ppu::render_tile_atlas()]'s output, 16 tiles across by 24 tiles down.
+/// See [Ppu::tile_atlas_width()] for the full width.
+pub const TILE_ATLAS_WIDTH: usize = 16 * 8;
+pub const TILE_ATLAS_HEIGHT: usize = 24 * 8;
 
 #[derive(Clone, Debug, Default)]
 pub struct Tile {
+    /// Indexed by row, then column.
     pub pixels: [[u32; 8]; 8],
 }
 
+/// One frame of the visible screen, as 0x00RRGGBB pixels in rows from top to bottom.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Framebuffer {
+    pixels: Vec<u32>,
+}
+impl Framebuffer {
+    pub const WIDTH: usize = 160;
+    pub const HEIGHT: usize = 144;
+    
+    pub fn new() -> Self { Self {
+        pixels: vec![0u32; Self::WIDTH * Self::HEIGHT],
+    }}
+    
+    pub fn pixels(&self) -> &[u32; Self::WIDTH * Self::HEIGHT] {
+        self.pixels.as_slice().try_into().unwrap()
+    }
+    
+    pub fn get(&self, x: usize, y: usize) -> u32 {
+        self.pixels[(y * Self::WIDTH) + x]
+    }
+    
+    pub fn set(&mut self, x: usize, y: usize, pixel: u32) {
+        self.pixels[(y * Self::WIDTH) + x] = pixel;
+    }
+    
+    /// The pixels of scanline `y`.
+    pub fn line(&self, y: usize) -> &[u32] {
+        &self.pixels[(y * Self::WIDTH)..((y + 1) * Self::WIDTH)]
+    }
+    
+    pub fn line_mut(&mut self, y: usize) -> &mut [u32] {
+        &mut self.pixels[(y * Self::WIDTH)..((y + 1) * Self::WIDTH)]
+    }
+}
+impl Default for Framebuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Outline drawn around the visible area by [Ppu::render_full_map()].
+const VIEWPORT_COLOR: u32 = 0x00FF0000;
+
+/// Colors for the four DMG shades, from lightest (0) to darkest (3).
+pub const GRAYSCALE_PALETTE: [u32; 4] = [0x00FFFFFF, 0x00AAAAAA, 0x00555555, 0x00000000];
+/// The green tint of the original DMG screen.
+pub const CLASSIC_GREEN_PALETTE: [u32; 4] = [0x009BBC0F, 0x008BAC0F, 0x00306230, 0x000F380F];
+
+/// One of the 40 entries in OAM.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Sprite {
+    /// Vertical position + 16
+    pub y: u8,
+    /// Horizontal position + 8
+    pub x: u8,
+    pub tile: u8,
+    pub flags: u8,
+}
+impl Sprite {
+    pub fn from_bytes(bytes: &[u8]) -> Self { Self {
+        y: bytes[0],
+        x: bytes[1],
+        tile: bytes[2],
+        flags: bytes[3],
+    }}
+    
+    /// Bit 7: When set, BG and window colors 1-3 are drawn over this sprite.
+    pub fn behind_bg(&self) -> bool {
+        self.flags & 0b10000000 != 0
+    }
+    
+    /// Bit 6
+    pub fn y_flip(&self) -> bool {
+        self.flags & 0b01000000 != 0
+    }
+    
+    /// Bit 5
+    pub fn x_flip(&self) -> bool {
+        self.flags & 0b00100000 != 0
+    }
+    
+    /// Bit 4: Selects OBP0 or OBP1 (DMG only).
+    pub fn dmg_palette(&self) -> u8 {
+        (self.flags & 0b00010000) >> 4
+    }
+    
+    /// Bit 3: VRAM bank of the tile (CGB only).
+    pub fn vram_bank(&self) -> u8 {
+        (self.flags & 0b00001000) >> 3
+    }
+    
+    /// Bits 0-2: Selects OBP0-7 (CGB only).
+    pub fn cgb_palette(&self) -> u8 {
+        self.flags & 0b00000111
+    }
+}
+
+/// Kind of CPU access which triggers the DMG OAM corruption bug, see [Ppu::corrupt_oam()].
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OamCorruption {
+    /// A read from 0xFE00-0xFEFF.
+    Read,
+    /// A write to 0xFE00-0xFEFF, or an increment/decrement of a 16-bit register holding an address
+    /// in that range. Both at once count as a single write.
+    Write,
+    /// A read from 0xFE00-0xFEFF in the same m-cycle that the register holding the address is
+    /// incremented or decremented, e.g. `LD A,(HL+)` and `POP`.
+    ReadIncrement,
+}
+
+/// A change of PPU mode, returned by [Ppu::tcycle()].
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ModeTransition {
+    pub from: u8,
+    pub to: u8,
+    /// LY and dot of the first dot in the new mode.
+    pub ly: u8,
+    pub dot: u16,
+}
+
+/// Window state carried from one scanline to the next within a frame.
+#[derive(Copy, Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct WindowState {
+    /// Internal line counter, which only advances on lines where the window was actually drawn.
+    line: u8,
+    /// Set after a line drawn with WX=166, which causes the window to cover all of the next line.
+    wrap: bool,
+    /// Set once LY has matched WY during the frame. The window can only be drawn from then on, even
+    /// if WY is changed afterwards.
+    triggered: bool,
+}
+
+/// BG map attributes for a single tile, stored in VRAM bank 1 at the same offset as the tile's index
+/// in bank 0 (CGB only).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct TileAttributes(pub u8);
+impl TileAttributes {
+    /// Bit 7: When set, BG colors 1-3 are drawn over all sprites.
+    pub fn priority(&self) -> bool {
+        self.0 & 0b10000000 != 0
+    }
+    
+    /// Bit 6
+    pub fn y_flip(&self) -> bool {
+        self.0 & 0b01000000 != 0
+    }
+    
+    /// Bit 5
+    pub fn x_flip(&self) -> bool {
+        self.0 & 0b00100000 != 0
+    }
+    
+    /// Bit 3: VRAM bank of the tile data.
+    pub fn vram_bank(&self) -> u8 {
+        (self.0 & 0b00001000) >> 3
+    }
+    
+    /// Bits 0-2: Selects BGP0-7.
+    pub fn palette(&self) -> u8 {
+        self.0 & 0b00000111
+    }
+}
+
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Ppu {
     mode: SystemMode,
-    pub vram: [u8; 0x2000],
+    /// Boxed, like WRAM, so that moving the PPU around doesn't need a large stack frame.
+    #[cfg_attr(feature = "serde", serde(with = "crate::util::byte_array::boxed"))]
+    pub vram: Box<[u8; 0x2000]>,
+    /// Second VRAM bank (CGB only), holding more tile data and the BG map attributes.
+    #[cfg_attr(feature = "serde", serde(with = "crate::util::byte_array::boxed"))]
+    pub vram1: Box<[u8; 0x2000]>,
+    /// VRAM Bank Select (0xFF4F) (R/W, CGB only). Bit 0 selects the bank seen by the CPU.
+    pub vbk: u8,
+    /// Object Attribute Memory, 40 sprites of 4 bytes each.
+    #[cfg_attr(feature = "serde", serde(with = "crate::util::byte_array"))]
+    pub oam: [u8; 0xA0],
     /// LCD Control (0xFF40) (R/W)
     pub lcdc: u8,
     /// LCD Status (0xFF41) (R/W)
@@ -20,90 +217,646 @@ pub struct Ppu {
     pub scx: u8,
     /// LCD Y Coordinate (0xFF44) (R)
     pub ly: u8,
+    /// Current dot within the scanline, 0-455.
+    pub dot: u16,
+    /// State of the combined STAT interrupt line. The interrupt is only requested on a rising edge.
+    stat_line: bool,
+    /// Scanlines drawn so far, 160x144. Each line is drawn as the PPU enters mode 3 on it.
+    framebuffer: Framebuffer,
+    window: WindowState,
+    /// Number of frames completed, incremented as the PPU enters VBlank.
+    pub frames: u64,
+    /// Colors used to display each of the four DMG shades.
+    dmg_palette: [u32; 4],
     /// LY Compare (0xFF45) (R/W)
     pub lyc: u8,
     /// BG Palette Data (0xFF47) (R/W)
     pub bgp: u8,
+    /// OBJ Palette 0 Data (0xFF48) (R/W)
+    pub obp0: u8,
+    /// OBJ Palette 1 Data (0xFF49) (R/W)
+    pub obp1: u8,
+    /// Background Palette Specification (0xFF68) (R/W, CGB only). Bits 0-5 index into BG palette
+    /// RAM, and bit 7 enables auto-increment after each write to BCPD.
+    pub bcps: u8,
+    /// Object Palette Specification (0xFF6A) (R/W, CGB only). Same layout as BCPS.
+    pub ocps: u8,
+    /// BG palette RAM, eight palettes of four little-endian BGR555 colors (CGB only).
+    #[cfg_attr(feature = "serde", serde(with = "crate::util::byte_array"))]
+    pub bg_palette_ram: [u8; 64],
+    /// OBJ palette RAM, eight palettes of four little-endian BGR555 colors (CGB only).
+    #[cfg_attr(feature = "serde", serde(with = "crate::util::byte_array"))]
+    pub obj_palette_ram: [u8; 64],
     /// Window Y Position (0xFF4A) (R/W)
     pub wy: u8,
     /// Window X Position + 7 (0xFF4B) (R/W)
-    pub wx: u8, //TODO: Implement hardware bugs when wx == 0 or 166
+    pub wx: u8,
+    /// Mode change that [tcycle()] hasn't returned yet. Turning the LCD off changes the mode as
+    /// LCDC is written, rather than during a dot. Not included in save states.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    transition: Option<ModeTransition>,
 }
 impl Ppu {
     pub fn new(mode: SystemMode) -> Self { Self {
         mode,
-        vram: [0u8; 0x2000],
+        vram: Box::new([0u8; 0x2000]),
+        vram1: Box::new([0u8; 0x2000]),
+        vbk: 0,
+        oam: [0u8; 0xA0],
         lcdc: 0,
         stat: 0,
         bgp: 0,
+        obp0: 0,
+        obp1: 0,
+        bcps: 0,
+        ocps: 0,
+        bg_palette_ram: [0u8; 64],
+        obj_palette_ram: [0u8; 64],
         scy: 0,
         scx: 0,
-        ly: 0x90,
+        ly: 0,
+        dot: 0,
+        stat_line: false,
+        framebuffer: Framebuffer::new(),
+        window: WindowState::default(),
+        frames: 0,
+        dmg_palette: GRAYSCALE_PALETTE,
         lyc: 0,
         wy: 0,
         wx: 0,
+        transition: None,
     }}
     
-    pub fn tcycle(&mut self, bus: &mut Bus) {
+    /// Base address of the tile map used by the background, selected by LCDC bit 3.
+    pub fn bg_map_addr(&self) -> u16 {
+        if self.lcdc & 0b00001000 != 0 { 0x9C00 } else { 0x9800 }
+    }
+    
+    /// Base address of the tile map used by the window, selected by LCDC bit 6.
+    /// 
+    /// This is independent of the background's selection; both layers may use either map.
+    pub fn window_map_addr(&self) -> u16 {
+        if self.lcdc & 0b01000000 != 0 { 0x9C00 } else { 0x9800 }
+    }
+    
+    /// Address of the data for a BG or window tile, using the addressing mode selected by LCDC bit 4.
+    /// 
+    /// When set, tiles 0-255 are at 0x8000-0x8FFF. When clear, tiles are signed and 0 is at 0x9000.
+    pub fn bg_tile_addr(&self, tile: u8) -> u16 {
+        if self.lcdc & 0b00010000 != 0 {
+            0x8000 + ((tile as u16) * 16)
+        } else {
+            0x9000u16.wrapping_add(((tile as i8 as i16) * 16) as u16)
+        }
+    }
+    
+    /// Color index (0-3) of a pixel in the tile whose data starts at `addr` in VRAM bank `bank`.
+    pub fn tile_color(&self, bank: u8, addr: u16, x: u8, y: u8) -> u8 {
+        let vram = if bank == 0 { &self.vram } else { &self.vram1 };
+        let row = (addr as usize + ((y as usize) * 2)) & 0x1FFF;
+        let lsb = vram[row];
+        let msb = vram[row + 1];
+        let bit = 7 - x;
         
+        (((msb >> bit) & 0b1) << 1) | ((lsb >> bit) & 0b1)
     }
     
-    pub fn render(&self, buf: &mut [u32]) {
-        /*for i in 0..buf.len() {
-            if let Some(vram_pix) = self.vram.get(i) {
-                buf[i] = *vram_pix as u32;
-            }
-        }*/
+    /// Color index (0-3) at a position within the 256x256 pixel tile map starting at `map`, along
+    /// with the attributes of the tile there.
+    /// 
+    /// Attributes are only used in CGB mode, where they can flip the tile and select its VRAM bank.
+    /// Otherwise they're always empty.
+    pub fn map_color(&self, map: u16, x: u8, y: u8) -> (u8, TileAttributes) {
+        let offset = ((map as usize) + ((y as usize / 8) * 32) + (x as usize / 8)) & 0x1FFF;
+        let tile = self.vram[offset];
+        let attrs = if self.mode == SystemMode::GameboyColorGBC {
+            TileAttributes(self.vram1[offset])
+        } else {
+            TileAttributes::default()
+        };
         
+        let x = if attrs.x_flip() { 7 - (x % 8) } else { x % 8 };
+        let y = if attrs.y_flip() { 7 - (y % 8) } else { y % 8 };
         
+        (self.tile_color(attrs.vram_bank(), self.bg_tile_addr(tile), x, y), attrs)
+    }
+    
+    /// Tile indexes and attributes of all 32x32 entries in the tile map starting at `map`, row by row.
+    /// 
+    /// As with [Ppu::map_color()], attributes are always empty outside of CGB mode.
+    pub fn dump_tilemap(&self, map: u16) -> Vec<(u8, TileAttributes)> {
+        let base = (map as usize) & 0x1FFF;
+        (base..(base + 1024)).map(|offset| {
+            let attrs = if self.mode == SystemMode::GameboyColorGBC {
+                TileAttributes(self.vram1[offset])
+            } else {
+                TileAttributes::default()
+            };
+            
+            (self.vram[offset], attrs)
+        }).collect()
+    }
+    
+    /// Renders the entire 256x256 background tile map, including the area that's off-screen, and
+    /// outlines the visible 160x144 viewport at SCX/SCY (wrapping around the edges).
+    pub fn render_full_map(&self, buf: &mut [u32]) {
+        let map = self.bg_map_addr();
+        for y in 0..256usize {
+            for x in 0..256usize {
+                if let Some(pixel) = buf.get_mut((y * 256) + x) {
+                    let (color, attrs) = self.map_color(map, x as u8, y as u8);
+                    *pixel = self.bg_pixel(attrs.palette(), color);
+                }
+            }
+        }
         
-        let mut x = 0;
-        let mut y = 0;
-        let width = 160;
-        for tile in self.tiles() {
-            for ty in 0..8 {
-                for tx in 0..8 {
-                    if (y * width) + x >= buf.len() { return; }
-                    //println!("{}, {}", x, y);
-                    buf[(y * width) + x] = tile.pixels[tx][ty];
-                    x += 1;
+        for i in 0..160u8 {
+            for y in [self.scy, self.scy.wrapping_add(143)] {
+                if let Some(pixel) = buf.get_mut(((y as usize) * 256) + (self.scx.wrapping_add(i) as usize)) {
+                    *pixel = VIEWPORT_COLOR;
+                }
+            }
+        }
+        for i in 0..144u8 {
+            for x in [self.scx, self.scx.wrapping_add(159)] {
+                if let Some(pixel) = buf.get_mut(((self.scy.wrapping_add(i) as usize) * 256) + (x as usize)) {
+                    *pixel = VIEWPORT_COLOR;
                 }
-                x -= 8;
-                y += 1;
             }
-            y -= 8;
-            x += 8;
+        }
+    }
+    
+    /// Decodes a single entry (0-39) from OAM.
+    pub fn sprite(&self, index: usize) -> Sprite {
+        Sprite::from_bytes(&self.oam[(index * 4)..((index * 4) + 4)])
+    }
+    
+    /// Decodes all 40 entries from OAM, in OAM order.
+    pub fn sprites(&self) -> [Sprite; 40] {
+        let mut sprites = [Sprite::default(); 40];
+        for (i, sprite) in sprites.iter_mut().enumerate() {
+            *sprite = self.sprite(i);
+        }
+        
+        sprites
+    }
+    
+    /// Height of all sprites, 8 or 16 pixels, selected by LCDC bit 2.
+    pub fn sprite_height(&self) -> u8 {
+        if self.lcdc & 0b00000100 != 0 { 16 } else { 8 }
+    }
+    
+    /// Sprites found on line `ly` by the OAM scan, along with their OAM index, highest priority first.
+    /// 
+    /// Only the first 10 sprites in OAM which overlap the line are selected. In CGB mode the lower OAM
+    /// index always wins. Otherwise the sprite with the smaller X wins, with ties going to the lower
+    /// OAM index.
+    pub fn line_sprites(&self, ly: u8) -> Vec<(usize, Sprite)> {
+        let height = self.sprite_height() as i16;
+        let mut sprites: Vec<(usize, Sprite)> = self.sprites().into_iter()
+            .enumerate()
+            .filter(|(_, sprite)| {
+                let top = (sprite.y as i16) - 16;
+                (top..(top + height)).contains(&(ly as i16))
+            })
+            .take(10)
+            .collect();
+        
+        if self.mode != SystemMode::GameboyColorGBC {
+            sprites.sort_by_key(|(_, sprite)| sprite.x); // stable, so equal X stays in OAM order
+        }
+        
+        sprites
+    }
+    
+    /// True while the PPU is drawing (mode 3), when the CPU can't access VRAM.
+    /// 
+    /// Like OAM and palette RAM, access is never blocked while the LCD is off, regardless of what
+    /// mode STAT was left in.
+    fn vram_blocked(&self) -> bool {
+        self.lcdc & 0b10000000 != 0 && self.stat & 0b11 == 3
+    }
+    
+    /// True while the PPU is scanning OAM or drawing (modes 2 and 3), when the CPU can't access OAM.
+    fn oam_blocked(&self) -> bool {
+        self.lcdc & 0b10000000 != 0 && self.stat & 0b11 >= 2
+    }
+    
+    /// Corrupts OAM the way DMG hardware does when the CPU puts an address in 0xFE00-0xFEFF on the bus
+    /// while the PPU is scanning OAM. Does nothing outside of mode 2, or on CGB hardware.
+    /// 
+    /// OAM is treated as 20 rows of four 16-bit words, and mode 2 reads one row per m-cycle. The row
+    /// being read is corrupted using the row before it, so nothing happens during the first m-cycle.
+    /// Reads and writes replace the row's first word with a mix of the words around it, and copy the
+    /// other three words from the previous row. A read during an increment first also corrupts the
+    /// previous row and copies it over the rows on either side, except around the first four rows
+    /// and the last one.
+    pub fn corrupt_oam(&mut self, kind: OamCorruption) {
+        if self.mode.is_cgb_hardware() || self.lcdc & 0b10000000 == 0 || self.mode() != 2 {
+            return;
+        }
+        let row = (self.dot / 4) as usize;
+        
+        if kind == OamCorruption::ReadIncrement && (4..19).contains(&row) {
+            let a = self.oam_word(row - 2, 0);
+            let b = self.oam_word(row - 1, 0);
+            let c = self.oam_word(row, 0);
+            let d = self.oam_word(row - 1, 2);
+            self.set_oam_word(row - 1, 0, (b & (a | c | d)) | (a & c & d));
             
-            if x >= 150 {
-                x = 0;
-                y += 8;
+            let prev = (row - 1) * 8;
+            self.oam.copy_within(prev..(prev + 8), row * 8);
+            self.oam.copy_within(prev..(prev + 8), (row - 2) * 8);
+        }
+        
+        if row == 0 {
+            return;
+        }
+        let a = self.oam_word(row, 0);
+        let b = self.oam_word(row - 1, 0);
+        let c = self.oam_word(row - 1, 2);
+        let first = match kind {
+            OamCorruption::Write => ((a ^ c) & (b ^ c)) ^ c,
+            OamCorruption::Read | OamCorruption::ReadIncrement => b | (a & c),
+        };
+        self.set_oam_word(row, 0, first);
+        
+        let prev = (row - 1) * 8;
+        self.oam.copy_within((prev + 2)..(prev + 8), (row * 8) + 2);
+    }
+    
+    /// Word (0-3) of an 8 byte OAM row, as used by [corrupt_oam()].
+    fn oam_word(&self, row: usize, word: usize) -> u16 {
+        let i = (row * 8) + (word * 2);
+        u16::from_le_bytes([self.oam[i], self.oam[i + 1]])
+    }
+    
+    fn set_oam_word(&mut self, row: usize, word: usize, data: u16) {
+        let i = (row * 8) + (word * 2);
+        self.oam[i..(i + 2)].copy_from_slice(&data.to_le_bytes());
+    }
+    
+    /// Mode for the current LY and dot: 2 for the first 80 dots, 3 for the next 172, and 0 for the
+    /// rest of the line. Lines 144-153 are entirely mode 1.
+    fn dot_mode(&self) -> u8 {
+        if self.ly >= VBLANK_LINE {
+            1
+        } else if self.dot < OAM_SCAN_DOTS {
+            2
+        } else if self.dot < OAM_SCAN_DOTS + DRAWING_DOTS {
+            3
+        } else {
+            0
+        }
+    }
+    
+    /// Current mode, from the lower 2 bits of STAT.
+    /// 
+    /// 0: HBlank, 1: VBlank, 2: OAM scan, 3: Drawing
+    pub fn mode(&self) -> u8 {
+        self.stat & 0b11
+    }
+    
+    /// Sets the mode in STAT, recording the change for [tcycle()] to return.
+    fn set_mode(&mut self, mode: u8) {
+        let from = self.mode();
+        self.stat = (self.stat & !0b11) | mode;
+        if from != mode {
+            self.transition = Some(ModeTransition { from, to: mode, ly: self.ly, dot: self.dot });
+        }
+    }
+    
+    /// LY as seen through 0xFF44 and compared against LYC. This is the current line, except on the
+    /// last line of VBlank (153), where it wraps around to 0 after the first few dots.
+    pub fn read_ly(&self) -> u8 {
+        if self.ly == FRAME_LINES - 1 && self.dot >= LAST_LINE_LY_DOTS {
+            0
+        } else {
+            self.ly
+        }
+    }
+    
+    /// Row of the window that will be drawn next. Unlike LY, this only advances on lines where the
+    /// window was drawn, and is reset at the start of each frame.
+    pub fn window_line(&self) -> u8 {
+        self.window.line
+    }
+    
+    /// Puts the PPU in the state it's held in while the LCD is off: LY 0, at the start of the line, in
+    /// mode 0. Turning the LCD back on then starts a new frame from there.
+    /// 
+    /// The coincidence flag keeps comparing LYC against LY, now 0, rather than holding on to the
+    /// comparison from the line the LCD was turned off on.
+    fn stop(&mut self) {
+        self.ly = 0;
+        self.dot = 0;
+        self.set_mode(0);
+        self.stat = (self.stat & !0b100) | (((self.lyc == 0) as u8) << 2);
+        self.stat_line = false;
+        self.window = WindowState::default();
+    }
+    
+    /// Performs one dot, returning any interrupts that should be requested, and the mode change if
+    /// there was one. At most one change happens per dot, for debugging PPU timing.
+    pub fn tcycle(&mut self) -> (Interrupt, Option<ModeTransition>) {
+        let mut interrupts = Interrupt::empty();
+        
+        if self.lcdc & 0b10000000 == 0 {
+            self.stop();
+            return (interrupts, self.transition.take());
+        }
+        
+        let mode = self.dot_mode();
+        let coincidence = self.read_ly() == self.lyc;
+        self.stat = (self.stat & !0b100) | ((coincidence as u8) << 2);
+        self.set_mode(mode);
+        
+        if mode == 3 && self.dot == OAM_SCAN_DOTS {
+            let mut framebuffer = std::mem::take(&mut self.framebuffer);
+            let mut window = self.window;
+            self.draw_line(self.ly, &mut window, framebuffer.line_mut(self.ly as usize));
+            self.framebuffer = framebuffer;
+            self.window = window;
+        }
+        
+        if self.ly == VBLANK_LINE && self.dot == 0 {
+            interrupts |= Interrupt::VBlank;
+            self.frames += 1;
+        }
+        
+        let stat_line = (self.stat & 0b01000000 != 0 && coincidence)
+            || (self.stat & 0b00100000 != 0 && mode == 2)
+            || (self.stat & 0b00010000 != 0 && mode == 1)
+            || (self.stat & 0b00001000 != 0 && mode == 0);
+        if stat_line && !self.stat_line {
+            interrupts |= Interrupt::Stat;
+        }
+        self.stat_line = stat_line;
+        
+        self.dot += 1;
+        if self.dot == LINE_DOTS {
+            self.dot = 0;
+            self.ly = (self.ly + 1) % FRAME_LINES;
+            if self.ly == 0 {
+                self.window = WindowState::default();
+            }
+        }
+        // STAT always reports the mode of the dot the PPU is on, so reads at dot 80 already see mode 3
+        self.set_mode(self.dot_mode());
+        
+        (interrupts, self.transition.take())
+    }
+    
+    /// Draws one 160 pixel scanline using the current register and VRAM state.
+    /// 
+    /// The background is fetched from the map selected by LCDC bit 3, offset by SCX/SCY and wrapping
+    /// around the 256x256 map. Outside of CGB mode, it's blank (color 0) while LCDC bit 0 is clear.
+    /// 
+    /// The window covers the background from X=WX-7 onward, once LY has matched WY in the current
+    /// frame, while LCDC bit 5 is set and the background isn't blanked. It's fetched from the map
+    /// selected by LCDC bit 6, using the internal line counter in `window` rather than LY. That
+    /// counter only advances on lines where the window is drawn, so toggling the window mid-frame
+    /// picks up where it left off.
+    fn draw_line(&self, ly: u8, window: &mut WindowState, line: &mut [u32]) {
+        let map = self.bg_map_addr();
+        let y = ly.wrapping_add(self.scy);
+        
+        // Raw BG color indices and attributes are kept around for resolving OBJ-to-BG priority
+        let mut bg = [(0u8, TileAttributes::default()); Framebuffer::WIDTH];
+        let cgb = self.mode == SystemMode::GameboyColorGBC;
+        // In CGB mode LCDC bit 0 doesn't blank the BG and window, it only takes away their priority
+        let bg_enabled = cgb || self.lcdc & 0b00000001 != 0;
+        if bg_enabled {
+            for (x, pixel) in bg.iter_mut().enumerate() {
+                *pixel = self.map_color(map, (x as u8).wrapping_add(self.scx), y);
+            }
+        }
+        
+        if ly == self.wy {
+            window.triggered = true;
+        }
+        let wrap = window.wrap;
+        window.wrap = false;
+        if bg_enabled && self.lcdc & 0b00100000 != 0 && window.triggered && self.wx <= 166 {
+            let map = self.window_map_addr();
+            // With WX=0 the window is shifted further left by SCX's fine scroll
+            let (start, skip) = match self.wx {
+                _ if wrap => (0, 0),
+                0 => (0, 7 + (self.scx & 0b111)),
+                1..=6 => (0, 7 - self.wx),
+                _ => ((self.wx - 7) as usize, 0),
+            };
+            
+            for (x, pixel) in bg.iter_mut().enumerate().skip(start) {
+                *pixel = self.map_color(map, (x - start) as u8 + skip, window.line);
+            }
+            window.line = window.line.wrapping_add(1);
+            window.wrap = self.wx == 166;
+        }
+        
+        for (pixel, &(color, attrs)) in line.iter_mut().zip(bg.iter()) {
+            *pixel = self.bg_pixel(attrs.palette(), color);
+        }
+        
+        if self.lcdc & 0b00000010 == 0 {
+            return;
+        }
+        
+        let height = self.sprite_height();
+        // Each pixel goes to the highest priority sprite with a non-transparent pixel there. Only then
+        // is that sprite's BG priority applied, so it hides lower priority sprites even when the BG
+        // covers it.
+        let mut taken = [false; Framebuffer::WIDTH];
+        for (_, sprite) in self.line_sprites(ly) {
+            let mut row = ly.wrapping_add(16).wrapping_sub(sprite.y);
+            if sprite.y_flip() {
+                row = height - 1 - row;
+            }
+            // 8x16 sprites ignore bit 0 of the tile index, the bottom half uses the next tile
+            let tile = if height == 16 { (sprite.tile & 0xFE) | (row / 8) } else { sprite.tile };
+            let addr = 0x8000 + (tile as u16) * 16;
+            let bank = if self.mode == SystemMode::GameboyColorGBC { sprite.vram_bank() } else { 0 };
+            
+            for col in 0..8u8 {
+                let x = sprite.x as i16 - 8 + col as i16;
+                if !(0..Framebuffer::WIDTH as i16).contains(&x) {
+                    continue;
+                }
+                let x = x as usize;
+                if taken[x] {
+                    continue;
+                }
+                
+                let color = self.tile_color(bank, addr, if sprite.x_flip() { 7 - col } else { col }, row % 8);
+                if color == 0 {
+                    continue;
+                }
+                taken[x] = true;
+                
+                let (bg_color, attrs) = bg[x];
+                let bg_priority = if cgb {
+                    self.lcdc & 0b00000001 != 0 && (sprite.behind_bg() || attrs.priority())
+                } else {
+                    sprite.behind_bg()
+                };
+                if bg_priority && bg_color != 0 {
+                    continue;
+                }
+                
+                line[x] = self.obj_pixel(&sprite, color);
             }
         }
     }
     
-    fn tiles(&self) -> Vec<Tile> {
+    /// Final color of a BG or window pixel, given its tile's CGB palette (0-7) and its color index.
+    /// 
+    /// In CGB mode the palette is looked up in BG palette RAM. DMG games on a CGB still go through BGP,
+    /// with the resulting shade looked up in CGB palette 0, which the boot ROM sets up.
+    fn bg_pixel(&self, palette: u8, color: u8) -> u32 {
+        match self.mode {
+            SystemMode::GameboyColorGBC => Self::cgb_color(&self.bg_palette_ram, palette, color),
+            SystemMode::GameboyColorDMG => Self::cgb_color(&self.bg_palette_ram, 0, Self::shade(self.bgp, color)),
+            _ => self.palette(Self::shade(self.bgp, color)),
+        }
+    }
+    
+    /// Colors that BG color indices 0-3 are currently displayed as, using BGP or CGB BG palette 0.
+    pub fn bg_colors(&self) -> [u32; 4] {
+        [0, 1, 2, 3].map(|color| self.bg_pixel(0, color))
+    }
+    
+    /// Final color of a sprite pixel, selecting the palette from the sprite's flags.
+    /// 
+    /// DMG games on a CGB still go through OBP0/OBP1, with the shade looked up in CGB OBJ palette 0/1.
+    fn obj_pixel(&self, sprite: &Sprite, color: u8) -> u32 {
+        let obp = if sprite.dmg_palette() == 0 { self.obp0 } else { self.obp1 };
+        match self.mode {
+            SystemMode::GameboyColorGBC => Self::cgb_color(&self.obj_palette_ram, sprite.cgb_palette(), color),
+            SystemMode::GameboyColorDMG => Self::cgb_color(&self.obj_palette_ram, sprite.dmg_palette(), Self::shade(obp, color)),
+            _ => self.palette(Self::shade(obp, color)),
+        }
+    }
+    
+    /// Converts a BGR555 color from CGB palette RAM into 0x00RRGGBB.
+    fn cgb_color(ram: &[u8; 64], palette: u8, color: u8) -> u32 {
+        let i = ((palette as usize & 0b111) * 8) + ((color as usize & 0b11) * 2);
+        let bgr = ((ram[i + 1] as u32) << 8) | (ram[i] as u32);
+        
+        // Scales 5 bits up to 8, so that 0x1F becomes 0xFF
+        let channel = |c: u32| {
+            let c = c & 0x1F;
+            (c << 3) | (c >> 2)
+        };
+        
+        (channel(bgr) << 16) | (channel(bgr >> 5) << 8) | channel(bgr >> 10)
+    }
+    
+    /// True while the CPU can't access CGB palette RAM, which is in use during mode 3.
+    fn palette_ram_blocked(&self) -> bool {
+        self.lcdc & 0b10000000 != 0 && self.stat & 0b11 == 3
+    }
+    
+    /// Writes to BCPD/OCPD at the index in `spec`, then auto-increments the index if enabled.
+    fn write_palette_data(ram: &mut [u8; 64], spec: &mut u8, data: u8, blocked: bool) {
+        if !blocked {
+            ram[(*spec & 0x3F) as usize] = data;
+        }
+        // The index is incremented even when the write itself is blocked
+        if *spec & 0b10000000 != 0 {
+            *spec = (*spec & 0b10000000) | ((*spec + 1) & 0x3F);
+        }
+    }
+    
+    /// Maps a color index (0-3) through a DMG palette register (BGP, OBP0, or OBP1) to a shade (0-3).
+    fn shade(palette: u8, color: u8) -> u8 {
+        (palette >> (color * 2)) & 0b11
+    }
+    
+    /// The most recently drawn frame.
+    pub fn framebuffer(&self) -> &Framebuffer {
+        &self.framebuffer
+    }
+    
+    /// Copies the most recently drawn frame into `frame`.
+    pub fn render(&self, frame: &mut Framebuffer) {
+        frame.clone_from(&self.framebuffer);
+    }
+    
+    /// Draws every scanline right now from the current state, instead of as the PPU reaches them.
+    pub fn render_immediate(&self, frame: &mut Framebuffer) {
+        let mut window = WindowState::default();
+        for ly in 0..Framebuffer::HEIGHT {
+            self.draw_line(ly as u8, &mut window, frame.line_mut(ly));
+        }
+    }
+    
+    /// Draws as many of the tiles in VRAM as fit on screen as a grid, 20 across by 18 down, for
+    /// debugging. See [render_tile_atlas()] for all of them.
+    pub fn render_tiles(&self, frame: &mut Framebuffer) {
+        let tiles_per_row = Framebuffer::WIDTH / 8;
+        
+        for (i, tile) in self.tiles().iter().enumerate().take(tiles_per_row * (Framebuffer::HEIGHT / 8)) {
+            let x = (i % tiles_per_row) * 8;
+            let y = (i / tiles_per_row) * 8;
+            for (row, pixels) in tile.pixels.iter().enumerate() {
+                frame.line_mut(y + row)[x..(x + 8)].copy_from_slice(pixels);
+            }
+        }
+    }
+    
+    /// Number of VRAM banks, 2 in CGB mode.
+    fn vram_banks(&self) -> usize {
+        if self.mode == SystemMode::GameboyColorGBC { 2 } else { 1 }
+    }
+    
+    /// Width of [render_tile_atlas()]'s output, [TILE_ATLAS_WIDTH] for each VRAM bank.
+    pub fn tile_atlas_width(&self) -> usize {
+        TILE_ATLAS_WIDTH * self.vram_banks()
+    }
+    
+    /// Draws all 384 tiles in each VRAM bank as a [tile_atlas_width()]x[TILE_ATLAS_HEIGHT] image,
+    /// in order from left to right, then top to bottom.
+    /// 
+    /// In CGB mode, the 384 tiles in VRAM bank 1 are drawn to the right of those in bank 0.
+    pub fn render_tile_atlas(&self) -> Vec<u32> {
+        let width = self.tile_atlas_width();
+        let mut buf = vec![0u32; width * TILE_ATLAS_HEIGHT];
+        let tiles_per_row = TILE_ATLAS_WIDTH / 8;
+        let tiles_per_bank = tiles_per_row * (TILE_ATLAS_HEIGHT / 8);
+        
+        for (i, tile) in self.tiles().iter().enumerate() {
+            let bank = i / tiles_per_bank;
+            let i = i % tiles_per_bank;
+            let x = (bank * TILE_ATLAS_WIDTH) + ((i % tiles_per_row) * 8);
+            let y = (i / tiles_per_row) * 8;
+            for (row, pixels) in tile.pixels.iter().enumerate() {
+                let start = ((y + row) * width) + x;
+                buf[start..(start + 8)].copy_from_slice(pixels);
+            }
+        }
+        
+        buf
+    }
+    
+    /// Decodes the 384 tiles in each VRAM bank, bank 0 first, using the current BG palette.
+    pub fn tiles(&self) -> Vec<Tile> {
         let mut tiles = vec![];
-        let mut chunks = self.vram[0..=0x17FF].chunks_exact(16);
+        let banks = [&self.vram, &self.vram1];
+        let chunks = banks[..self.vram_banks()].iter().flat_map(|bank| bank[0..=0x17FF].chunks_exact(16));
         
-        for _ in 0..chunks.len() {
-            let chunk = chunks.next().unwrap();
+        for chunk in chunks {
             let mut tile = Tile::default();
-            let mut row = 0;
-            for bi in (0..16).step_by(2) {
+            for (row, bi) in (0..16).step_by(2).enumerate() {
                 let lsb = chunk[bi];
                 let msb = chunk[bi + 1];
                 
-                let mut col = 0;
-                for idi in (0..8).rev() {
-                    let msb = msb & (1 << idi) >> idi;
-                    let lsb = lsb & (1 << idi) >> idi;
+                for (col, idi) in (0..8).rev().enumerate() {
+                    let msb = (msb >> idi) & 0b1;
+                    let lsb = (lsb >> idi) & 0b1;
                     
                     let colori = (msb << 1) | lsb;
-                    tile.pixels[row][col] = self.palette(colori);
-                    
-                    col += 1;
+                    tile.pixels[row][col] = self.palette(Self::shade(self.bgp, colori));
                 }
-                row += 1;
             }
             
             tiles.push(tile);
@@ -112,38 +865,67 @@ impl Ppu {
         tiles
     }
     
-    fn palette(&self, index: u8) -> u32 {
-        match index {
-            0 => 0x00331111,
-            1 => 0x00116611,
-            2 => 0x001111AA,
-            3 => 0x00FFFFFF,
-            _ => {
-                0x00FF0000
-            },
-        }
+    /// Sets the colors used to display the four DMG shades, from lightest to darkest.
+    pub fn set_dmg_palette(&mut self, palette: [u32; 4]) {
+        self.dmg_palette = palette;
+    }
+    
+    /// Color of a shade (0-3), after it's been mapped through BGP, OBP0, or OBP1.
+    fn palette(&self, shade: u8) -> u32 {
+        self.dmg_palette[(shade & 0b11) as usize]
     }
 }
 
 impl BusAccessable for Ppu {
     fn write(&mut self, addr: u16, data: u8) {
         match addr {
+            0x8000..=0x9FFF if self.vram_blocked() => (),
+            0x8000..=0x9FFF if self.vbk & 0b1 != 0 => self.vram1[(addr & 0x1FFF) as usize] = data,
             0x8000..=0x9FFF => self.vram[(addr & 0x1FFF) as usize] = data,
-            0xFF40 => self.lcdc = data,
-            0xFF41 => self.stat = data,
+            0xFE00..=0xFE9F if self.oam_blocked() => (),
+            0xFE00..=0xFE9F => self.oam[(addr - 0xFE00) as usize] = data,
+            0xFEA0..=0xFEFF => (), // Prohibited
+            0xFF40 => {
+                self.lcdc = data;
+                // Turning the LCD off takes effect immediately, even mid-line
+                if data & 0b10000000 == 0 {
+                    self.stop();
+                }
+            },
+            0xFF41 => self.stat = (self.stat & 0b00000111) | (data & 0b01111000), // mode and coincidence are read-only
             0xFF42 => self.scy = data,
             0xFF43 => self.scx = data,
             0xFF44 => (),
-            0xFF45 => self.lyc = data,
+            0xFF45 => {
+                self.lyc = data;
+                if self.lcdc & 0b10000000 == 0 {
+                    self.stop();
+                }
+            },
             0xFF47 => self.bgp = data,
-            0xFF4A => self.wy = data, //TODO: Check if register can be set above value 143
-            0xFF4B => self.wx = data, //TODO: Check if register can be set above value 166
-            _ => todo!("write {:#04X} to {:#06X}", data, addr)
+            0xFF48 => self.obp0 = data,
+            0xFF49 => self.obp1 = data,
+            0xFF4F if self.mode == SystemMode::GameboyColorGBC => self.vbk = data & 0b1,
+            0xFF4F => (),
+            0xFF68..=0xFF6B if !self.mode.is_cgb_hardware() => (),
+            0xFF68 => self.bcps = data & 0b10111111,
+            0xFF69 => {
+                let blocked = self.palette_ram_blocked();
+                Self::write_palette_data(&mut self.bg_palette_ram, &mut self.bcps, data, blocked);
+            },
+            0xFF6A => self.ocps = data & 0b10111111,
+            0xFF6B => {
+                let blocked = self.palette_ram_blocked();
+                Self::write_palette_data(&mut self.obj_palette_ram, &mut self.ocps, data, blocked);
+            },
+            0xFF4A => self.wy = data, // Any value is kept, above 143 the window just never triggers
+            0xFF4B => self.wx = data, // Any value is kept, above 166 the window is off screen
+            _ => (), // Not a PPU register
         }
         
         match addr {
             0x8000..=0x9FFF => {
-                info!("Wrote to VRAM: {:02X} ({:02X}) at {:04X}", data, self.vram[(addr & 0x1FFF) as usize], addr);
+                trace!("Wrote to VRAM{}: {:02X} at {:04X}", self.vbk, data, addr);
             },
             _ => ()
         }
@@ -151,17 +933,502 @@ impl BusAccessable for Ppu {
 
     fn read(&mut self, addr: u16) -> u8 {
         match addr {
+            0x8000..=0x9FFF if self.vram_blocked() => 0xFF,
+            0x8000..=0x9FFF if self.vbk & 0b1 != 0 => self.vram1[(addr & 0x1FFF) as usize],
             0x8000..=0x9FFF => self.vram[(addr & 0x1FFF) as usize],
+            0xFE00..=0xFEFF if self.oam_blocked() => 0xFF,
+            0xFE00..=0xFE9F => self.oam[(addr - 0xFE00) as usize],
+            0xFEA0..=0xFEFF => 0x00, // Prohibited
             0xFF40 => self.lcdc,
-            0xFF41 => self.stat,
+            0xFF41 => self.stat | 0b10000000, // bit 7 is unused and reads as 1
             0xFF42 => self.scy,
             0xFF43 => self.scx,
-            0xFF44 => self.ly,
+            0xFF44 => self.read_ly(),
             0xFF45 => self.lyc,
             0xFF47 => self.bgp,
+            0xFF48 => self.obp0,
+            0xFF49 => self.obp1,
+            0xFF4F if self.mode == SystemMode::GameboyColorGBC => self.vbk | 0b11111110, // only bit 0 is used
+            0xFF4F => 0xFF,
+            0xFF68..=0xFF6B if !self.mode.is_cgb_hardware() => 0xFF,
+            0xFF68 => self.bcps | 0b01000000, // bit 6 is unused and reads as 1
+            0xFF69 | 0xFF6B if self.palette_ram_blocked() => 0xFF,
+            0xFF69 => self.bg_palette_ram[(self.bcps & 0x3F) as usize],
+            0xFF6A => self.ocps | 0b01000000,
+            0xFF6B => self.obj_palette_ram[(self.ocps & 0x3F) as usize],
             0xFF4A => self.wy,
             0xFF4B => self.wx,
-            _ => todo!("read from {:#06X}", addr)
+            _ => 0xFF, // Not a PPU register
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// A DMG PPU in mode 2, reading OAM row `row`, with rows 3-5 filled in.
+    fn scanning_ppu(mode: SystemMode, row: u16) -> Ppu {
+        let mut ppu = Ppu::new(mode);
+        ppu.lcdc = 0b10000000;
+        ppu.dot = row * 4;
+        ppu.stat = 2;
+        set_row(&mut ppu, 3, [0xAAAA, 0x8888, 0x9999, 0xBBBB]);
+        set_row(&mut ppu, 4, [0xF0F0, 0x1111, 0x0FF0, 0x2222]);
+        set_row(&mut ppu, 5, [0x3C3C, 0x5555, 0x6666, 0x7777]);
+        ppu
+    }
+    
+    fn set_row(ppu: &mut Ppu, row: usize, words: [u16; 4]) {
+        for (i, word) in words.into_iter().enumerate() {
+            ppu.set_oam_word(row, i, word);
+        }
+    }
+    
+    fn row(ppu: &Ppu, row: usize) -> [u16; 4] {
+        [0, 1, 2, 3].map(|i| ppu.oam_word(row, i))
+    }
+    
+    #[test]
+    fn oam_read_corruption() {
+        let mut ppu = scanning_ppu(SystemMode::Gameboy, 5);
+        ppu.corrupt_oam(OamCorruption::Read);
+        
+        // b | (a & c) = 0xF0F0 | (0x3C3C & 0x0FF0)
+        assert_eq!(row(&ppu, 5), [0xFCF0, 0x1111, 0x0FF0, 0x2222]);
+        assert_eq!(row(&ppu, 4), [0xF0F0, 0x1111, 0x0FF0, 0x2222]);
+        assert_eq!(row(&ppu, 3), [0xAAAA, 0x8888, 0x9999, 0xBBBB]);
+    }
+    
+    #[test]
+    fn oam_write_corruption() {
+        let mut ppu = scanning_ppu(SystemMode::Gameboy, 5);
+        ppu.corrupt_oam(OamCorruption::Write);
+        
+        // ((a ^ c) & (b ^ c)) ^ c = (0x33CC & 0xFF00) ^ 0x0FF0
+        assert_eq!(row(&ppu, 5), [0x3CF0, 0x1111, 0x0FF0, 0x2222]);
+        assert_eq!(row(&ppu, 4), [0xF0F0, 0x1111, 0x0FF0, 0x2222]);
+    }
+    
+    #[test]
+    fn oam_read_increment_corruption() {
+        let mut ppu = scanning_ppu(SystemMode::Gameboy, 5);
+        ppu.corrupt_oam(OamCorruption::ReadIncrement);
+        
+        // Row 4's first word becomes (b & (a | c | d)) | (a & c & d) = 0xB0F0 | 0x0820, and is copied
+        // over rows 3 and 5. The read corruption that follows leaves row 5 unchanged.
+        for i in 3..=5 {
+            assert_eq!(row(&ppu, i), [0xB8F0, 0x1111, 0x0FF0, 0x2222], "row {}", i);
+        }
+        assert_eq!(row(&ppu, 6), [0; 4]);
+        assert_eq!(row(&ppu, 2), [0; 4]);
+    }
+    
+    #[test]
+    fn oam_read_increment_near_start_is_a_read() {
+        let mut ppu = scanning_ppu(SystemMode::Gameboy, 3);
+        set_row(&mut ppu, 2, [0x0F0F, 0x1234, 0x00FF, 0x5678]);
+        ppu.corrupt_oam(OamCorruption::ReadIncrement);
+        
+        // b | (a & c) = 0x0F0F | (0xAAAA & 0x00FF)
+        assert_eq!(row(&ppu, 3), [0x0FAF, 0x1234, 0x00FF, 0x5678]);
+        assert_eq!(row(&ppu, 1), [0; 4]);
+    }
+    
+    #[test]
+    fn oam_corruption_only_during_dmg_oam_scan() {
+        let mut ppu = scanning_ppu(SystemMode::Gameboy, 0);
+        ppu.corrupt_oam(OamCorruption::Write);
+        assert_eq!(row(&ppu, 0), [0; 4]);
+        
+        let mut ppu = scanning_ppu(SystemMode::GameboyColorDMG, 5);
+        ppu.corrupt_oam(OamCorruption::Write);
+        assert_eq!(row(&ppu, 5), [0x3C3C, 0x5555, 0x6666, 0x7777]);
+        
+        let mut ppu = scanning_ppu(SystemMode::Gameboy, 5);
+        ppu.stat = 3;
+        ppu.corrupt_oam(OamCorruption::Write);
+        assert_eq!(row(&ppu, 5), [0x3C3C, 0x5555, 0x6666, 0x7777]);
+    }
+    
+    #[test]
+    fn ly_wraps_early_on_line_153() {
+        let mut ppu = Ppu::new(SystemMode::Gameboy);
+        ppu.lcdc = 0b10000000;
+        ppu.lyc = 0;
+        ppu.ly = 152;
+        ppu.dot = LINE_DOTS - 1;
+        ppu.tcycle();
+        
+        for dot in 0..LAST_LINE_LY_DOTS {
+            assert_eq!((ppu.ly, ppu.dot), (153, dot));
+            assert_eq!(ppu.read(0xFF44), 153, "dot {}", dot);
+            ppu.tcycle();
+            assert_eq!(ppu.stat & 0b100, 0, "LYC=0 matched at dot {}", dot);
+        }
+        while ppu.ly == 153 {
+            assert_eq!(ppu.read(0xFF44), 0, "dot {}", ppu.dot);
+            ppu.tcycle();
+            assert_ne!(ppu.stat & 0b100, 0, "LYC=0 didn't match at dot {}", ppu.dot);
+        }
+        assert_eq!((ppu.ly, ppu.dot, ppu.read(0xFF44)), (0, 0, 0));
+    }
+    
+    #[test]
+    fn mode_trace_over_one_frame() {
+        let mut ppu = Ppu::new(SystemMode::Gameboy);
+        ppu.lcdc = 0b10000000;
+        let mut transitions = vec![];
+        for _ in 0..FRAME_TCYCLES {
+            transitions.extend(ppu.tcycle().1);
+        }
+        
+        let transition = |from, to, ly, dot| ModeTransition { from, to, ly, dot };
+        let mut expected = vec![transition(0, 2, 0, 0)];
+        for ly in 0..VBLANK_LINE {
+            expected.push(transition(2, 3, ly, OAM_SCAN_DOTS));
+            expected.push(transition(3, 0, ly, OAM_SCAN_DOTS + DRAWING_DOTS));
+            expected.push(if ly + 1 < VBLANK_LINE { transition(0, 2, ly + 1, 0) } else { transition(0, 1, VBLANK_LINE, 0) });
         }
+        expected.push(transition(1, 2, 0, 0));
+        
+        assert_eq!(transitions, expected);
     }
-}
\ No newline at end of file
+    
+    #[test]
+    fn lcd_off_mid_line() {
+        let mut ppu = Ppu::new(SystemMode::Gameboy);
+        ppu.write(0xFF45, 50);
+        ppu.write(0xFF40, 0b10000000);
+        for _ in 0..((50 * LINE_DOTS as usize) + 200) {
+            ppu.tcycle();
+        }
+        assert_eq!((ppu.ly, ppu.dot, ppu.mode()), (50, 200, 3));
+        assert_ne!(ppu.read(0xFF41) & 0b100, 0);
+        
+        ppu.write(0xFF40, 0x00);
+        assert_eq!(ppu.read(0xFF44), 0);
+        assert_eq!(ppu.read(0xFF41) & 0b111, 0, "mode or a stale coincidence flag left in STAT");
+        assert_eq!(ppu.tcycle().1, Some(ModeTransition { from: 3, to: 0, ly: 0, dot: 0 }));
+        assert_eq!((ppu.read(0xFF44), ppu.dot, ppu.mode()), (0, 0, 0));
+        
+        // Turning it back on starts a new frame, with VBlank 144 lines later
+        ppu.write(0xFF40, 0b10000000);
+        assert_eq!(ppu.tcycle().0, Interrupt::empty());
+        assert_eq!(ppu.mode(), 2);
+        for _ in 1..(VBLANK_LINE as usize * LINE_DOTS as usize) {
+            assert!(!ppu.tcycle().0.contains(Interrupt::VBlank));
+        }
+        assert!(ppu.tcycle().0.contains(Interrupt::VBlank));
+    }
+    
+    #[test]
+    fn lcd_off_coincidence_follows_lyc() {
+        let mut ppu = Ppu::new(SystemMode::Gameboy);
+        ppu.write(0xFF45, 0);
+        assert_ne!(ppu.read(0xFF41) & 0b100, 0);
+        ppu.write(0xFF45, 1);
+        assert_eq!(ppu.read(0xFF41) & 0b100, 0);
+    }
+    
+    #[test]
+    fn tile_atlas_size() {
+        for (mode, banks) in [(SystemMode::Gameboy, 1), (SystemMode::GameboyColorGBC, 2)] {
+            let mut ppu = Ppu::new(mode);
+            ppu.bgp = 0b11100100;
+            // The last tile of the last bank is solid color 3
+            let last_bank = if banks == 2 { &mut ppu.vram1 } else { &mut ppu.vram };
+            last_bank[0x17F0..0x1800].fill(0xFF);
+            
+            assert_eq!(ppu.tiles().len(), 384 * banks, "{:?}", mode);
+            assert_eq!(ppu.tile_atlas_width(), TILE_ATLAS_WIDTH * banks);
+            let atlas = ppu.render_tile_atlas();
+            assert_eq!(atlas.len(), TILE_ATLAS_WIDTH * banks * TILE_ATLAS_HEIGHT);
+            
+            let width = ppu.tile_atlas_width();
+            let black = ppu.palette(3);
+            for y in 0..TILE_ATLAS_HEIGHT {
+                for x in 0..width {
+                    let last_tile = x >= width - 8 && y >= TILE_ATLAS_HEIGHT - 8;
+                    assert_eq!(atlas[(y * width) + x] == black, last_tile, "{:?} at {},{}", mode, x, y);
+                }
+            }
+        }
+    }
+    
+    #[test]
+    fn window_line_only_counts_drawn_lines() {
+        let mut ppu = Ppu::new(SystemMode::Gameboy);
+        ppu.wy = 0;
+        ppu.wx = 7;
+        
+        // The window is only enabled for even lines, so it's drawn on half as many lines as LY counts
+        for ly in 0..VBLANK_LINE {
+            ppu.lcdc = if ly % 2 == 0 { 0b10100001 } else { 0b10000001 };
+            for _ in 0..LINE_DOTS {
+                ppu.tcycle();
+            }
+            assert_eq!(ppu.window_line(), (ly / 2) + 1, "after LY {}", ly);
+        }
+        
+        for _ in (VBLANK_LINE as usize * LINE_DOTS as usize)..FRAME_TCYCLES {
+            ppu.tcycle();
+        }
+        assert_eq!((ppu.ly, ppu.window_line()), (0, 0));
+    }
+    
+    #[test]
+    fn read_only_register_bits() {
+        let mut ppu = Ppu::new(SystemMode::Gameboy);
+        ppu.write(0xFF40, 0b10000000);
+        for _ in 0..((10 * LINE_DOTS as usize) + 100) {
+            ppu.tcycle();
+        }
+        assert_eq!((ppu.read(0xFF44), ppu.read(0xFF41)), (10, 0x83));
+        
+        // LY is entirely read-only, STAT's mode and coincidence bits are, and LYC is fully writable
+        ppu.write(0xFF44, 0xFF);
+        assert_eq!(ppu.read(0xFF44), 10);
+        ppu.write(0xFF41, 0xFF);
+        assert_eq!(ppu.read(0xFF41), 0xFB);
+        ppu.write(0xFF45, 0xFF);
+        assert_eq!(ppu.read(0xFF45), 0xFF);
+        ppu.tcycle();
+        assert_eq!((ppu.read(0xFF44), ppu.read(0xFF41)), (10, 0xFB));
+    }
+    
+    #[test]
+    fn decode_logo_tile() {
+        let mut ppu = Ppu::new(SystemMode::Gameboy);
+        ppu.bgp = 0b11100100;
+        // The first tile of the Nintendo logo, as the boot ROM expands it from 0xCE 0xED in the header.
+        // Each nibble becomes two rows with every bit doubled, using only color 1.
+        ppu.vram[0x0010..0x0020].copy_from_slice(&[0xF0, 0x00, 0xF0, 0x00, 0xFC, 0x00, 0xFC, 0x00, 0xFC, 0x00, 0xFC, 0x00, 0xF3, 0x00, 0xF3, 0x00]);
+        // Then a tile using both bitplanes, for colors 0-3 from left to right
+        ppu.vram[0x0020..0x0030].copy_from_slice(&[0x33, 0x0F].repeat(8));
+        
+        let tiles = ppu.tiles();
+        let indices = |tile: &Tile| tile.pixels.map(|row| row.map(|pixel| GRAYSCALE_PALETTE.iter().position(|&color| color == pixel).unwrap()));
+        let logo = [[1, 1, 1, 1, 0, 0, 0, 0], [1, 1, 1, 1, 1, 1, 0, 0], [1, 1, 1, 1, 1, 1, 0, 0], [1, 1, 1, 1, 0, 0, 1, 1]];
+        assert_eq!(indices(&tiles[1]), [logo[0], logo[0], logo[1], logo[1], logo[2], logo[2], logo[3], logo[3]]);
+        assert_eq!(indices(&tiles[2]), [[0, 0, 1, 1, 2, 2, 3, 3]; 8]);
+    }
+    
+    #[test]
+    fn lcd_off_unblocks_vram_and_oam() {
+        let mut ppu = Ppu::new(SystemMode::Gameboy);
+        ppu.write(0xFF40, 0b10000000);
+        for _ in 0..(OAM_SCAN_DOTS + 10) {
+            ppu.tcycle();
+        }
+        assert_eq!(ppu.mode(), 3);
+        ppu.write(0x8000, 0x11);
+        ppu.write(0xFE00, 0x22);
+        assert_eq!((ppu.vram[0], ppu.oam[0]), (0x00, 0x00));
+        assert_eq!((ppu.read(0x8000), ppu.read(0xFE00)), (0xFF, 0xFF));
+        
+        // Even with STAT still holding mode 3, nothing is blocked once LCDC bit 7 is clear
+        ppu.lcdc = 0x00;
+        assert_eq!(ppu.mode(), 3);
+        ppu.write(0x8000, 0x11);
+        ppu.write(0xFE00, 0x22);
+        assert_eq!((ppu.read(0x8000), ppu.read(0xFE00)), (0x11, 0x22));
+        
+        ppu.write(0xFF40, 0b10000000);
+        for _ in 0..(OAM_SCAN_DOTS + 10) {
+            ppu.tcycle();
+        }
+        ppu.write(0xFF40, 0x00);
+        ppu.write(0x8001, 0x33);
+        ppu.write(0xFE01, 0x44);
+        assert_eq!((ppu.read(0x8001), ppu.read(0xFE01)), (0x33, 0x44));
+    }
+    
+    #[test]
+    fn sprite_priority_before_bg_priority() {
+        let mut ppu = Ppu::new(SystemMode::Gameboy);
+        ppu.lcdc = 0b10010011;
+        (ppu.bgp, ppu.obp0) = (0b11100100, 0b11100100);
+        // BG tile 1 is color 2 on its left half, and goes in the top-left corner
+        ppu.vram[0x0010..0x0020].copy_from_slice(&[0x00, 0xF0].repeat(8));
+        ppu.vram[0x1800] = 0x01;
+        // Sprite 0 is behind the BG, using tile 2 (color 1, apart from the last two columns). Sprite 1
+        // uses tile 3 (all color 3) at the same position, so it has lower priority.
+        ppu.vram[0x0020..0x0030].copy_from_slice(&[0xFC, 0x00].repeat(8));
+        ppu.vram[0x0030..0x0040].copy_from_slice(&[0xFF, 0xFF].repeat(8));
+        ppu.oam[0..8].copy_from_slice(&[16, 8, 0x02, 0b10000000, 16, 8, 0x03, 0x00]);
+        
+        let mut frame = Framebuffer::new();
+        ppu.render_immediate(&mut frame);
+        // Sprite 0 takes the pixels where it isn't transparent, so the BG covers both sprites there
+        // instead of showing sprite 1
+        let expected = [2, 2, 2, 2, 1, 1, 3, 3, 0];
+        for y in 0..8 {
+            for (x, &color) in expected.iter().enumerate() {
+                assert_eq!(frame.get(x, y), GRAYSCALE_PALETTE[color], "{},{}", x, y);
+            }
+        }
+    }
+    
+    #[test]
+    fn bg_and_window_maps_selected_independently() {
+        let mut ppu = Ppu::new(SystemMode::Gameboy);
+        ppu.bgp = 0b11100100;
+        (ppu.wy, ppu.wx) = (0, 7 + 80);
+        // The map at 0x9800 is all tile 1 (color 1), and the one at 0x9C00 all tile 2 (color 2)
+        ppu.vram[0x0010..0x0020].copy_from_slice(&[0xFF, 0x00].repeat(8));
+        ppu.vram[0x0020..0x0030].copy_from_slice(&[0x00, 0xFF].repeat(8));
+        ppu.vram[0x1800..0x1C00].fill(0x01);
+        ppu.vram[0x1C00..0x2000].fill(0x02);
+        
+        for (bg_bit, window_bit, bg_color, window_color) in [(0, 0, 1, 1), (1, 0, 2, 1), (0, 1, 1, 2), (1, 1, 2, 2)] {
+            ppu.lcdc = 0b10110001 | (bg_bit << 3) | (window_bit << 6);
+            assert_eq!(ppu.bg_map_addr(), if bg_bit == 1 { 0x9C00 } else { 0x9800 });
+            assert_eq!(ppu.window_map_addr(), if window_bit == 1 { 0x9C00 } else { 0x9800 });
+            
+            let mut frame = Framebuffer::new();
+            ppu.render_immediate(&mut frame);
+            assert_eq!(frame.get(79, 50), GRAYSCALE_PALETTE[bg_color], "LCDC {:08b}", ppu.lcdc);
+            assert_eq!(frame.get(80, 50), GRAYSCALE_PALETTE[window_color], "LCDC {:08b}", ppu.lcdc);
+        }
+    }
+    
+    #[test]
+    fn full_map_matches_render() {
+        let mut ppu = Ppu::new(SystemMode::Gameboy);
+        ppu.lcdc = 0b10010001;
+        ppu.bgp = 0b11100100;
+        (ppu.scx, ppu.scy) = (200, 180);
+        for (i, byte) in ppu.vram[..0x1000].iter_mut().enumerate() {
+            *byte = (i as u8).wrapping_mul(37) ^ (i >> 4) as u8;
+        }
+        for (i, tile) in ppu.vram[0x1800..0x1C00].iter_mut().enumerate() {
+            *tile = (i as u8).wrapping_mul(7);
+        }
+        
+        let mut full = vec![0; 256 * 256];
+        ppu.render_full_map(&mut full);
+        let mut frame = Framebuffer::new();
+        ppu.render_immediate(&mut frame);
+        
+        // The viewport wraps around the map, and everything inside its outline matches the screen
+        for y in 0..Framebuffer::HEIGHT {
+            for x in 0..Framebuffer::WIDTH {
+                let pixel = full[((ppu.scy as usize + y) % 256) * 256 + ((ppu.scx as usize + x) % 256)];
+                if x == 0 || y == 0 || x == Framebuffer::WIDTH - 1 || y == Framebuffer::HEIGHT - 1 {
+                    assert_eq!(pixel, VIEWPORT_COLOR, "outline at {},{}", x, y);
+                } else {
+                    assert_eq!(pixel, frame.get(x, y), "{},{}", x, y);
+                }
+            }
+        }
+    }
+    
+    #[test]
+    fn line_sprite_priority() {
+        // Sprites 0-2 overlap line 0, with 1 and 2 sharing an X left of sprite 0. Sprite 3 is on
+        // another line.
+        let oam = [16, 50, 0, 0, 16, 20, 0, 0, 16, 20, 0, 0, 40, 5, 0, 0];
+        let indices = |ppu: &Ppu| ppu.line_sprites(0).into_iter().map(|(i, _)| i).collect::<Vec<_>>();
+        
+        let mut ppu = Ppu::new(SystemMode::Gameboy);
+        ppu.oam[..16].copy_from_slice(&oam);
+        assert_eq!(indices(&ppu), [1, 2, 0]);
+        
+        // CGB mode only goes by OAM index
+        let mut ppu = Ppu::new(SystemMode::GameboyColorGBC);
+        ppu.oam[..16].copy_from_slice(&oam);
+        assert_eq!(indices(&ppu), [0, 1, 2]);
+        
+        // Only the first 10 in OAM are kept, before sorting
+        let mut ppu = Ppu::new(SystemMode::Gameboy);
+        for i in 0..12 {
+            ppu.oam[(i * 4)..((i * 4) + 2)].copy_from_slice(&[16, 100 - i as u8]);
+        }
+        assert_eq!(indices(&ppu), (0..10).rev().collect::<Vec<_>>());
+    }
+    
+    #[test]
+    fn window_covers_lower_right() {
+        let mut ppu = Ppu::new(SystemMode::Gameboy);
+        ppu.lcdc = 0b11110001;
+        ppu.bgp = 0b11100100;
+        (ppu.wy, ppu.wx) = (72, 7 + 80);
+        // The BG is all tile 1 (color 1). The window is all tile 2, which is color 2 on its first row
+        // and color 3 below, so it's only color 2 where the window starts on each tile row.
+        ppu.vram[0x0010..0x0020].copy_from_slice(&[0xFF, 0x00].repeat(8));
+        ppu.vram[0x0020..0x0022].copy_from_slice(&[0x00, 0xFF]);
+        ppu.vram[0x0022..0x0030].fill(0xFF);
+        ppu.vram[0x1800..0x1C00].fill(0x01);
+        ppu.vram[0x1C00..0x2000].fill(0x02);
+        
+        let mut frame = Framebuffer::new();
+        ppu.render_immediate(&mut frame);
+        for y in 0..Framebuffer::HEIGHT {
+            for x in 0..Framebuffer::WIDTH {
+                let color = match (x >= 80, y >= 72) {
+                    (true, true) if (y - 72) % 8 == 0 => 2,
+                    (true, true) => 3,
+                    _ => 1,
+                };
+                assert_eq!(frame.get(x, y), GRAYSCALE_PALETTE[color], "{},{}", x, y);
+            }
+        }
+    }
+    
+    #[test]
+    fn bg_tile_attributes_flip_and_bank() {
+        let mut ppu = Ppu::new(SystemMode::GameboyColorGBC);
+        ppu.lcdc = 0b10010001;
+        for (i, color) in [0x0000u16, 0x001F, 0x03E0, 0x7C00].iter().enumerate() {
+            ppu.bg_palette_ram[i * 2..(i * 2) + 2].copy_from_slice(&color.to_le_bytes());
+        }
+        // Tile 1 is color 1 everywhere in bank 0, but in bank 1 only its top-left pixel is set (color 3).
+        ppu.vram[0x0010..0x0020].copy_from_slice(&[0xFF, 0x00].repeat(8));
+        ppu.vram1[0x0010..0x0012].copy_from_slice(&[0x80, 0x80]);
+        ppu.vram[0x1800] = 0x01;
+        ppu.vram1[0x1800] = 0b00101000; // H-flip, bank 1
+        
+        let mut frame = Framebuffer::new();
+        ppu.render_immediate(&mut frame);
+        let colors = [0, 1, 2, 3].map(|color| Ppu::cgb_color(&ppu.bg_palette_ram, 0, color));
+        for y in 0..8 {
+            for x in 0..8 {
+                let color = if (x, y) == (7, 0) { 3 } else { 0 };
+                assert_eq!(frame.get(x, y), colors[color], "{},{}", x, y);
+            }
+        }
+    }
+    
+    #[test]
+    fn vram_banks_isolated() {
+        let mut ppu = Ppu::new(SystemMode::GameboyColorGBC);
+        ppu.write(0xFF4F, 0);
+        ppu.write(0x8000, 0x12);
+        ppu.write(0x9FFF, 0x34);
+        ppu.write(0xFF4F, 1);
+        assert_eq!(ppu.read(0xFF4F), 0xFF);
+        assert_eq!((ppu.read(0x8000), ppu.read(0x9FFF)), (0, 0));
+        ppu.write(0x8000, 0x56);
+        ppu.write(0x9FFF, 0x78);
+        
+        ppu.write(0xFF4F, 0);
+        assert_eq!(ppu.read(0xFF4F), 0xFE);
+        assert_eq!((ppu.read(0x8000), ppu.read(0x9FFF)), (0x12, 0x34));
+        ppu.write(0xFF4F, 1);
+        assert_eq!((ppu.read(0x8000), ppu.read(0x9FFF)), (0x56, 0x78));
+        assert_eq!((ppu.vram[0x0000], ppu.vram[0x1FFF]), (0x12, 0x34));
+        assert_eq!((ppu.vram1[0x0000], ppu.vram1[0x1FFF]), (0x56, 0x78));
+    }
+    
+    #[test]
+    fn stat_mode_at_boundary_dots() {
+        let mut ppu = Ppu::new(SystemMode::Gameboy);
+        ppu.lcdc = 0b10000000;
+        for (dot, mode) in [(79, 2), (80, 3), (251, 3), (252, 0)] {
+            while ppu.dot != dot {
+                ppu.tcycle();
+            }
+            assert_eq!(ppu.read(0xFF41) & 0b11, mode, "dot {}", dot);
+        }
+    }
+}
@@ -0,0 +1,296 @@
+use std::collections::VecDeque;
+use crate::arch::cpu::{CpuError, Interrupt};
+use crate::arch::ppu::FRAME_TCYCLES;
+use crate::arch::{Bus, BusAccessable, Gameboy, SystemMode};
+
+/// T-cycles per bit using the internal clock, for a rate of 8192 Hz.
+const BIT_TCYCLES: u16 = 512;
+/// T-cycles per bit using the CGB's fast internal clock (SC bit 1), for a rate of 262144 Hz.
+const FAST_BIT_TCYCLES: u16 = 16;
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Serial {
+    mode: SystemMode,
+    /// Serial Transfer Data (0xFF01) (R/W)
+    pub sb: u8,
+    /// Serial Transfer Control (0xFF02) (R/W). Bit 7 starts a transfer and stays set until it's done,
+    /// and bit 0 selects the internal clock.
+    pub sc: u8,
+    /// Bits left to shift in the current transfer.
+    bits_left: u8,
+    /// T-cycles until the next bit is shifted.
+    bit_timer: u16,
+    /// Bytes sent so far, until they're taken by [Serial::take_output()].
+    output: Vec<u8>,
+    /// Set while a [SerialLink] is driving this port, in which case bits are exchanged by the link
+    /// instead of being shifted in here.
+    connected: bool,
+    /// Set when the internal clock ticks while connected, until the link exchanges the bit.
+    clock_pulse: bool,
+}
+impl Serial {
+    pub fn new(mode: SystemMode) -> Self { Self {
+        mode,
+        sb: 0,
+        sc: 0,
+        bits_left: 0,
+        bit_timer: 0,
+        output: vec![],
+        connected: false,
+        clock_pulse: false,
+    }}
+    
+    fn bit_tcycles(&self) -> u16 {
+        if self.mode.is_cgb_hardware() && self.sc & 0b10 != 0 { FAST_BIT_TCYCLES } else { BIT_TCYCLES }
+    }
+    
+    /// Performs one t-cycle, returning true when a transfer finishes and the serial interrupt should
+    /// be requested.
+    /// 
+    /// Only transfers using the internal clock make progress here. With nothing connected, each bit
+    /// shifted in is 1, so a finished transfer leaves 0xFF in SB. Transfers using the external clock
+    /// stall until a partner clocks them.
+    pub fn tcycle(&mut self) -> bool {
+        if self.bits_left == 0 || self.sc & 0b1 == 0 {
+            return false;
+        }
+        
+        self.bit_timer -= 1;
+        if self.bit_timer > 0 {
+            return false;
+        }
+        self.bit_timer = self.bit_tcycles();
+        
+        if self.connected {
+            self.clock_pulse = true;
+            return false;
+        }
+        self.shift(0b1)
+    }
+    
+    /// Shifts `bit` into SB, returning true when the transfer finishes.
+    fn shift(&mut self, bit: u8) -> bool {
+        self.sb = (self.sb << 1) | bit;
+        self.bits_left -= 1;
+        if self.bits_left > 0 {
+            return false;
+        }
+        
+        self.sc &= !0b10000000;
+        true
+    }
+    
+    /// True while a transfer is waiting on the partner's clock.
+    fn awaiting_clock(&self) -> bool {
+        self.bits_left > 0 && self.sc & 0b1 == 0
+    }
+    
+    /// Takes the bytes sent since the last call.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+}
+
+impl BusAccessable for Serial {
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0xFF01 => self.sb = data,
+            0xFF02 => {
+                self.sc = data;
+                if data & 0b10000000 != 0 {
+                    self.output.push(self.sb);
+                    self.bits_left = 8;
+                    self.bit_timer = self.bit_tcycles();
+                } else {
+                    self.bits_left = 0;
+                }
+            },
+            _ => panic!("unreachable")
+        }
+    }
+    
+    fn read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0xFF01 => self.sb,
+            0xFF02 if self.mode.is_cgb_hardware() => self.sc | 0b01111100, // bits 2-6 are unused and read as 1
+            0xFF02 => self.sc | 0b01111110, // bits 1-6 are unused and read as 1
+            _ => panic!("unreachable")
+        }
+    }
+}
+
+/// Two Gameboys connected by a link cable, stepped in lockstep.
+/// 
+/// Whichever side starts a transfer with the internal clock drives it. Each clock pulse swaps the top
+/// bit of both SB registers, so after 8 pulses the two bytes have been exchanged. If the other side
+/// isn't waiting on the external clock, the pulse shifts in 1s as if nothing were connected.
+/// 
+/// The cable can be given a latency, which delays each exchange by that many t-cycles after the
+/// pulse that caused it.
+pub struct SerialLink {
+    pub left: Gameboy,
+    pub right: Gameboy,
+    /// T-cycles between a clock pulse and its bits being exchanged.
+    latency: u32,
+    /// Pulses waiting out the latency, oldest first, as the t-cycles left and whether the left side
+    /// sent it.
+    in_flight: VecDeque<(u32, bool)>,
+}
+impl SerialLink {
+    pub fn new(mut left: Gameboy, mut right: Gameboy) -> Self {
+        left.bus.serial.connected = true;
+        right.bus.serial.connected = true;
+        
+        Self {
+            left,
+            right,
+            latency: 0,
+            in_flight: VecDeque::new(),
+        }
+    }
+    
+    /// Sets the cable latency in t-cycles. Pulses already in flight keep their original delay.
+    pub fn set_latency(&mut self, tcycles: u32) {
+        self.latency = tcycles;
+    }
+    
+    pub fn latency(&self) -> u32 {
+        self.latency
+    }
+    
+    /// Disconnects the cable, returning both Gameboys. Any bits still in flight are lost.
+    pub fn disconnect(mut self) -> (Gameboy, Gameboy) {
+        self.left.bus.serial.connected = false;
+        self.right.bus.serial.connected = false;
+        
+        (self.left, self.right)
+    }
+    
+    /// Performs one t-cycle on both Gameboys, then exchanges any bits whose latency has passed.
+    pub fn tcycle(&mut self) -> Result<(), CpuError> {
+        self.left.tcycle()?;
+        self.right.tcycle()?;
+        
+        for (tcycles, _) in self.in_flight.iter_mut() {
+            *tcycles = tcycles.saturating_sub(1);
+        }
+        
+        let left = &mut self.left.bus;
+        let right = &mut self.right.bus;
+        if std::mem::take(&mut left.serial.clock_pulse) {
+            self.in_flight.push_back((self.latency, true));
+        }
+        if std::mem::take(&mut right.serial.clock_pulse) {
+            self.in_flight.push_back((self.latency, false));
+        }
+        while let Some(&(0, from_left)) = self.in_flight.front() {
+            self.in_flight.pop_front();
+            if from_left {
+                Self::exchange(left, right);
+            } else {
+                Self::exchange(right, left);
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Runs until the left Gameboy finishes drawing the current frame. See [Gameboy::run_frame()].
+    pub fn run_frame(&mut self) -> Result<(), CpuError> {
+        let frames = self.left.frame_count();
+        for _ in 0..FRAME_TCYCLES {
+            self.tcycle()?;
+            if self.left.frame_count() != frames {
+                break;
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Exchanges one bit for a clock pulse from `master`, requesting the serial interrupt on whichever
+    /// sides finish their transfer. If `master` cancelled its transfer while the pulse was in flight,
+    /// only the slave is clocked.
+    fn exchange(master: &mut Bus, slave: &mut Bus) {
+        let master_bit = master.serial.sb >> 7;
+        let slave_bit = if slave.serial.awaiting_clock() {
+            let bit = slave.serial.sb >> 7;
+            if slave.serial.shift(master_bit) {
+                slave.request_interrupt(Interrupt::Serial);
+            }
+            bit
+        } else {
+            0b1
+        };
+        
+        if master.serial.bits_left > 0 && master.serial.shift(slave_bit) {
+            master.request_interrupt(Interrupt::Serial);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// A DMG Gameboy past the boot ROM, looping in place.
+    fn gameboy() -> Gameboy {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+        
+        let mut gb = Gameboy::from_rom(rom);
+        gb.skip_bootrom();
+        gb.bus.interrupt_flags = 0;
+        gb
+    }
+    
+    /// Starts a transfer of `data`, using the internal clock if `master` is set.
+    fn start(gb: &mut Gameboy, data: u8, master: bool) {
+        gb.bus.serial.write(0xFF01, data);
+        gb.bus.serial.write(0xFF02, 0x80 | (master as u8));
+    }
+    
+    #[test]
+    fn link_exchanges_bytes_both_ways() {
+        let mut link = SerialLink::new(gameboy(), gameboy());
+        
+        // The left side drives the first transfer, the right side the second
+        for (left_byte, right_byte, left_master) in [(0x12, 0x34, true), (0xA5, 0x3C, false)] {
+            start(&mut link.right, right_byte, !left_master);
+            start(&mut link.left, left_byte, left_master);
+            for _ in 0..(BIT_TCYCLES as u32 * 8) {
+                link.tcycle().unwrap();
+            }
+            
+            for (gb, byte) in [(&mut link.left, right_byte), (&mut link.right, left_byte)] {
+                assert_eq!(gb.bus.serial.sb, byte);
+                assert_eq!(gb.bus.serial.sc & 0x80, 0);
+                assert_eq!(gb.bus.interrupt_flags & Interrupt::Serial.bits(), Interrupt::Serial.bits());
+                gb.bus.interrupt_flags = 0;
+            }
+        }
+    }
+    
+    #[test]
+    fn latency_delays_each_bit() {
+        for latency in [0, 100, 1000] {
+            let mut link = SerialLink::new(gameboy(), gameboy());
+            link.set_latency(latency);
+            start(&mut link.right, 0x34, false);
+            start(&mut link.left, 0x12, true);
+            
+            // The last bit is exchanged `latency` t-cycles after the left side's 8th pulse
+            for _ in 0..(BIT_TCYCLES as u32 * 8) + latency - 1 {
+                link.tcycle().unwrap();
+            }
+            assert_eq!(link.left.bus.serial.sc & 0x80, 0x80, "latency {}", latency);
+            assert_eq!(link.right.bus.serial.sc & 0x80, 0x80, "latency {}", latency);
+            
+            link.tcycle().unwrap();
+            assert_eq!((link.left.bus.serial.sb, link.right.bus.serial.sb), (0x34, 0x12), "latency {}", latency);
+            assert_eq!(link.left.bus.serial.sc & 0x80, 0, "latency {}", latency);
+            assert_eq!(link.right.bus.serial.sc & 0x80, 0, "latency {}", latency);
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use crate::arch::BusAccessable;
+
+/// T-cycles between TIMA overflowing and TMA being reloaded into it. TIMA reads 0x00 in between.
+const RELOAD_DELAY: u8 = 4;
+/// Counter bit (DIV bit 4) whose falling edge clocks the APU's frame sequencer, for a rate of 512 Hz.
+const DIV_APU_BIT: u16 = 1 << 12;
+/// Like [DIV_APU_BIT], but used in double speed (DIV bit 5) so the rate stays the same.
+const DIV_APU_BIT_DOUBLE: u16 = 1 << 13;
+
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Timer {
+    /// Internal 16-bit counter, incremented every t-cycle. DIV (0xFF04) is the upper 8 bits.
+    pub counter: u16,
+    /// Timer Counter (0xFF05) (R/W)
+    pub tima: u8,
+    /// Timer Modulo (0xFF06) (R/W)
+    pub tma: u8,
+    /// Timer Control (0xFF07) (R/W)
+    pub tac: u8,
+    /// T-cycles left until an overflowed TIMA is reloaded, or 0 if no reload is pending.
+    reload_delay: u8,
+    /// Set when [DIV_APU_BIT] falls, until taken by [Timer::take_div_apu()].
+    div_apu: bool,
+    /// Set when [DIV_APU_BIT_DOUBLE] falls, until taken by [Timer::take_div_apu()]. Always taken in
+    /// the same t-cycle, so it isn't saved.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    div_apu_double: bool,
+    /// Set when DIV is written, until the rest of that t-cycle is performed. Never set between t-cycles,
+    /// so it isn't saved.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    div_written: bool,
+}
+impl Timer {
+    pub fn new() -> Self { Self {
+        counter: 0,
+        tima: 0,
+        tma: 0,
+        tac: 0,
+        reload_delay: 0,
+        div_apu: false,
+        div_apu_double: false,
+        div_written: false,
+    }}
+    
+    /// Counter bit whose falling edge increments TIMA, selected by TAC bits 0-1.
+    /// 
+    /// 00: 4096 Hz, 01: 262144 Hz, 10: 65536 Hz, 11: 16384 Hz
+    fn selected_bit(&self) -> u16 {
+        match self.tac & 0b11 {
+            0 => 1 << 9,
+            1 => 1 << 3,
+            2 => 1 << 5,
+            3 => 1 << 7,
+            _ => panic!("unreachable")
+        }
+    }
+    
+    /// Signal watched for falling edges to increment TIMA: the selected counter bit, ANDed with the
+    /// enable bit (TAC bit 2).
+    fn timer_input(&self) -> bool {
+        self.tac & 0b100 != 0 && self.counter & self.selected_bit() != 0
+    }
+    
+    fn increment_tima(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        if overflow {
+            self.reload_delay = RELOAD_DELAY;
+        }
+    }
+    
+    /// Sets the internal counter, incrementing TIMA and clocking the APU if their bits fall.
+    /// 
+    /// This is how the counter normally advances, but also applies when DIV is written. Resetting the
+    /// counter while the selected bit is set causes an extra TIMA increment.
+    fn set_counter(&mut self, counter: u16) {
+        let input = self.timer_input();
+        let falling = self.counter & !counter;
+        
+        self.counter = counter;
+        if input && !self.timer_input() {
+            self.increment_tima();
+        }
+        if falling & DIV_APU_BIT != 0 {
+            self.div_apu = true;
+        }
+        if falling & DIV_APU_BIT_DOUBLE != 0 {
+            self.div_apu_double = true;
+        }
+    }
+    
+    /// Performs one t-cycle, returning true when the timer interrupt should be requested.
+    /// 
+    /// CPU accesses happen before this in the same t-cycle. A read of DIV sees the counter before it's
+    /// incremented, and a write resets it in place of the increment, so the counter reads 0 for that
+    /// whole t-cycle and a falling edge from the reset increments TIMA only once.
+    pub fn tcycle(&mut self) -> bool {
+        let mut interrupt = false;
+        if self.reload_delay > 0 {
+            self.reload_delay -= 1;
+            if self.reload_delay == 0 {
+                self.tima = self.tma;
+                interrupt = true;
+            }
+        }
+        
+        if !std::mem::take(&mut self.div_written) {
+            self.set_counter(self.counter.wrapping_add(1));
+        }
+        
+        interrupt
+    }
+    
+    /// Returns true once for each time DIV bit 4 (bit 5 in double speed) has fallen, which clocks the
+    /// APU's frame sequencer.
+    pub fn take_div_apu(&mut self, double_speed: bool) -> bool {
+        let normal = std::mem::take(&mut self.div_apu);
+        let double = std::mem::take(&mut self.div_apu_double);
+        if double_speed { double } else { normal }
+    }
+}
+
+impl BusAccessable for Timer {
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0xFF04 => {
+                self.set_counter(0);
+                self.div_written = true;
+            },
+            0xFF05 => {
+                // Writing during the reload delay cancels the reload (and its interrupt)
+                self.reload_delay = 0;
+                self.tima = data;
+            },
+            0xFF06 => self.tma = data,
+            0xFF07 => {
+                // Like a DIV write, disabling the timer or selecting another bit can make the input fall
+                let input = self.timer_input();
+                self.tac = data & 0b111;
+                if input && !self.timer_input() {
+                    self.increment_tima();
+                }
+            },
+            _ => panic!("unreachable")
+        }
+    }
+    
+    fn read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0xFF04 => (self.counter >> 8) as u8,
+            0xFF05 => self.tima,
+            0xFF06 => self.tma,
+            0xFF07 => self.tac | 0b11111000, // upper 5 bits are unused and read as 1
+            _ => panic!("unreachable")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// Timer counting on bit 3 (262144 Hz), one t-cycle before that bit falls.
+    fn timer_before_fall() -> Timer {
+        let mut timer = Timer::new();
+        timer.write(0xFF07, 0b101);
+        timer.counter = 0x0F;
+        timer
+    }
+    
+    #[test]
+    fn div_write_on_falling_cycle_increments_tima_once() {
+        let mut timer = timer_before_fall();
+        timer.write(0xFF04, 0x00);
+        timer.tcycle();
+        
+        assert_eq!(timer.tima, 1);
+        assert_eq!(timer.read(0xFF04), 0);
+        assert_eq!(timer.counter, 0);
+        
+        // The increment was replaced by the reset, so the bit next falls a full period later
+        for _ in 0..15 {
+            timer.tcycle();
+        }
+        assert_eq!(timer.tima, 1);
+        timer.tcycle();
+        assert_eq!(timer.tima, 2);
+    }
+    
+    #[test]
+    fn falling_cycle_without_div_write() {
+        let mut timer = timer_before_fall();
+        timer.tcycle();
+        assert_eq!(timer.tima, 1);
+        assert_eq!(timer.counter, 0x10);
+    }
+    
+    #[test]
+    fn div_read_sees_counter_before_increment() {
+        let mut timer = Timer::new();
+        timer.counter = 0x01FF;
+        assert_eq!(timer.read(0xFF04), 0x01);
+        timer.tcycle();
+        assert_eq!(timer.read(0xFF04), 0x02);
+    }
+    
+    #[test]
+    fn div_apu_bit_follows_speed() {
+        // Bit 12 falls every 0x2000 t-cycles and bit 13 every 0x4000, for 512 Hz at either speed
+        for (double_speed, period) in [(false, 0x2000), (true, 0x4000)] {
+            let mut timer = Timer::new();
+            let mut clocks = vec![];
+            for tcycle in 1..=0x8000 {
+                timer.tcycle();
+                if timer.take_div_apu(double_speed) {
+                    clocks.push(tcycle);
+                }
+            }
+            assert_eq!(clocks, (1..=(0x8000 / period)).map(|i| i * period).collect::<Vec<_>>());
+        }
+    }
+}
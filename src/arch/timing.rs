@@ -0,0 +1,102 @@
+use std::fmt::{Display, Formatter};
+use crate::arch::cpu::{AccessKind, Cpu, ProcedureSource, ACCESS_TCYCLE};
+
+/// Bus accesses made by each unprefixed opcode on hardware, one character per m-cycle: `R` for a
+/// read (including opcode and operand fetches), `W` for a write, and `-` for neither. Conditional
+/// instructions have a `|` where they end if the condition isn't met.
+/// 
+/// Empty for STOP, the 0xCB prefix, and the illegal opcodes, which aren't checked.
+const TIMINGS: [&str; 256] = [
+    "R",       "RRR",  "RW",     "R-",    "R",        "R",     "RR",   "R",     "RRRWW",   "R-",    "RR",     "R-",  "R",        "R",       "RR",  "R",
+    "",        "RRR",  "RW",     "R-",    "R",        "R",     "RR",   "R",     "RR-",     "R-",    "RR",     "R-",  "R",        "R",       "RR",  "R",
+    "RR|-",    "RRR",  "RW",     "R-",    "R",        "R",     "RR",   "R",     "RR|-",    "R-",    "RR",     "R-",  "R",        "R",       "RR",  "R",
+    "RR|-",    "RRR",  "RW",     "R-",    "RRW",      "RRW",   "RRW",  "R",     "RR|-",    "R-",    "RR",     "R-",  "R",        "R",       "RR",  "R",
+    "R",       "R",    "R",      "R",     "R",        "R",     "RR",   "R",     "R",       "R",     "R",      "R",   "R",        "R",       "RR",  "R",
+    "R",       "R",    "R",      "R",     "R",        "R",     "RR",   "R",     "R",       "R",     "R",      "R",   "R",        "R",       "RR",  "R",
+    "R",       "R",    "R",      "R",     "R",        "R",     "RR",   "R",     "R",       "R",     "R",      "R",   "R",        "R",       "RR",  "R",
+    "RW",      "RW",   "RW",     "RW",    "RW",       "RW",    "R",    "RW",    "R",       "R",     "R",      "R",   "R",        "R",       "RR",  "R",
+    "R",       "R",    "R",      "R",     "R",        "R",     "RR",   "R",     "R",       "R",     "R",      "R",   "R",        "R",       "RR",  "R",
+    "R",       "R",    "R",      "R",     "R",        "R",     "RR",   "R",     "R",       "R",     "R",      "R",   "R",        "R",       "RR",  "R",
+    "R",       "R",    "R",      "R",     "R",        "R",     "RR",   "R",     "R",       "R",     "R",      "R",   "R",        "R",       "RR",  "R",
+    "R",       "R",    "R",      "R",     "R",        "R",     "RR",   "R",     "R",       "R",     "R",      "R",   "R",        "R",       "RR",  "R",
+    "R-|RR-",  "RRR",  "RRR|-",  "RRR-",  "RRR|-WW",  "R-WW",  "RR",   "R-WW",  "R-|RR-",  "RRR-",  "RRR|-",  "",    "RRR|-WW",  "RRR-WW",  "RR",  "R-WW",
+    "R-|RR-",  "RRR",  "RRR|-",  "",      "RRR|-WW",  "R-WW",  "RR",   "R-WW",  "R-|RR-",  "RRR-",  "RRR|-",  "",    "RRR|-WW",  "",        "RR",  "R-WW",
+    "RRW",     "RRR",  "RW",     "",      "",         "R-WW",  "RR",   "R-WW",  "RR--",    "R",     "RRRW",   "",    "",         "",        "RR",  "R-WW",
+    "RRR",     "RRR",  "RR",     "R",     "",         "R-WW",  "RR",   "R-WW",  "RR-",     "R-",    "RRRR",   "R",   "",         "",        "RR",  "R-WW",
+];
+
+/// Reference accesses for an instruction, in the same format as [TIMINGS].
+pub fn expected(source: ProcedureSource, opcode: u8) -> &'static str {
+    match source {
+        ProcedureSource::Opcode => TIMINGS[opcode as usize],
+        // The prefix and the opcode are fetched on separate m-cycles, then (HL) is read and written back
+        ProcedureSource::Prefixed if opcode & 0b111 != 6 => "RR",
+        ProcedureSource::Prefixed if opcode >> 6 == 1 => "RRR",
+        ProcedureSource::Prefixed => "RRRW",
+        ProcedureSource::Interrupt => "--WW-",
+    }
+}
+
+/// Expands a reference into the kind and t-cycle of each access, as made by the CPU. Conditional
+/// instructions give both the accesses made when the condition fails and when it passes.
+fn expand(reference: &str) -> Vec<Vec<(AccessKind, u8)>> {
+    let accesses = |cycles: &str| cycles.chars().filter(|&c| c != '|').enumerate().filter_map(|(i, c)| {
+        let tcycle = ((i as u8) * 4) + ACCESS_TCYCLE;
+        match c {
+            'R' => Some((AccessKind::Read, tcycle)),
+            'W' => Some((AccessKind::Write, tcycle)),
+            _ => None,
+        }
+    }).collect::<Vec<_>>();
+    
+    match reference.split_once('|') {
+        Some((failed, _)) => vec![accesses(failed), accesses(reference)],
+        None => vec![accesses(reference)],
+    }
+}
+
+/// An instruction whose bus accesses didn't match the reference, from [check()].
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimingMismatch {
+    pub source: ProcedureSource,
+    pub opcode: u8,
+    pub expected: &'static str,
+    /// Kind and t-cycle of each access actually made.
+    pub actual: Vec<(AccessKind, u8)>,
+}
+impl Display for TimingMismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.source {
+            ProcedureSource::Opcode => write!(f, "opcode {:02X}", self.opcode)?,
+            ProcedureSource::Prefixed => write!(f, "opcode CB {:02X}", self.opcode)?,
+            ProcedureSource::Interrupt => write!(f, "interrupt dispatch")?,
+        }
+        write!(f, " accessed the bus at t-cycles [")?;
+        for (i, (kind, tcycle)) in self.actual.iter().enumerate() {
+            let kind = if *kind == AccessKind::Read { 'R' } else { 'W' };
+            write!(f, "{}{}@{}", if i == 0 { "" } else { ", " }, kind, tcycle)?;
+        }
+        
+        write!(f, "], expected \"{}\" (one character per m-cycle)", self.expected)
+    }
+}
+
+/// Checks the accesses from [Cpu::last_access_log()] against the reference for the instruction that
+/// made them. Instructions without a reference, or with nothing logged yet, always pass.
+pub fn check(cpu: &Cpu) -> Result<(), TimingMismatch> {
+    let (source, opcode) = match cpu.last_logged_instruction() {
+        Some(instruction) => instruction,
+        None => return Ok(()),
+    };
+    let expected = expected(source, opcode);
+    if expected.is_empty() {
+        return Ok(());
+    }
+    
+    let actual: Vec<(AccessKind, u8)> = cpu.last_access_log().iter().map(|&(_, kind, tcycle)| (kind, tcycle)).collect();
+    if expand(expected).contains(&actual) {
+        Ok(())
+    } else {
+        Err(TimingMismatch { source, opcode, expected, actual })
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use crate::arch::apu::SAMPLE_RATE;
+use crate::arch::cpu::CpuError;
+use crate::arch::Gameboy;
+
+/// Amount of audio buffered ahead of the device (50ms, in left/right pairs) before emulation waits
+/// for it to catch up. This keeps the frame loop in sync with the audio clock.
+const TARGET_BUFFERED: usize = (SAMPLE_RATE as usize) / 20 * 2;
+
+/// Something which plays the APU's samples, such as [AudioOutput].
+pub trait AudioSink {
+    /// Number of interleaved left/right samples the sink is ready to queue, or 0 once it has
+    /// buffered enough.
+    fn requested(&self) -> usize;
+    
+    /// Queues interleaved left/right samples to be played.
+    fn push(&mut self, samples: &[f32]);
+}
+
+/// Runs the system just long enough to produce the samples `sink` has requested (up to the size of
+/// `buf`), and passes them on. This lets the audio device's clock pace emulation, instead of a frame
+/// timer. Returns the number of samples pushed, which is 0 if nothing was requested.
+pub fn run_audio_synced<S: AudioSink>(gb: &mut Gameboy, sink: &mut S, buf: &mut [f32]) -> Result<usize, CpuError> {
+    let requested = sink.requested().min(buf.len()) & !0b1;
+    if requested == 0 {
+        return Ok(0);
+    }
+    
+    gb.run_audio(requested)?;
+    let len = gb.drain_audio(&mut buf[..requested]);
+    sink.push(&buf[..len]);
+    
+    Ok(len)
+}
+
+/// Plays interleaved stereo samples from the APU on the host's default output device.
+pub struct AudioOutput {
+    #[cfg(feature = "audio")]
+    _stream: cpal::Stream,
+    queue: Arc<Mutex<VecDeque<f32>>>,
+}
+impl AudioOutput {
+    /// Opens the default output device at [SAMPLE_RATE], logging why and returning None if it can't be.
+    #[cfg(feature = "audio")]
+    pub fn open() -> Option<Self> {
+        use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+        use log::error;
+        
+        let device = match cpal::default_host().default_output_device() {
+            Some(device) => device,
+            None => {
+                error!("No audio output device available");
+                return None;
+            }
+        };
+        let config = cpal::StreamConfig {
+            channels: 2,
+            sample_rate: cpal::SampleRate(SAMPLE_RATE),
+            buffer_size: cpal::BufferSize::Default,
+        };
+        
+        let queue = Arc::new(Mutex::new(VecDeque::with_capacity(TARGET_BUFFERED * 2)));
+        let stream_queue = queue.clone();
+        let stream = device.build_output_stream(
+            &config,
+            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                let mut queue = stream_queue.lock().unwrap();
+                for sample in data.iter_mut() {
+                    *sample = queue.pop_front().unwrap_or(0.0); // underruns play silence
+                }
+            },
+            |err| error!("Audio stream error: {}", err),
+            None,
+        );
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                error!("Failed to open audio stream: {}", err);
+                return None;
+            }
+        };
+        if let Err(err) = stream.play() {
+            error!("Failed to start audio stream: {}", err);
+            return None;
+        }
+        
+        Some(Self {
+            _stream: stream,
+            queue,
+        })
+    }
+    
+    #[cfg(not(feature = "audio"))]
+    pub fn open() -> Option<Self> {
+        log::warn!("Built without the 'audio' feature, sound is disabled");
+        None
+    }
+}
+impl AudioSink for AudioOutput {
+    /// Enough to keep [TARGET_BUFFERED] samples queued ahead of the device.
+    fn requested(&self) -> usize {
+        TARGET_BUFFERED.saturating_sub(self.queue.lock().unwrap().len())
+    }
+    
+    fn push(&mut self, samples: &[f32]) {
+        self.queue.lock().unwrap().extend(samples);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// Requests a fixed sequence of buffer sizes, recording what it's given.
+    struct MockSink {
+        requests: VecDeque<usize>,
+        pushed: Vec<usize>,
+    }
+    impl AudioSink for MockSink {
+        fn requested(&self) -> usize {
+            self.requests.front().copied().unwrap_or(0)
+        }
+        
+        fn push(&mut self, samples: &[f32]) {
+            self.requests.pop_front();
+            self.pushed.push(samples.len());
+        }
+    }
+    
+    #[test]
+    fn runs_exactly_enough_to_fill_each_request() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]); // JR -2
+        let mut gb = Gameboy::from_rom(rom);
+        gb.skip_bootrom();
+        let requests = [1600, 2, 800, 4096];
+        let mut sink = MockSink { requests: requests.into(), pushed: vec![] };
+        let mut buf = vec![0f32; 4096];
+        
+        let mut pairs = 0;
+        for requested in requests {
+            assert_eq!(run_audio_synced(&mut gb, &mut sink, &mut buf).unwrap(), requested);
+            
+            // A pair is produced on each t-cycle that the sample timer passes the clock rate
+            pairs += (requested / 2) as u64;
+            let tcycles = (pairs * 4194304).div_ceil(SAMPLE_RATE as u64);
+            assert_eq!(gb.tcycles as u64, tcycles);
+        }
+        assert_eq!(sink.pushed, requests);
+        assert_eq!(run_audio_synced(&mut gb, &mut sink, &mut buf).unwrap(), 0);
+    }
+}
@@ -0,0 +1,246 @@
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use crate::arch::cpu::CpuError;
+use crate::arch::Gameboy;
+
+/// CPU state at an instruction boundary, in the same shape as a gameboy-doctor style trace line:
+/// 
+/// `A: 01 F: B0 B: 00 C: 13 D: 00 E: D8 H: 01 L: 4D SP: FFFE PC: 00:0100 (00 C3 13 02)`
+/// 
+/// Lines may also give timing state after the registers, as `CY:` (t-cycles since the first line,
+/// in decimal), `DIV:`, `TIMA:`, and `LY:`. These are only compared when the reference has them.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TraceState {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    pub cycles: Option<u64>,
+    pub div: Option<u8>,
+    pub tima: Option<u8>,
+    pub ly: Option<u8>,
+}
+impl TraceState {
+    /// Captures the system's current state, with `cycles` counted from the first line of the trace.
+    pub fn from_gameboy(gb: &Gameboy, cycles: u64) -> Self {
+        let cpu = &gb.cpu;
+        let bus = &gb.bus;
+        Self {
+            a: cpu.regs.a,
+            f: cpu.regs.f.bits(),
+            b: cpu.regs.b,
+            c: cpu.regs.c,
+            d: cpu.regs.d,
+            e: cpu.regs.e,
+            h: cpu.regs.h,
+            l: cpu.regs.l,
+            sp: cpu.regs.sp,
+            pc: cpu.regs.pc,
+            cycles: Some(cycles),
+            div: Some((bus.timer.counter >> 8) as u8),
+            tima: Some(bus.timer.tima),
+            ly: Some(bus.ppu.ly),
+        }
+    }
+    
+    /// Parses a single trace line. Any trailing memory bytes after the PC are ignored.
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut state = Self { a: 0, f: 0, b: 0, c: 0, d: 0, e: 0, h: 0, l: 0, sp: 0, pc: 0, cycles: None, div: None, tima: None, ly: None };
+        let mut found = 0;
+        
+        let mut tokens = line.split_whitespace();
+        while let Some(name) = tokens.next() {
+            let value = match name {
+                "A:" | "F:" | "B:" | "C:" | "D:" | "E:" | "H:" | "L:" | "SP:" | "PC:" => tokens.next()?,
+                "CY:" => {
+                    state.cycles = Some(tokens.next()?.parse().ok()?);
+                    continue;
+                },
+                "DIV:" | "TIMA:" | "LY:" => {
+                    let value = Some(u8::from_str_radix(tokens.next()?, 16).ok()?);
+                    match name {
+                        "DIV:" => state.div = value,
+                        "TIMA:" => state.tima = value,
+                        "LY:" => state.ly = value,
+                        _ => panic!("unreachable")
+                    }
+                    continue;
+                },
+                _ => continue,
+            };
+            // PC may be prefixed with a bank number, e.g. `00:0100`
+            let value = u16::from_str_radix(value.rsplit(':').next()?, 16).ok()?;
+            
+            match name {
+                "A:" => state.a = value as u8,
+                "F:" => state.f = value as u8,
+                "B:" => state.b = value as u8,
+                "C:" => state.c = value as u8,
+                "D:" => state.d = value as u8,
+                "E:" => state.e = value as u8,
+                "H:" => state.h = value as u8,
+                "L:" => state.l = value as u8,
+                "SP:" => state.sp = value,
+                "PC:" => state.pc = value,
+                _ => panic!("unreachable")
+            }
+            found += 1;
+        }
+        
+        if found == 10 { Some(state) } else { None }
+    }
+    
+    /// Returns the name, expected value, and actual value of the first field that differs. Timing
+    /// fields missing from this state aren't compared.
+    pub fn first_mismatch(&self, actual: &Self) -> Option<(&'static str, u64, u64)> {
+        let fields = [
+            ("PC", Some(self.pc as u64), actual.pc as u64),
+            ("SP", Some(self.sp as u64), actual.sp as u64),
+            ("A", Some(self.a as u64), actual.a as u64),
+            ("F", Some(self.f as u64), actual.f as u64),
+            ("B", Some(self.b as u64), actual.b as u64),
+            ("C", Some(self.c as u64), actual.c as u64),
+            ("D", Some(self.d as u64), actual.d as u64),
+            ("E", Some(self.e as u64), actual.e as u64),
+            ("H", Some(self.h as u64), actual.h as u64),
+            ("L", Some(self.l as u64), actual.l as u64),
+            ("CY", self.cycles, actual.cycles.unwrap_or(0)),
+            ("DIV", self.div.map(u64::from), actual.div.unwrap_or(0) as u64),
+            ("TIMA", self.tima.map(u64::from), actual.tima.unwrap_or(0) as u64),
+            ("LY", self.ly.map(u64::from), actual.ly.unwrap_or(0) as u64),
+        ];
+        
+        fields.into_iter()
+            .filter_map(|(name, expected, actual)| expected.map(|expected| (name, expected, actual)))
+            .find(|(_, expected, actual)| expected != actual)
+    }
+}
+impl Display for TraceState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "A: {:02X} F: {:02X} B: {:02X} C: {:02X} D: {:02X} E: {:02X} H: {:02X} L: {:02X} SP: {:04X} PC: 00:{:04X}",
+            self.a, self.f, self.b, self.c, self.d, self.e, self.h, self.l, self.sp, self.pc
+        )?;
+        if let Some(cycles) = self.cycles {
+            write!(f, " CY: {}", cycles)?;
+        }
+        if let Some(div) = self.div {
+            write!(f, " DIV: {:02X}", div)?;
+        }
+        if let Some(tima) = self.tima {
+            write!(f, " TIMA: {:02X}", tima)?;
+        }
+        if let Some(ly) = self.ly {
+            write!(f, " LY: {:02X}", ly)?;
+        }
+        
+        Ok(())
+    }
+}
+
+/// The first point at which the emulator disagreed with the reference trace.
+#[derive(Clone, Debug)]
+pub struct Divergence {
+    /// 1-based line number within the reference trace.
+    pub line: usize,
+    pub field: &'static str,
+    pub expected: TraceState,
+    pub actual: TraceState,
+}
+impl Display for Divergence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Diverged from reference on line {} at field {}", self.line, self.field)?;
+        writeln!(f, "  expected: {}", self.expected)?;
+        write!(f, "  actual:   {}", self.actual)
+    }
+}
+
+/// Why [ReferenceTrace::run()] stopped before reaching the end of the reference.
+#[derive(Clone, Debug)]
+pub enum CompareError {
+    Diverged(Divergence),
+    Cpu(CpuError),
+    /// The t-cycle budget ran out first.
+    CycleLimit,
+}
+impl Display for CompareError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Diverged(divergence) => write!(f, "{}", divergence),
+            Self::Cpu(err) => write!(f, "{}", err),
+            Self::CycleLimit => write!(f, "ran out of t-cycles before the end of the reference trace"),
+        }
+    }
+}
+
+/// Reference per-instruction state dump (e.g. from SameBoy or BGB) which the emulator is checked
+/// against in lockstep, one line per executed instruction.
+pub struct ReferenceTrace {
+    lines: Box<dyn Iterator<Item = std::io::Result<String>>>,
+    line: usize,
+    /// System t-cycle count at the first checked line, which `CY:` is counted from.
+    start: Option<usize>,
+}
+impl ReferenceTrace {
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        Ok(Self::from_reader(BufReader::new(File::open(path)?)))
+    }
+    
+    pub fn from_reader<R: BufRead + 'static>(reader: R) -> Self { Self {
+        lines: Box::new(reader.lines()),
+        line: 0,
+        start: None,
+    }}
+    
+    /// Compares the system's current state against the next line of the reference.
+    /// 
+    /// Returns `Ok(false)` once the reference has been exhausted without any divergence.
+    pub fn check(&mut self, gb: &Gameboy) -> Result<bool, Divergence> {
+        let expected = loop {
+            match self.lines.next() {
+                Some(Ok(line)) => {
+                    self.line += 1;
+                    if let Some(state) = TraceState::parse(&line) {
+                        break state;
+                    }
+                },
+                _ => return Ok(false),
+            }
+        };
+        
+        let start = *self.start.get_or_insert(gb.tcycles);
+        let actual = TraceState::from_gameboy(gb, (gb.tcycles - start) as u64);
+        match expected.first_mismatch(&actual) {
+            Some((field, _, _)) => Err(Divergence { line: self.line, field, expected, actual }),
+            None => Ok(true),
+        }
+    }
+    
+    /// Runs the system in lockstep with the whole reference, checking each instruction once the boot
+    /// ROM has finished, for up to `max_tcycles`. Returns the number of lines matched.
+    pub fn run(&mut self, gb: &mut Gameboy, max_tcycles: usize) -> Result<usize, CompareError> {
+        let mut last_checked = 0;
+        for _ in 0..(max_tcycles / 4) {
+            if gb.cpu.instr_count != last_checked && gb.bus.boot_disabled > 0 && !self.check(gb).map_err(CompareError::Diverged)? {
+                return Ok(self.line);
+            }
+            last_checked = gb.cpu.instr_count;
+            
+            gb.mcycle().map_err(CompareError::Cpu)?;
+        }
+        
+        Err(CompareError::CycleLimit)
+    }
+    
+    /// Number of reference lines consumed so far.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+}
@@ -0,0 +1,8 @@
+pub mod arch;
+pub mod audio;
+pub mod compare;
+pub mod logger;
+pub mod overlay;
+pub mod recent;
+pub mod screenshot;
+pub mod util;
@@ -1,20 +1,61 @@
 extern crate core;
 
+use std::collections::HashSet;
 use std::fs::File;
 use std::io::{LineWriter, Write};
+use std::panic::AssertUnwindSafe;
+use std::path::PathBuf;
 use std::time::{Duration, Instant};
 use clap::{AppSettings, Arg, Command};
-use log::{info, LevelFilter};
+use log::{error, info, warn, LevelFilter};
 use minifb::{Key, KeyRepeat, Scale, ScaleMode, Window, WindowOptions};
-use crate::arch::{Gameboy, SystemMode};
+use gbcrs::{audio, compare, logger, overlay, screenshot};
+use gbcrs::audio::AudioSink;
+use gbcrs::arch::{timing, Gameboy};
+use gbcrs::arch::cpu::CpuError;
+use gbcrs::arch::joypad::Button;
+use gbcrs::arch::ppu::Framebuffer;
+use gbcrs::overlay::OverlayMode;
+use gbcrs::recent::{RecentRoms, RECENT_LIMIT};
 
-pub mod arch;
-pub mod logger;
-pub mod util;
+const KEYMAP: [(Key, Button); 8] = [
+    (Key::Right, Button::Right),
+    (Key::Left, Button::Left),
+    (Key::Up, Button::Up),
+    (Key::Down, Button::Down),
+    (Key::X, Button::A),
+    (Key::Z, Button::B),
+    (Key::Backspace, Button::Select),
+    (Key::Enter, Button::Start),
+];
 
 fn main() {
     let matches = Command::new("gbcrs")
         .version(clap::crate_version!())
+        .arg(Arg::new("rom")
+            .required_unless_present_any(["recent", "list-recent"])
+            .value_name("ROM")
+            .help("Path to the cartridge ROM to run."))
+        .arg(Arg::new("recent")
+            .long("recent")
+            .takes_value(true)
+            .value_name("N")
+            .default_missing_value("1")
+            .conflicts_with("rom")
+            .help("Run the Nth most recently played ROM instead of ROM (default: 1, the last one played)."))
+        .arg(Arg::new("list-recent")
+            .long("list-recent")
+            .help("List the recently played ROMs, most recent first, and exit."))
+        .arg(Arg::new("boot")
+            .long("boot")
+            .takes_value(true)
+            .value_name("BOOTROM")
+            .help("Path to a boot ROM to run instead of the embedded DMG boot ROM, 256 bytes for DMG cartridges or 2304 bytes for CGB ones. CGB cartridges otherwise skip the boot ROM."))
+        .arg(Arg::new("no-bootrom")
+            .long("no-bootrom")
+            .alias("skip-bootrom")
+            .conflicts_with("boot")
+            .help("Skip the boot ROM and start at the cartridge entry point, as if it had just finished."))
         .arg(Arg::new("verbose")
             .short('v')
             .long("verbose")
@@ -26,6 +67,62 @@ fn main() {
         .arg(Arg::new("log")
             .long("log")
             .hide(true))
+        .arg(Arg::new("info")
+            .long("info")
+            .help("Print information about the ROM and exit."))
+        .arg(Arg::new("full-map")
+            .long("full-map")
+            .help("Show the entire 256x256 background map, with the visible area outlined, instead of the screen. Press G to cycle a grid overlay showing each tile's index or CGB attributes."))
+        .arg(Arg::new("no-focus-pause")
+            .long("no-focus-pause")
+            .help("Keep running while the window is unfocused, instead of pausing."))
+        .arg(Arg::new("dump-trace-on-panic")
+            .long("dump-trace-on-panic")
+            .takes_value(true)
+            .value_name("FILE")
+            .default_missing_value("crash.txt")
+            .help("If emulation crashes, write the CPU state and recently executed instructions to FILE (default: crash.txt)."))
+        .arg(Arg::new("export-tiles")
+            .long("export-tiles")
+            .takes_value(true)
+            .value_name("FILE")
+            .default_missing_value("tiles.2bpp")
+            .help("Press T to write the VRAM tile data to FILE as raw 2bpp, and the BG palette next to it as a .pal (default: tiles.2bpp)."))
+        .arg(Arg::new("input-latency")
+            .long("input-latency")
+            .help("Measure the frames between a key press and the game reading it, and show them in the window title."))
+        .arg(Arg::new("no-audio")
+            .long("no-audio")
+            .help("Don't play sound. Sound is only available when built with the 'audio' feature."))
+        .arg(Arg::new("sync")
+            .long("sync")
+            .takes_value(true)
+            .value_name("CLOCK")
+            .default_value("video")
+            .possible_values(["video", "audio"])
+            .help("What paces emulation. 'video' runs a frame per 60 Hz window update, 'audio' runs just enough to fill the audio device's buffer, which avoids underruns. Falls back to 'video' without sound, and can't be used with --compare or --check-timing."))
+        .arg(Arg::new("compare")
+            .long("compare")
+            .takes_value(true)
+            .value_name("TRACE")
+            .help("Run in lockstep against a reference per-instruction state dump (gameboy-doctor format, optionally with CY/DIV/TIMA/LY timing fields), stopping at the first divergence."))
+        .arg(Arg::new("check-timing")
+            .long("check-timing")
+            .help("Log a warning the first time each instruction accesses memory on different cycles than it does on hardware."))
+        .arg(Arg::new("trace-ppu-modes")
+            .long("trace-ppu-modes")
+            .help("Log every PPU mode change, with the LY and dot the new mode starts on."))
+        .arg(Arg::new("screenshot-on-frame")
+            .long("screenshot-on-frame")
+            .takes_value(true)
+            .value_name("N")
+            .help("Run N frames without opening a window, save the last one as a PNG (see --screenshot-out), and exit."))
+        .arg(Arg::new("screenshot-out")
+            .long("screenshot-out")
+            .takes_value(true)
+            .value_name("FILE")
+            .default_value("screenshot.png")
+            .help("Where --screenshot-on-frame saves its PNG. Screenshots taken with F2 are named after the current time instead."))
         .next_line_help(true)
         .setting(AppSettings::DeriveDisplayOrder)
         .get_matches();
@@ -44,10 +141,123 @@ fn main() {
         logbuilder.filter_level(level);
         logbuilder.init();
     }
-    const width: usize = 160;
-    const height: usize = 144;
-    //const width: usize = 256;
-    //const height: usize = 256;
+    
+    let recent_path = RecentRoms::default_path();
+    let mut recent = match recent_path.as_ref() {
+        Some(recent_path) => RecentRoms::load(recent_path, RECENT_LIMIT),
+        None => RecentRoms::new(RECENT_LIMIT),
+    };
+    if matches.is_present("list-recent") {
+        for (i, path) in recent.paths().iter().enumerate() {
+            info!("{}: {}", i + 1, path.display());
+        }
+        
+        return;
+    }
+    
+    let mut boot_rom = None;
+    if let Some(path) = matches.value_of("boot") {
+        boot_rom = match std::fs::read(path) {
+            Ok(data) => Some(data),
+            Err(err) => {
+                error!("Failed to read boot ROM '{}': {}", path, err);
+                return;
+            }
+        };
+    }
+    
+    let path = match matches.value_of("recent") {
+        Some(n) => match n.parse::<usize>().ok().and_then(|n| recent.paths().get(n.wrapping_sub(1))) {
+            Some(path) => path.clone(),
+            None => {
+                error!("No recent ROM '{}', there are {} recently played ROMs (see --list-recent)", n, recent.paths().len());
+                return;
+            }
+        },
+        None => PathBuf::from(matches.value_of("rom").unwrap()),
+    };
+    let rom = match std::fs::read(&path) {
+        Ok(rom) => rom,
+        Err(err) => {
+            error!("Failed to read ROM '{}': {}", path.display(), err);
+            return;
+        }
+    };
+    if rom.len() < 0x150 {
+        error!("ROM '{}' is too small to contain a cartridge header ({} bytes)", path.display(), rom.len());
+        return;
+    }
+    let mut gb = Gameboy::from_rom(rom);
+    if matches.is_present("no-bootrom") {
+        gb.skip_bootrom();
+    }
+    if let Some(boot_rom) = boot_rom {
+        let len = gb.mode().boot_rom_len();
+        if boot_rom.len() != len {
+            error!("Boot ROM '{}' must be exactly {} bytes for {:?} mode, but is {} bytes", matches.value_of("boot").unwrap(), len, gb.mode(), boot_rom.len());
+            return;
+        }
+        gb.bus.boot_rom = boot_rom;
+        gb.bus.boot_disabled = 0;
+        gb.cpu.regs.pc = 0x0000;
+    }
+    
+    if matches.is_present("info") {
+        let cart = &gb.bus.cart;
+        if let Some(header) = cart.header() {
+            info!("Title: {}", header.title);
+            info!("Cartridge type: {:02X}", header.cartridge_type);
+            info!("ROM size: {} KiB, RAM size: {} KiB", header.rom_bytes() / 1024, header.ram_bytes() / 1024);
+            info!("CGB flag: {:02X}, SGB flag: {:02X}, destination: {:02X}", header.cgb_flag, header.sgb_flag, header.destination);
+            info!("System mode: {:?}", header.system_mode());
+            info!("Header checksum: {:02X} ({})", header.header_checksum, if header.header_checksum_valid { "valid" } else { "invalid" });
+        }
+        match cart.global_checksum() {
+            Some(checksum) => info!("Global checksum: {:04X} ({})", checksum, if cart.verify_global_checksum() { "valid" } else { "invalid" }),
+            None => info!("Global checksum: missing, ROM is too small to contain a header"),
+        }
+        
+        return;
+    }
+    
+    if let Some(recent_path) = recent_path {
+        recent.add(path.canonicalize().unwrap_or(path));
+        if let Err(err) = recent.save(&recent_path) {
+            warn!("Failed to save recent ROMs to '{}': {}", recent_path.display(), err);
+        }
+    }
+    
+    if matches.is_present("trace-ppu-modes") {
+        gb.mode_trace = Some(Box::new(|t| info!("PPU mode {} -> {} at LY {} dot {}", t.from, t.to, t.ly, t.dot)));
+    }
+    
+    let dump_path = matches.value_of("dump-trace-on-panic");
+    if let Some(frames) = matches.value_of("screenshot-on-frame") {
+        let frames = match frames.parse::<u64>() {
+            Ok(frames) => frames,
+            Err(_) => {
+                error!("Invalid frame count '{}'", frames);
+                return;
+            }
+        };
+        gb.set_audio_generation(false);
+        for _ in 0..frames {
+            if !run_guarded(&mut gb, dump_path, |gb| gb.run_frame().map(|_| ())) {
+                return;
+            }
+        }
+        
+        let path = matches.value_of("screenshot-out").unwrap();
+        match screenshot::save_png(path, gb.bus.ppu.framebuffer()) {
+            Ok(()) => info!("Saved frame {} to '{}'", gb.frame_count(), path),
+            Err(err) => error!("Failed to save screenshot to '{}': {}", path, err),
+        }
+        
+        return;
+    }
+    
+    let full_map = matches.is_present("full-map");
+    let (width, height) = if full_map { (256, 256) } else { (Framebuffer::WIDTH, Framebuffer::HEIGHT) };
     
     
     let mut window = Window::new("gbcrs", width, height, WindowOptions {
@@ -62,12 +272,8 @@ fn main() {
     }).unwrap();
     window.limit_update_rate(Some(Duration::from_secs_f64(1.0 / 60.0)));
     //window.limit_update_rate(None);
-    let mut window_buf = [0u32; width * height];
-    
-    let mut gb = Gameboy::new(SystemMode::Gameboy);
-    gb.bus.get_mut().boot_rom = *include_bytes!("../bootroms/DMG1.rom");
-    //gb.bus.get_mut().cart.rom.extend_from_slice(include_bytes!("../testroms/mooneye/acceptance/serial/boot_sclk_align-dmgABCmgb.gb"));
-    gb.bus.get_mut().cart.rom.extend_from_slice(include_bytes!("../testroms/blargg/cpu_instrs/individual/03-op sp,hl.gb"));
+    let mut window_buf = vec![0u32; width * height];
+    let mut frame = Framebuffer::new();
     
     let mut writer = None;
     if matches.is_present("log") {
@@ -76,19 +282,107 @@ fn main() {
         writer = Some(LineWriter::new(file));
     }
     
+    let mut reference = None;
+    if let Some(path) = matches.value_of("compare") {
+        reference = Some(compare::ReferenceTrace::open(path).unwrap());
+    }
+    
+    // Instructions already reported as mismatched, so each is only reported once
+    let mut timing_mismatches = None;
+    if matches.is_present("check-timing") {
+        gb.cpu.set_access_logging(true);
+        timing_mismatches = Some(HashSet::new());
+    }
+    
+    let focus_pause = !matches.is_present("no-focus-pause");
+    let tiles_path = matches.value_of("export-tiles");
+    let show_latency = matches.is_present("input-latency");
+    gb.set_input_latency_tracking(show_latency);
+    let mut latency_samples = 0;
+    let mut audio = if matches.is_present("no-audio") { None } else { audio::AudioOutput::open() };
+    let mut audio_buf = vec![0f32; 4096];
+    let audio_sync = matches.value_of("sync") == Some("audio");
+    if audio_sync && (reference.is_some() || timing_mismatches.is_some()) {
+        error!("--sync audio can't be used with --compare or --check-timing, which check every instruction");
+        return;
+    }
+    if audio.is_none() {
+        if audio_sync {
+            warn!("No audio output, syncing to video instead");
+        }
+        // Nothing will play the samples, so don't spend time generating them
+        gb.set_audio_generation(false);
+    }
+    
     let mut line_count = 0usize;
     let mut last_instr = 0;
+    let mut last_checked = 0;
+    let mut last_timed = 0;
+    let mut frame_step = false;
+    let mut overlay_mode = OverlayMode::Off;
     //let mut frames = 0;
     while window.is_open() && !window.is_key_down(Key::Escape) {
         //let start = Instant::now();
         
+        if window.is_key_pressed(Key::F, KeyRepeat::No) {
+            frame_step = !frame_step;
+            if frame_step {
+                info!("Frame step enabled, press N to advance one frame");
+                gb.pause();
+            } else {
+                info!("Frame step disabled");
+                gb.resume();
+            }
+        }
+        
+        if let Some(path) = tiles_path {
+            if window.is_key_pressed(Key::T, KeyRepeat::No) {
+                match gb.export_tileset(path) {
+                    Ok(()) => info!("Exported tiles to '{}'", path),
+                    Err(err) => error!("Failed to export tiles to '{}': {}", path, err),
+                }
+            }
+        }
+        
+        if full_map && window.is_key_pressed(Key::G, KeyRepeat::No) {
+            overlay_mode = overlay_mode.next();
+            info!("Tile map overlay: {:?}", overlay_mode);
+        }
+        
+        if window.is_key_pressed(Key::F2, KeyRepeat::No) {
+            let path = screenshot::timestamped_path();
+            match screenshot::save_png(&path, gb.bus.ppu.framebuffer()) {
+                Ok(()) => info!("Saved screenshot to '{}'", path.display()),
+                Err(err) => error!("Failed to save screenshot to '{}': {}", path.display(), err),
+            }
+        }
+        
+        if focus_pause && !frame_step {
+            update_focus_pause(&mut gb, window.is_active());
+        }
+        
         //if window.is_key_pressed(Key::Space, KeyRepeat::No) || window.is_key_down(Key::M) {
         //    info!("f: {}", frames);
+        // Emulation waits while the audio device is behind, so the frame rate follows the audio clock
+        let audio_waiting = audio.as_ref().is_some_and(|audio| audio.requested() == 0);
+        if let Some(audio) = audio.as_mut().filter(|_| audio_sync && !gb.is_paused()) {
+            for (key, button) in KEYMAP {
+                gb.set_button(button, window.is_key_down(key));
+            }
+            
+            if !run_guarded(&mut gb, dump_path, |gb| audio::run_audio_synced(gb, audio, &mut audio_buf).map(|_| ())) {
+                return;
+            }
+        } else if !gb.is_paused() && !audio_waiting {
+            for (key, button) in KEYMAP {
+                gb.set_button(button, window.is_key_down(key));
+            }
+            
             for _ in 0..(2097152 / 2 / 60) {
                 if let Some(writer) = writer.as_mut() {
-                    use crate::arch::BusAccessable;
-                    let bus = gb.bus.get_mut();
-                    let cpu = &gb.bus.get().cpu;
+                    use gbcrs::arch::BusAccessable;
+                    let bus = &mut gb.bus;
+                    let cpu = &gb.cpu;
                     let count = cpu.instr_count;
                     if (count != last_instr || count == 0) && bus.boot_disabled > 0 {
                         writer.write_all(format!("A: {:02X} F: {:02X} B: {:02X} C: {:02X} D: {:02X} E: {:02X} H: {:02X} L: {:02X} SP: {:04X} PC: 00:{:04X} ({:02X} {:02X} {:02X} {:02X})\n",
@@ -98,6 +392,33 @@ fn main() {
                     }
                     last_instr = count;
                 }
+                if let Some(reference) = reference.as_mut() {
+                    if gb.cpu.instr_count != last_checked && gb.bus.boot_disabled > 0 {
+                        match reference.check(&gb) {
+                            Ok(true) => (),
+                            Ok(false) => {
+                                info!("Matched all {} lines of the reference trace", reference.line());
+                                return;
+                            },
+                            Err(divergence) => {
+                                error!("{}", divergence);
+                                return;
+                            },
+                        }
+                    }
+                    last_checked = gb.cpu.instr_count;
+                }
+                if let Some(reported) = timing_mismatches.as_mut() {
+                    let cpu = &gb.cpu;
+                    if cpu.instr_count != last_timed {
+                        if let Err(mismatch) = timing::check(cpu) {
+                            if reported.insert((mismatch.source, mismatch.opcode)) {
+                                warn!("{}", mismatch);
+                            }
+                        }
+                    }
+                    last_timed = cpu.instr_count;
+                }
                 if line_count == 180000 {
                     if let Some(mut writer) = writer {
                         writer.flush().unwrap();
@@ -108,14 +429,57 @@ fn main() {
                     return;
                 }
                 
-                gb.mcycle();
+                if !run_guarded(&mut gb, dump_path, Gameboy::mcycle) {
+                    return;
+                }
+            }
+        } else if frame_step && window.is_key_pressed(Key::N, KeyRepeat::Yes) {
+            for (key, button) in KEYMAP {
+                gb.set_button(button, window.is_key_down(key));
+            }
+            
+            if !run_guarded(&mut gb, dump_path, |gb| gb.run_frame().map(|_| ())) {
+                return;
             }
+            info!("Frame {}", gb.frame_count());
+        }
+        
+        let serial = gb.take_serial_output();
+        if !serial.is_empty() {
+            let mut stdout = std::io::stdout();
+            stdout.write_all(&serial).unwrap();
+            stdout.flush().unwrap();
+        }
+        
+        if let Some(audio) = audio.as_mut() {
+            loop {
+                let len = gb.drain_audio(&mut audio_buf);
+                if len == 0 {
+                    break;
+                }
+                audio.push(&audio_buf[..len]);
+            }
+        }
         //    frames += 1;
         //}
         if last_instr >= 1068423 { break }
         
-        gb.bus.get().ppu.render(&mut window_buf);
-        window.update_with_buffer(&window_buf, width, height).unwrap();
+        let buf = if full_map {
+            let ppu = &gb.bus.ppu;
+            ppu.render_full_map(&mut window_buf);
+            overlay::draw(&mut window_buf, &ppu.dump_tilemap(ppu.bg_map_addr()), overlay_mode);
+            &window_buf[..]
+        } else {
+            gb.bus.ppu.render(&mut frame);
+            &frame.pixels()[..]
+        };
+        if let Some(latency) = gb.input_latency().filter(|latency| latency.samples != latency_samples) {
+            latency_samples = latency.samples;
+            window.set_title(&format!("gbcrs | input latency: {} frames (min {}, avg {:.1}, max {})",
+                latency.last.unwrap_or(0), latency.min.unwrap_or(0), latency.average().unwrap_or(0.0), latency.max.unwrap_or(0)
+            ));
+        }
+        window.update_with_buffer(buf, width, height).unwrap();
         
         //let elapsed = start.elapsed().as_secs_f64();
         //info!("Time: {:.3}us | Factor: {:.3}", elapsed * 1000000.0, (1.0 / 60.0) / elapsed);
@@ -125,3 +489,85 @@ fn main() {
         writer.flush().unwrap();
     }
 }
+
+/// Runs `f` on the system, writing a crash dump if the CPU fails or panics. Returns false if
+/// emulation should stop.
+fn run_guarded<F: FnOnce(&mut Gameboy) -> Result<(), CpuError>>(gb: &mut Gameboy, dump_path: Option<&str>, f: F) -> bool {
+    match std::panic::catch_unwind(AssertUnwindSafe(|| f(gb))) {
+        Ok(Ok(())) => true,
+        Ok(Err(err)) => {
+            error!("{}", err);
+            if let Some(path) = dump_path {
+                write_crash_dump(gb, path);
+            }
+            false
+        },
+        Err(panic) => {
+            if let Some(path) = dump_path {
+                write_crash_dump(gb, path);
+            }
+            std::panic::resume_unwind(panic);
+        },
+    }
+}
+
+fn write_crash_dump(gb: &mut Gameboy, path: &str) {
+    match std::fs::write(path, gb.crash_report()) {
+        Ok(()) => error!("Wrote crash report to '{}'", path),
+        Err(err) => error!("Failed to write crash report to '{}': {}", path, err),
+    }
+}
+
+/// Pauses emulation when the window loses focus, and resumes it once focus returns.
+fn update_focus_pause(gb: &mut Gameboy, active: bool) {
+    if active && gb.is_paused() {
+        info!("Window focused, resuming");
+        gb.resume();
+    } else if !active && !gb.is_paused() {
+        info!("Window unfocused, pausing");
+        gb.pause();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gbcrs::arch::SystemMode;
+    
+    #[test]
+    fn focus_pauses_and_resumes() {
+        let mut gb = Gameboy::new(SystemMode::Gameboy);
+        let events = [(true, false), (false, true), (false, true), (true, false), (true, false), (false, true)];
+        for (i, (active, paused)) in events.into_iter().enumerate() {
+            update_focus_pause(&mut gb, active);
+            assert_eq!(gb.is_paused(), paused, "event {} (active: {})", i, active);
+        }
+    }
+    
+    #[test]
+    fn panic_writes_crash_dump() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[
+            0x00, // NOP
+            0x3E, 0x42, // LD A,0x42
+            0x00, // NOP
+        ]);
+        let mut gb = Gameboy::from_rom(rom);
+        gb.skip_bootrom();
+        
+        let path = std::env::temp_dir().join(format!("gbcrs-crash-{}.txt", std::process::id()));
+        let dump_path = path.to_str().unwrap();
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| run_guarded(&mut gb, Some(dump_path), |gb| {
+            gb.step_instruction()?;
+            gb.step_instruction()?;
+            panic!("deliberate panic");
+        })));
+        assert!(result.is_err(), "the panic wasn't passed on");
+        
+        let report = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(report.starts_with("Last instruction: 0101 (opcode 3E)\n"), "{}", report);
+        assert!(report.contains("A: 42 "), "{}", report);
+        assert!(report.contains("  0100: NOP\n  0101: LD A,$42\n"), "{}", report);
+    }
+}
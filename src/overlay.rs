@@ -0,0 +1,135 @@
+use crate::arch::ppu::TileAttributes;
+
+/// Color of the lines between tile map cells.
+const GRID_COLOR: u32 = 0x00404040;
+/// Color of the hex byte drawn in each cell.
+const LABEL_COLOR: u32 = 0x00FF00FF;
+
+/// 3x5 pixel hex digits, one row per byte with the leftmost pixel in bit 2.
+const HEX_FONT: [[u8; 5]; 16] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111], // 0
+    [0b010, 0b110, 0b010, 0b010, 0b111], // 1
+    [0b111, 0b001, 0b111, 0b100, 0b111], // 2
+    [0b111, 0b001, 0b111, 0b001, 0b111], // 3
+    [0b101, 0b101, 0b111, 0b001, 0b001], // 4
+    [0b111, 0b100, 0b111, 0b001, 0b111], // 5
+    [0b111, 0b100, 0b111, 0b101, 0b111], // 6
+    [0b111, 0b001, 0b010, 0b010, 0b010], // 7
+    [0b111, 0b101, 0b111, 0b101, 0b111], // 8
+    [0b111, 0b101, 0b111, 0b001, 0b111], // 9
+    [0b010, 0b101, 0b111, 0b101, 0b101], // A
+    [0b110, 0b101, 0b110, 0b101, 0b110], // B
+    [0b011, 0b100, 0b100, 0b100, 0b011], // C
+    [0b110, 0b101, 0b101, 0b101, 0b110], // D
+    [0b111, 0b100, 0b110, 0b100, 0b111], // E
+    [0b111, 0b100, 0b110, 0b100, 0b100], // F
+];
+
+/// What the tile map overlay shows in each cell.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OverlayMode {
+    Off,
+    TileIndex,
+    /// BG map attributes, which are always 00 outside of CGB mode.
+    Attributes,
+}
+impl OverlayMode {
+    /// The mode selected by the next press of the overlay key.
+    pub fn next(self) -> Self {
+        match self {
+            OverlayMode::Off => OverlayMode::TileIndex,
+            OverlayMode::TileIndex => OverlayMode::Attributes,
+            OverlayMode::Attributes => OverlayMode::Off,
+        }
+    }
+}
+
+/// Byte shown in each cell of a tile map from [Ppu::dump_tilemap()](crate::arch::ppu::Ppu::dump_tilemap()),
+/// in the same order. Empty when the overlay is off.
+pub fn annotations(tilemap: &[(u8, TileAttributes)], mode: OverlayMode) -> Vec<u8> {
+    tilemap.iter().filter_map(|(tile, attrs)| match mode {
+        OverlayMode::Off => None,
+        OverlayMode::TileIndex => Some(*tile),
+        OverlayMode::Attributes => Some(attrs.0),
+    }).collect()
+}
+
+/// Draws the 32x32 cell grid over a 256x256 render of the tile map, like the one from
+/// [Ppu::render_full_map()](crate::arch::ppu::Ppu::render_full_map()), with each cell's annotation
+/// as two hex digits in its corner.
+pub fn draw(buf: &mut [u32], tilemap: &[(u8, TileAttributes)], mode: OverlayMode) {
+    if mode == OverlayMode::Off {
+        return;
+    }
+    
+    for (i, pixel) in buf.iter_mut().enumerate().take(256 * 256) {
+        if (i % 256) % 8 == 0 || (i / 256) % 8 == 0 {
+            *pixel = GRID_COLOR;
+        }
+    }
+    
+    for (cell, label) in annotations(tilemap, mode).into_iter().enumerate().take(32 * 32) {
+        let x = ((cell % 32) * 8) + 1;
+        let y = ((cell / 32) * 8) + 2;
+        draw_digit(buf, x, y, label >> 4);
+        draw_digit(buf, x + 4, y, label & 0x0F);
+    }
+}
+
+fn draw_digit(buf: &mut [u32], x: usize, y: usize, digit: u8) {
+    for (row, bits) in HEX_FONT[digit as usize].iter().enumerate() {
+        for col in 0..3 {
+            if bits & (0b100 >> col) != 0 {
+                if let Some(pixel) = buf.get_mut(((y + row) * 256) + x + col) {
+                    *pixel = LABEL_COLOR;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::SystemMode;
+    use crate::arch::ppu::Ppu;
+    
+    #[test]
+    fn annotations_per_cell() {
+        let mut ppu = Ppu::new(SystemMode::GameboyColorGBC);
+        ppu.vram[0x1C00..0x1C03].copy_from_slice(&[0x12, 0x00, 0xFF]);
+        ppu.vram1[0x1C00..0x1C03].copy_from_slice(&[0x00, 0x2B, 0x80]);
+        let tilemap = ppu.dump_tilemap(0x9C00);
+        
+        assert_eq!(annotations(&tilemap, OverlayMode::Off), []);
+        let tiles = annotations(&tilemap, OverlayMode::TileIndex);
+        assert_eq!(tiles.len(), 32 * 32);
+        assert_eq!(tiles[..4], [0x12, 0x00, 0xFF, 0x00]);
+        let attrs = annotations(&tilemap, OverlayMode::Attributes);
+        assert_eq!(attrs.len(), 32 * 32);
+        assert_eq!(attrs[..4], [0x00, 0x2B, 0x80, 0x00]);
+    }
+    
+    #[test]
+    fn draw_labels_cells() {
+        let mut tilemap = vec![(0x00, TileAttributes::default()); 32 * 32];
+        tilemap[33] = (0x1F, TileAttributes::default());
+        let mut buf = vec![0u32; 256 * 256];
+        draw(&mut buf, &tilemap, OverlayMode::TileIndex);
+        
+        // Cell 33 is at (8, 8), with its label starting at (9, 10)
+        let pixel = |x: usize, y: usize| buf[(y * 256) + x];
+        assert_eq!((pixel(8, 12), pixel(12, 8)), (GRID_COLOR, GRID_COLOR));
+        let label: Vec<[bool; 7]> = (10..15).map(|y| [9, 10, 11, 12, 13, 14, 15].map(|x| pixel(x, y) == LABEL_COLOR)).collect();
+        for (row, bits) in label.iter().enumerate() {
+            let expected = ((HEX_FONT[0x1][row] as u32) << 4) | (HEX_FONT[0xF][row] as u32);
+            let drawn = bits.iter().fold(0, |acc, &bit| (acc << 1) | (bit as u32));
+            assert_eq!(drawn, expected, "row {}", row);
+        }
+        assert_eq!(pixel(11, 11), 0);
+        
+        let mut off = vec![0u32; 256 * 256];
+        draw(&mut off, &tilemap, OverlayMode::Off);
+        assert!(off.iter().all(|&pixel| pixel == 0));
+    }
+}
@@ -0,0 +1,99 @@
+use std::path::{Path, PathBuf};
+
+/// Number of ROMs remembered by default.
+pub const RECENT_LIMIT: usize = 10;
+
+/// Recently played ROMs, most recent first. Persisted as a text file with one path per line.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecentRoms {
+    paths: Vec<PathBuf>,
+    limit: usize,
+}
+impl RecentRoms {
+    pub fn new(limit: usize) -> Self { Self {
+        paths: vec![],
+        limit,
+    }}
+    
+    /// Parses the contents of a recent list file. Blank lines are skipped, and anything past `limit`
+    /// entries is dropped.
+    pub fn parse(text: &str, limit: usize) -> Self {
+        let mut recent = Self::new(limit);
+        for line in text.lines().rev().filter(|line| !line.trim().is_empty()) {
+            recent.add(line.trim());
+        }
+        
+        recent
+    }
+    
+    /// Loads the list from `path`. A missing or unreadable file gives an empty list.
+    pub fn load<P: AsRef<Path>>(path: P, limit: usize) -> Self {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text, limit),
+            Err(_) => Self::new(limit),
+        }
+    }
+    
+    /// Writes the list to `path`, creating its parent directories if needed.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        if let Some(parent) = path.as_ref().parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        
+        std::fs::write(path, self.to_text())
+    }
+    
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for path in &self.paths {
+            text.push_str(&path.to_string_lossy());
+            text.push('\n');
+        }
+        
+        text
+    }
+    
+    /// Moves `rom` to the front of the list, adding it if it isn't there already and dropping the
+    /// oldest entry if the list is full.
+    pub fn add<P: AsRef<Path>>(&mut self, rom: P) {
+        let rom = rom.as_ref();
+        self.paths.retain(|path| path != rom);
+        self.paths.insert(0, rom.to_path_buf());
+        self.paths.truncate(self.limit);
+    }
+    
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+    
+    /// Default location of the recent list file: `$XDG_CONFIG_HOME/gbcrs/recent.txt`, falling back
+    /// to `~/.config/gbcrs/recent.txt`, or `%APPDATA%\gbcrs\recent.txt` on Windows.
+    pub fn default_path() -> Option<PathBuf> {
+        let config = std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+            .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        
+        Some(config.join("gbcrs").join("recent.txt"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn most_recent_first_deduplicated_and_capped() {
+        let mut recent = RecentRoms::new(3);
+        for rom in ["a.gb", "b.gb", "c.gb"] {
+            recent.add(rom);
+        }
+        assert_eq!(recent.paths(), ["c.gb", "b.gb", "a.gb"].map(PathBuf::from));
+        
+        recent.add("a.gb");
+        assert_eq!(recent.paths(), ["a.gb", "c.gb", "b.gb"].map(PathBuf::from));
+        
+        recent.add("d.gb");
+        assert_eq!(recent.paths(), ["d.gb", "a.gb", "c.gb"].map(PathBuf::from));
+        assert_eq!(RecentRoms::parse(&recent.to_text(), 3), recent);
+    }
+}
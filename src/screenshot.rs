@@ -0,0 +1,20 @@
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::arch::ppu::Framebuffer;
+
+/// Writes `frame` to `path` as an RGB PNG.
+pub fn save_png<P: AsRef<Path>>(path: P, frame: &Framebuffer) -> image::ImageResult<()> {
+    let mut rgb = Vec::with_capacity(Framebuffer::WIDTH * Framebuffer::HEIGHT * 3);
+    for pixel in frame.pixels() {
+        rgb.extend_from_slice(&[(pixel >> 16) as u8, (pixel >> 8) as u8, *pixel as u8]);
+    }
+    
+    image::save_buffer(path, &rgb, Framebuffer::WIDTH as u32, Framebuffer::HEIGHT as u32, image::ColorType::Rgb8)
+}
+
+/// Path in the working directory named after the current time, e.g. `gbcrs-1700000000123.png`.
+pub fn timestamped_path() -> PathBuf {
+    let millis = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_millis()).unwrap_or(0);
+    
+    PathBuf::from(format!("gbcrs-{}.png", millis))
+}
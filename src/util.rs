@@ -1,38 +1,85 @@
-
-use std::cell::UnsafeCell;
-
-/// Infinite access unsafe cell. Multiple mutable references of this data can exist
-/// across threads. No locking or any kind of safety checks are performed.
-/// 
-/// Extreme care must be taken when using this across threads. However, undefined
-/// behavor may be desirable to mimic similar behavior on hardware.
-#[derive(Debug)]
-pub struct InfCell<T> {
-    uc: UnsafeCell<T>,
-}
-impl<T> InfCell<T> {
-    pub fn new(val: T) -> Self {
-        Self { uc: UnsafeCell::new(val) }
+/// Serde helpers for byte arrays too large for serde's built-in array support (over 32 elements).
+/// Use with `#[serde(with = "crate::util::byte_array")]`.
+#[cfg(feature = "serde")]
+pub mod byte_array {
+    use serde::{Deserializer, Serializer};
+    use serde::de::{Error, SeqAccess, Visitor};
+    
+    pub fn serialize<S: Serializer, const N: usize>(bytes: &[u8; N], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(bytes)
+    }
+    
+    pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(deserializer: D) -> Result<[u8; N], D::Error> {
+        let bytes = deserializer.deserialize_bytes(BytesVisitor)?;
+        let len = bytes.len();
+        bytes.try_into().map_err(|_| D::Error::invalid_length(len, &format!("{} bytes", N).as_str()))
     }
     
-    /// Get mutable pointer to wrapped data.
-    pub fn get_raw(&self) -> *mut T {
-        self.uc.get()
+    /// Same as [byte_array](self), for a boxed byte array.
+    pub mod boxed {
+        use serde::{Deserializer, Serializer};
+        use serde::de::Error;
+        
+        pub fn serialize<S: Serializer, const N: usize>(bytes: &[u8; N], serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_bytes(bytes)
+        }
+        
+        pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(deserializer: D) -> Result<Box<[u8; N]>, D::Error> {
+            let bytes = deserializer.deserialize_bytes(super::BytesVisitor)?;
+            let len = bytes.len();
+            bytes.into_boxed_slice().try_into().map_err(|_| D::Error::invalid_length(len, &format!("{} bytes", N).as_str()))
+        }
     }
     
-    /// Get immutable reference to wrapped data.
-    pub fn get(&self) -> &T {
-        unsafe { &*self.get_raw() }
+    /// Same as [byte_array](self), for a boxed array of byte arrays (e.g. memory banks). The banks are
+    /// stored back to back as one byte string.
+    pub mod banks {
+        use serde::{Deserializer, Serializer};
+        use serde::de::Error;
+        
+        pub fn serialize<S: Serializer, const N: usize, const M: usize>(banks: &[[u8; N]; M], serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_bytes(banks.as_flattened())
+        }
+        
+        pub fn deserialize<'de, D: Deserializer<'de>, const N: usize, const M: usize>(deserializer: D) -> Result<Box<[[u8; N]; M]>, D::Error> {
+            let bytes = deserializer.deserialize_bytes(super::BytesVisitor)?;
+            if bytes.len() != N * M {
+                return Err(D::Error::invalid_length(bytes.len(), &format!("{} bytes", N * M).as_str()));
+            }
+            
+            let mut banks: Box<[[u8; N]; M]> = vec![[0u8; N]; M].into_boxed_slice().try_into().unwrap();
+            for (bank, chunk) in banks.iter_mut().zip(bytes.chunks_exact(N)) {
+                bank.copy_from_slice(chunk);
+            }
+            
+            Ok(banks)
+        }
     }
     
-    /// Get mutable reference to wrapped data. No safety checks, no locking.
-    /// 
-    /// This can be used multiple times! Undefined behavior when multiple threads
-    /// write to the same data type/struct contained in the wrapped data.
-    pub fn get_mut(&self) -> &'static mut T {
-        unsafe { &mut *self.get_raw() }
+    /// Accepts a byte string, or a sequence of bytes for formats that don't have byte strings.
+    struct BytesVisitor;
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+        
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "a byte array")
+        }
+        
+        fn visit_bytes<E: Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+            Ok(bytes.to_vec())
+        }
+        
+        fn visit_byte_buf<E: Error>(self, bytes: Vec<u8>) -> Result<Self::Value, E> {
+            Ok(bytes)
+        }
+        
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            
+            Ok(bytes)
+        }
     }
 }
-
-unsafe impl<T> Send for InfCell<T> {}
-//unsafe impl<T> Sync for InfCell<T> {}
\ No newline at end of file
@@ -0,0 +1,41 @@
+use gbcrs::arch::Gameboy;
+use gbcrs::compare::{CompareError, ReferenceTrace};
+
+/// Program for `data/lockstep.trace`, placed at the cartridge entry point (0x0100).
+/// 
+/// The trace was recorded from this emulator rather than from a reference emulator like SameBoy or
+/// BGB, so it only checks the trace format and catches regressions, not accuracy.
+const PROGRAM: &[u8] = &[
+    0x00,             // NOP
+    0x3E, 0x12,       // LD A,0x12
+    0x06, 0x34,       // LD B,0x34
+    0x80,             // ADD A,B
+    0x21, 0x00, 0xC0, // LD HL,0xC000
+    0x77,             // LD (HL),A
+    0x2C,             // INC L
+    0x2D,             // DEC L
+    0x7E,             // LD A,(HL)
+    0xC5,             // PUSH BC
+    0xD1,             // POP DE
+    0x3C,             // INC A
+    0x18, 0xFE,       // JR -2
+];
+
+fn lockstep_rom() -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..(0x100 + PROGRAM.len())].copy_from_slice(PROGRAM);
+    rom
+}
+
+#[test]
+fn matches_recorded_trace() {
+    let mut gb = Gameboy::from_rom(lockstep_rom());
+    gb.skip_bootrom();
+    
+    let mut reference = ReferenceTrace::from_reader(include_str!("data/lockstep.trace").as_bytes());
+    match reference.run(&mut gb, 10_000) {
+        Ok(lines) => assert!(lines > 0, "reference trace is empty"),
+        Err(CompareError::Diverged(divergence)) => panic!("{}", divergence),
+        Err(err) => panic!("lockstep run failed on line {}: {}", reference.line(), err),
+    }
+}
@@ -0,0 +1,83 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use gbcrs::arch::cartridge::CartridgeHeader;
+use gbcrs::arch::Gameboy;
+
+const ROM_DIR: &str = "testroms/blargg/cpu_instrs";
+
+/// Frames to run before giving up. The combined ROM takes roughly 3300 frames on hardware, so this
+/// leaves plenty of room without letting a hung ROM spin forever.
+const FRAME_LIMIT: usize = 10_000;
+
+/// The combined ROM if it's present, otherwise each of the individual ROMs.
+fn roms() -> Vec<PathBuf> {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join(ROM_DIR);
+    let combined = root.join("cpu_instrs.gb");
+    if combined.is_file() {
+        return vec![combined];
+    }
+    
+    let mut roms = fs::read_dir(root.join("individual"))
+        .map(|dir| dir.filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "gb"))
+            .collect::<Vec<_>>())
+        .unwrap_or_default();
+    roms.sort();
+    roms
+}
+
+/// Sub-tests the combined ROM reported as anything but `NN:ok`, or the whole output if there are none
+/// (as with the individual ROMs, which print their own name and the failing opcodes).
+fn failures(output: &str) -> String {
+    let failed = output.split_whitespace()
+        .filter(|word| word.len() > 3 && word.as_bytes()[2] == b':' && word[..2].bytes().all(|b| b.is_ascii_digit()))
+        .filter(|word| &word[3..] != "ok")
+        .collect::<Vec<_>>();
+    
+    if failed.is_empty() {
+        output.trim().to_owned()
+    } else {
+        failed.join(", ")
+    }
+}
+
+/// Runs a ROM until it reports a result over the serial port, returning everything it sent.
+fn run(path: &Path, rom: Vec<u8>) -> String {
+    let mut gb = Gameboy::from_rom(rom);
+    gb.skip_bootrom();
+    gb.set_audio_generation(false);
+    
+    let mut output = String::new();
+    for _ in 0..FRAME_LIMIT {
+        if let Err(err) = gb.run_frame() {
+            panic!("{}: {:?}\nserial output:\n{}", path.display(), err, output);
+        }
+        output.extend(gb.take_serial_output().into_iter().map(|b| b as char));
+        if output.contains("Passed") || output.contains("Failed") {
+            return output;
+        }
+    }
+    
+    panic!("{}: no result after {} frames\nserial output:\n{}", path.display(), FRAME_LIMIT, output);
+}
+
+#[test]
+#[ignore = "needs blargg ROMs"]
+fn cpu_instrs() {
+    let roms = roms();
+    let mut ran = 0;
+    for path in roms {
+        let rom = fs::read(&path).unwrap();
+        // Skip anything that isn't a real cartridge, like an empty placeholder
+        if !CartridgeHeader::parse(&rom).is_some_and(|header| header.header_checksum_valid) {
+            eprintln!("skipping {}: not a valid ROM", path.display());
+            continue;
+        }
+        
+        let output = run(&path, rom);
+        assert!(output.contains("Passed"), "{} failed: {}", path.display(), failures(&output));
+        ran += 1;
+    }
+    
+    assert!(ran > 0, "no valid ROMs found in {}", ROM_DIR);
+}